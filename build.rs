@@ -0,0 +1,60 @@
+// Bundles everything under res/www into the kernel so the http server can serve it without a
+// filesystem. Generates a table of Asset entries that src/http/assets.rs includes
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+const ASSET_ROOT: &str = "res/www";
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = fs::read_dir(dir).unwrap_or_else(|e| panic!("failed to read {:?}: {}", dir, e));
+    for entry in entries {
+        let path = entry.expect("failed to read directory entry").path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+// FNV-1a, only needs to change when the content does
+fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", ASSET_ROOT);
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let root = manifest_dir.join(ASSET_ROOT);
+
+    let mut files = Vec::new();
+    collect_files(&root, &mut files);
+    files.sort();
+
+    let mut output = String::from("pub static ASSETS: &[Asset] = &[\n");
+    for file in files {
+        let data = fs::read(&file).unwrap_or_else(|e| panic!("failed to read {:?}: {}", file, e));
+        let relative = file.strip_prefix(&root).unwrap();
+        let path: Vec<_> = relative
+            .components()
+            .map(|c| c.as_os_str().to_str().expect("asset paths must be utf-8"))
+            .collect();
+
+        output += &format!(
+            "    Asset {{ path: {:?}, data: include_bytes!({:?}), etag: {:?} }},\n",
+            format!("/{}", path.join("/")),
+            file.to_str().expect("asset paths must be utf-8"),
+            format!("\"{:016x}\"", hash(&data)),
+        );
+    }
+    output += "];\n";
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("assets.rs"), output).expect("failed to write asset table");
+}
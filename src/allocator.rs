@@ -62,6 +62,15 @@ pub struct Allocator {
     lock: SpinLock<()>,
 }
 
+/// Summary of the free list at one point in time
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub free_bytes: usize,
+    pub free_segments: usize,
+    // Largest allocation that could currently succeed, less alignment and header
+    pub largest_free_segment: usize,
+}
+
 impl Allocator {
     pub const fn new() -> Allocator {
         Allocator {
@@ -69,6 +78,30 @@ impl Allocator {
             lock: SpinLock::new(()),
         }
     }
+
+    pub fn stats(&self) -> HeapStats {
+        let _guard1 = InterruptGuarded::new(());
+        let _guard1 = _guard1.lock();
+        let _guard2 = self.lock.lock();
+
+        let mut stats = HeapStats {
+            free_bytes: 0,
+            free_segments: 0,
+            largest_free_segment: 0,
+        };
+
+        let mut it = self.first_free.load(Ordering::Relaxed);
+        while !it.is_null() {
+            // Fields of the packed segment are copied out, never referenced
+            let (size, next) = unsafe { ((*it).size, (*it).next_segment) };
+            stats.free_bytes += size;
+            stats.free_segments += 1;
+            stats.largest_free_segment = stats.largest_free_segment.max(size);
+            it = next;
+        }
+
+        stats
+    }
 }
 
 pub unsafe fn init(info: &Multiboot2) {
@@ -316,4 +349,16 @@ mod test {
         test_eq!(initial_state, capture_alloc_state());
         Ok(())
     });
+
+    create_test!(test_heap_stats, {
+        let before = ALLOC.stats();
+        let allocation = Vec::<u8>::with_capacity(4096);
+        let during = ALLOC.stats();
+        test_ge!(before.free_bytes, during.free_bytes + allocation.capacity());
+
+        drop(allocation);
+        test_eq!(ALLOC.stats().free_bytes, before.free_bytes);
+        test_ge!(before.free_bytes, before.largest_free_segment);
+        Ok(())
+    });
 }
@@ -0,0 +1,305 @@
+use crate::{
+    allocator::{HeapStats, ALLOC},
+    http::{
+        sse::{self, EventStream},
+        Method, Response, Router, StatusCode,
+    },
+    io::{
+        pci::PciDeviceInfo,
+        rtc::{DateTime, Rtc},
+    },
+    logger::{self, Log, LogEvent},
+    multiprocessing::{self, CpuFnDispatcher},
+    net::{
+        arp::ArpTableEntry,
+        tcp::{TcpConnectionInfo, TcpConnectionStats, TcpListenerInfo},
+        NetStack,
+    },
+    rtl8139::Rtl8139,
+    time::MonotonicTime,
+    usb::{UsbDeviceDescriptor, UsbServiceHandle},
+    util::json::{write_object, ToJson},
+    IpAddr, MacAddr,
+};
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+// Listed at /api
+const ENDPOINTS: [&str; 8] = [
+    "/api/arp",
+    "/api/tcp",
+    "/api/pci",
+    "/api/usb",
+    "/api/heap",
+    "/api/cpus",
+    "/api/date",
+    "/logs",
+];
+
+/// Kernel state the api reports on
+pub struct ApiSources<'a> {
+    pub net: &'a NetStack<Rtl8139>,
+    pub pci_devices: &'a [PciDeviceInfo],
+    pub usb: UsbServiceHandle,
+    pub cpu_dispatcher: &'a CpuFnDispatcher,
+    pub rtc: &'a Rtc,
+    pub time: &'a MonotonicTime,
+}
+
+struct TcpSummary {
+    listeners: Vec<TcpListenerInfo>,
+    connections: Vec<TcpConnectionInfo>,
+}
+
+struct UsbDeviceInfo {
+    address: u8,
+    descriptor: UsbDeviceDescriptor<Vec<u8>>,
+}
+
+struct CpuSummary {
+    cpus: Vec<u32>,
+    // The one that answered the request
+    current: u8,
+    uptime_s: f32,
+}
+
+/// Adds GET endpoints under /api that return kernel state as JSON, and /logs which streams
+/// every log as a Server-Sent Event, for debugging
+pub fn add_routes<'a>(router: Router<'a>, sources: ApiSources<'a>) -> Router<'a> {
+    let ApiSources {
+        net,
+        pci_devices,
+        usb,
+        cpu_dispatcher,
+        rtc,
+        time,
+    } = sources;
+
+    router
+        .route(Method::Get, "/api", |_| {
+            Box::pin(async { Response::json(&ENDPOINTS[..]) })
+        })
+        .route(Method::Get, "/api/arp", move |_| {
+            Box::pin(async move { Response::json(&net.arp_entries().await) })
+        })
+        .route(Method::Get, "/api/tcp", move |_| {
+            Box::pin(async move {
+                let summary = TcpSummary {
+                    listeners: net.tcp().listeners(),
+                    connections: net.tcp().connections().await,
+                };
+                Response::json(&summary)
+            })
+        })
+        .route(Method::Get, "/api/pci", move |_| {
+            Box::pin(async move { Response::json(pci_devices) })
+        })
+        .route(Method::Get, "/api/usb", move |_| {
+            let usb = usb.clone();
+            Box::pin(async move {
+                let mut devices = Vec::new();
+                for address in usb.device_addresses() {
+                    let descriptor = usb.get_device_descriptor(address).await;
+                    devices.push(UsbDeviceInfo {
+                        address,
+                        descriptor,
+                    });
+                }
+                Response::json(&devices)
+            })
+        })
+        .route(Method::Get, "/api/heap", |_| {
+            Box::pin(async { Response::json(&ALLOC.stats()) })
+        })
+        .route(Method::Get, "/api/cpus", move |_| {
+            Box::pin(async move {
+                let summary = CpuSummary {
+                    cpus: cpu_dispatcher.cpus().collect(),
+                    current: multiprocessing::cpuid(),
+                    uptime_s: time.get() as f32 / time.tick_freq(),
+                };
+                Response::json(&summary)
+            })
+        })
+        .route(Method::Get, "/api/date", move |_| {
+            Box::pin(async move {
+                match rtc.read() {
+                    Ok(date) => Response::json(&date),
+                    Err(e) => {
+                        warn!("Failed to read rtc: {:?}", e);
+                        Response::from_status(StatusCode::InternalServerError)
+                    }
+                }
+            })
+        })
+        .route(Method::Get, "/logs", |_| {
+            Box::pin(async { sse::response(stream_logs) })
+        })
+}
+
+// A client that stops reading holds up events.send once its connection's send buffer is full.
+// Logs queue up in the subscriber in the meantime, and whatever doesn't fit is reported as dropped
+async fn stream_logs(events: EventStream) {
+    let subscriber = logger::subscribe();
+    loop {
+        let sent = match subscriber.recv().await {
+            LogEvent::Log(log) => events.send(Some("log"), &log.to_json()).await,
+            LogEvent::Dropped(count) => events.send(Some("dropped"), &count.to_json()).await,
+        };
+        if sent.is_err() {
+            return;
+        }
+    }
+}
+
+fn ip_string(ip: &IpAddr) -> String {
+    format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3])
+}
+
+fn mac_string(mac: &MacAddr) -> String {
+    format!(
+        "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    )
+}
+
+impl ToJson for Log {
+    fn write_json(&self, out: &mut String) {
+        write_object(out, |o| {
+            o.field("level", &self.level.to_string())
+                .field("file", self.file)
+                .field("line", &self.line)
+                .field("message", &self.message);
+        });
+    }
+}
+
+impl ToJson for ArpTableEntry {
+    fn write_json(&self, out: &mut String) {
+        write_object(out, |o| {
+            o.field("ip", &ip_string(&self.ip))
+                .field("mac", &mac_string(&self.mac))
+                .field("expires_in_s", &self.expires_in_s);
+        });
+    }
+}
+
+impl ToJson for TcpListenerInfo {
+    fn write_json(&self, out: &mut String) {
+        write_object(out, |o| {
+            o.field("ip", &ip_string(&self.ip))
+                .field("port", &self.port)
+                .field("queued", &self.queued);
+        });
+    }
+}
+
+impl ToJson for TcpConnectionStats {
+    fn write_json(&self, out: &mut String) {
+        write_object(out, |o| {
+            o.field("in_flight", &self.in_flight)
+                .field("send_buffered", &self.send_buffered)
+                .field("unread", &self.unread)
+                .field("congestion_window", &self.congestion_window)
+                .field("peer_window", &self.peer_window)
+                .field("srtt_s", &self.srtt_s)
+                .field("retransmits", &self.retransmits);
+        });
+    }
+}
+
+impl ToJson for TcpConnectionInfo {
+    fn write_json(&self, out: &mut String) {
+        write_object(out, |o| {
+            o.field("local_ip", &ip_string(&self.local_ip))
+                .field("local_port", &self.local_port)
+                .field("remote_ip", &ip_string(&self.remote_ip))
+                .field("remote_port", &self.remote_port)
+                .field("state", self.state)
+                .field("stats", &self.stats);
+        });
+    }
+}
+
+impl ToJson for TcpSummary {
+    fn write_json(&self, out: &mut String) {
+        write_object(out, |o| {
+            o.field("listeners", &self.listeners)
+                .field("connections", &self.connections);
+        });
+    }
+}
+
+impl ToJson for PciDeviceInfo {
+    fn write_json(&self, out: &mut String) {
+        write_object(out, |o| {
+            o.field("bus", &self.bus)
+                .field("slot", &self.slot)
+                .field("function", &self.function)
+                .field("vendor_id", &format!("{:#06x}", self.vendor_id))
+                .field("device_id", &format!("{:#06x}", self.device_id))
+                .field("class", &self.interface_id.class)
+                .field("subclass", &self.interface_id.subclass)
+                .field("interface", &self.interface_id.interface)
+                .field("revision", &self.interface_id.revision);
+        });
+    }
+}
+
+impl ToJson for UsbDeviceInfo {
+    fn write_json(&self, out: &mut String) {
+        let descriptor = &self.descriptor;
+        write_object(out, |o| {
+            o.field("address", &self.address)
+                .field("vendor_id", &format!("{:#06x}", descriptor.vendor_id()))
+                .field("product_id", &format!("{:#06x}", descriptor.product_id()))
+                .field("class", &descriptor.device_class())
+                .field("subclass", &descriptor.device_sublcass())
+                .field("protocol", &descriptor.device_protocol())
+                .field(
+                    "usb_version",
+                    &format!("{:x}", descriptor.bcd_usb_version()),
+                );
+        });
+    }
+}
+
+impl ToJson for HeapStats {
+    fn write_json(&self, out: &mut String) {
+        write_object(out, |o| {
+            o.field("free_bytes", &self.free_bytes)
+                .field("free_segments", &self.free_segments)
+                .field("largest_free_segment", &self.largest_free_segment);
+        });
+    }
+}
+
+impl ToJson for CpuSummary {
+    fn write_json(&self, out: &mut String) {
+        write_object(out, |o| {
+            o.field("cpus", &self.cpus)
+                .field("current", &self.current)
+                .field("uptime_s", &self.uptime_s);
+        });
+    }
+}
+
+impl ToJson for DateTime {
+    fn write_json(&self, out: &mut String) {
+        let year = self.century as u16 * 100 + self.year as u16;
+        write_object(out, |o| {
+            o.field("year", &year)
+                .field("month", &self.month)
+                .field("day", &self.day)
+                .field("hours", &self.hours)
+                .field("minutes", &self.minutes)
+                .field("seconds", &self.seconds)
+                .field("weekday", &self.weekday);
+        });
+    }
+}
@@ -0,0 +1,251 @@
+use super::{Request, Response, StatusCode};
+
+use alloc::{format, string::String, vec::Vec};
+use core::ops::Range;
+
+/// File from res/www, embedded at build time by build.rs
+pub struct Asset {
+    // Where it's served, e.g. /index.html
+    pub path: &'static str,
+    pub data: &'static [u8],
+    // Quoted, ready to go in an ETag header
+    pub etag: &'static str,
+}
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+#[derive(Debug, Eq, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial(Range<usize>),
+    Unsatisfiable,
+}
+
+/// Asset served at path. Directories are served by their index.html
+pub fn find(path: &str) -> Option<&'static Asset> {
+    let index: String;
+    let path = if path.ends_with('/') {
+        index = format!("{}index.html", path);
+        &index
+    } else {
+        path
+    };
+    ASSETS.iter().find(|asset| asset.path == path)
+}
+
+/// Content-Type for the file at path, going by its extension
+pub fn mime_type(path: &str) -> &'static str {
+    let extension = match path.rsplit_once('.') {
+        Some((_, extension)) => extension.to_ascii_lowercase(),
+        None => return "application/octet-stream",
+    };
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Answers a GET for one of the assets. Clients that already have the current version get a
+/// 304, Range requests get just the part they asked for
+pub fn handle(request: &Request) -> Response {
+    let asset = match find(&request.path) {
+        Some(asset) => asset,
+        None => return Response::from_status(StatusCode::NotFound),
+    };
+    let content_type = mime_type(asset.path);
+    let len = asset.data.len();
+
+    if let Some(if_none_match) = request.headers.get("If-None-Match") {
+        if etag_matches(if_none_match, asset.etag) {
+            let mut response = Response::new(StatusCode::NotModified, content_type, Vec::new());
+            response.headers.set("ETag", asset.etag);
+            return response;
+        }
+    }
+
+    // A Range is only for the version of the asset named in If-Range, if there is one
+    let if_range_matches = match request.headers.get("If-Range") {
+        Some(etag) => etag.trim() == asset.etag,
+        None => true,
+    };
+    let range = match request.headers.get("Range") {
+        Some(range) if if_range_matches => parse_range(range, len),
+        _ => ByteRange::Full,
+    };
+
+    let mut response = match range {
+        ByteRange::Full => Response::new(StatusCode::Ok, content_type, asset.data),
+        ByteRange::Partial(range) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, len);
+            let mut response =
+                Response::new(StatusCode::PartialContent, content_type, &asset.data[range]);
+            response.headers.set("Content-Range", &content_range);
+            response
+        }
+        ByteRange::Unsatisfiable => {
+            let mut response = Response::from_status(StatusCode::RangeNotSatisfiable);
+            response
+                .headers
+                .set("Content-Range", &format!("bytes */{}", len));
+            return response;
+        }
+    };
+
+    response.headers.set("ETag", asset.etag);
+    response.headers.set("Accept-Ranges", "bytes");
+    // Cache, but check the etag before using the cached copy
+    response.headers.set("Cache-Control", "no-cache");
+    response
+}
+
+// If-None-Match uses the weak comparison (RFC 9110 13.1.2)
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.trim() == "*"
+        || header.split(',').any(|candidate| {
+            let candidate = candidate.trim();
+            candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+        })
+}
+
+// A single byte range from a Range header (RFC 9110 14.1.2). Anything else, e.g. several
+// ranges, gets the whole asset
+fn parse_range(header: &str, len: usize) -> ByteRange {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(v) => v,
+        None => return ByteRange::Full,
+    };
+    let parse = |s: &str| {
+        if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+            s.parse::<usize>().ok()
+        } else {
+            None
+        }
+    };
+
+    // bytes=-n is the last n bytes
+    if start.is_empty() {
+        return match parse(end) {
+            Some(0) => ByteRange::Unsatisfiable,
+            Some(_) if len == 0 => ByteRange::Unsatisfiable,
+            Some(suffix) => ByteRange::Partial(len.saturating_sub(suffix)..len),
+            None => ByteRange::Full,
+        };
+    }
+
+    let start = match parse(start) {
+        Some(start) => start,
+        None => return ByteRange::Full,
+    };
+    let end = match end {
+        "" => usize::MAX,
+        end => match parse(end) {
+            Some(end) if end >= start => end,
+            _ => return ByteRange::Full,
+        },
+    };
+
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start..end.min(len - 1) + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        http::{Headers, Method, Version},
+        testing::*,
+    };
+    use alloc::string::ToString;
+
+    fn get(path: &str, headers: &[(&str, &str)]) -> Request {
+        let mut request = Request {
+            method: Method::Get,
+            path: path.to_string(),
+            query: None,
+            version: Version::Http11,
+            headers: Headers::default(),
+            body: Vec::new(),
+        };
+        for (name, value) in headers {
+            request.headers.append(name, value);
+        }
+        request
+    }
+
+    create_test!(test_parse_range, {
+        test_eq!(parse_range("bytes=0-4", 10), ByteRange::Partial(0..5));
+        test_eq!(parse_range("bytes=5-", 10), ByteRange::Partial(5..10));
+        test_eq!(parse_range("bytes=5-100", 10), ByteRange::Partial(5..10));
+        test_eq!(parse_range("bytes=-3", 10), ByteRange::Partial(7..10));
+        test_eq!(parse_range("bytes=-30", 10), ByteRange::Partial(0..10));
+        test_eq!(parse_range("bytes=10-", 10), ByteRange::Unsatisfiable);
+        test_eq!(parse_range("bytes=-0", 10), ByteRange::Unsatisfiable);
+        test_eq!(parse_range("bytes=0-1,4-5", 10), ByteRange::Full);
+        test_eq!(parse_range("bytes=4-1", 10), ByteRange::Full);
+        test_eq!(parse_range("items=0-1", 10), ByteRange::Full);
+
+        test_eq!(mime_type("/style.CSS"), "text/css; charset=utf-8");
+        test_eq!(mime_type("/README"), "application/octet-stream");
+
+        Ok(())
+    });
+
+    create_test!(test_asset_responses, {
+        let index = find("/").ok_or("no index.html")?;
+        test_eq!(index.path, "/index.html");
+        test_true!(find("/missing.html").is_none());
+
+        let response = handle(&get("/index.html", &[]));
+        test_eq!(response.status, StatusCode::Ok);
+        test_eq!(response.body, index.data);
+        test_eq!(response.headers.get("ETag"), Some(index.etag));
+        test_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        let weak_etag = format!("\"other\", W/{}", index.etag);
+        let response = handle(&get("/", &[("If-None-Match", &weak_etag)]));
+        test_eq!(response.status, StatusCode::NotModified);
+
+        let response = handle(&get("/", &[("Range", "bytes=0-8")]));
+        test_eq!(response.status, StatusCode::PartialContent);
+        test_eq!(response.body, &index.data[..9]);
+        let content_range = format!("bytes 0-8/{}", index.data.len());
+        test_eq!(
+            response.headers.get("Content-Range"),
+            Some(content_range.as_str())
+        );
+
+        // The client's copy is out of date, so it gets all of the new one
+        let response = handle(&get(
+            "/",
+            &[("Range", "bytes=0-8"), ("If-Range", "\"stale\"")],
+        ));
+        test_eq!(response.status, StatusCode::Ok);
+
+        let response = handle(&get("/", &[("Range", "bytes=999999-")]));
+        test_eq!(response.status, StatusCode::RangeNotSatisfiable);
+
+        Ok(())
+    });
+}
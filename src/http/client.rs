@@ -0,0 +1,463 @@
+use super::{
+    parser::{self, ParseError},
+    Method, Response, StatusCode,
+};
+
+use crate::{
+    future::{select, Either},
+    net::{
+        tcp::{ConnectError, TcpError},
+        NetStack, NetworkInterface,
+    },
+    util::async_io::AsyncRead,
+    IpAddr,
+};
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{fmt::Write, pin::pin};
+
+// Redirects followed before giving up on a request
+const MAX_REDIRECTS: usize = 5;
+// For get and post, covers the whole exchange including redirects
+const TIMEOUT_S: f32 = 10.0;
+const READ_SIZE: usize = 2048;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ClientError {
+    // Not an http url with an ipv4 address for a host, there's no dns to look names up with
+    InvalidUrl,
+    // The content type has characters that can't go in a header
+    InvalidContentType,
+    Connect(ConnectError),
+    Tcp(TcpError),
+    // The response wasn't http we understand
+    Parse(ParseError),
+    // The server closed the connection partway through its response
+    Incomplete,
+    TooManyRedirects,
+    Timeout,
+    // The server switched protocols, which we never ask it to
+    UnexpectedUpgrade,
+}
+
+impl From<TcpError> for ClientError {
+    fn from(error: TcpError) -> Self {
+        ClientError::Tcp(error)
+    }
+}
+
+impl From<ParseError> for ClientError {
+    fn from(error: ParseError) -> Self {
+        ClientError::Parse(error)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Url {
+    ip: IpAddr,
+    port: u16,
+    // Path and query, as they go in the request line
+    target: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url, ClientError> {
+        let rest = match url.get(..7) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &url[7..],
+            _ => return Err(ClientError::InvalidUrl),
+        };
+        let rest = without_fragment(rest);
+
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, target) = rest.split_at(authority_end);
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| ClientError::InvalidUrl)?),
+            None => (authority, 80),
+        };
+        let ip = parse_ip(host).ok_or(ClientError::InvalidUrl)?;
+
+        let target = match target {
+            "" => "/".to_string(),
+            target if target.starts_with('?') => format!("/{}", target),
+            target => target.to_string(),
+        };
+        validate_target(&target)?;
+        Ok(Url { ip, port, target })
+    }
+
+    // Resolves the Location of a redirect against this url (RFC 3986 5.2), without removing
+    // dot segments, the server can do that
+    fn join(&self, location: &str) -> Result<Url, ClientError> {
+        let location = without_fragment(location.trim());
+        if location.starts_with("//") {
+            return Url::parse(&format!("http:{}", location));
+        }
+        if has_scheme(location) {
+            return Url::parse(location);
+        }
+
+        let path = match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.target,
+        };
+        let target = if location.starts_with('/') {
+            location.to_string()
+        } else if location.starts_with('?') {
+            format!("{}{}", path, location)
+        } else {
+            // Relative to the directory the current path is in
+            let dir_end = path.rfind('/').map(|pos| pos + 1).unwrap_or(0);
+            format!("{}{}", &path[..dir_end], location)
+        };
+        validate_target(&target)?;
+
+        Ok(Url {
+            ip: self.ip,
+            port: self.port,
+            target,
+        })
+    }
+
+    // Value for the Host header, which leaves the port out when it's the default
+    fn host(&self) -> String {
+        let [a, b, c, d] = self.ip;
+        match self.port {
+            80 => format!("{}.{}.{}.{}", a, b, c, d),
+            port => format!("{}.{}.{}.{}:{}", a, b, c, d, port),
+        }
+    }
+}
+
+/// GETs url, following redirects. A response with an error status is still a response, only
+/// not getting one at all is an error
+pub async fn get<I: NetworkInterface>(
+    net: &NetStack<I>,
+    url: &str,
+) -> Result<Response, ClientError> {
+    request(net, Method::Get, url, None, TIMEOUT_S).await
+}
+
+/// POSTs data to url as content_type, following redirects
+pub async fn post<I: NetworkInterface>(
+    net: &NetStack<I>,
+    url: &str,
+    content_type: &str,
+    data: &[u8],
+) -> Result<Response, ClientError> {
+    request(
+        net,
+        Method::Post,
+        url,
+        Some((content_type, data)),
+        TIMEOUT_S,
+    )
+    .await
+}
+
+/// Sends a method request to url, with body as its content type and data if there is one. Each
+/// request gets its own connection. Fails with Timeout if there's no final response after
+/// timeout_s
+pub async fn request<I: NetworkInterface>(
+    net: &NetStack<I>,
+    method: Method,
+    url: &str,
+    body: Option<(&str, &[u8])>,
+    timeout_s: f32,
+) -> Result<Response, ClientError> {
+    let url = Url::parse(url)?;
+    if let Some((content_type, _)) = body {
+        validate_content_type(content_type)?;
+    }
+    let exchange = pin!(follow_redirects(net, method, url, body));
+    let timeout = pin!(net.sleep(timeout_s));
+    match select(exchange, timeout).await {
+        Either::Left((response, _)) => response,
+        Either::Right(_) => Err(ClientError::Timeout),
+    }
+}
+
+async fn follow_redirects<I: NetworkInterface>(
+    net: &NetStack<I>,
+    mut method: Method,
+    mut url: Url,
+    mut body: Option<(&str, &[u8])>,
+) -> Result<Response, ClientError> {
+    for _ in 0..=MAX_REDIRECTS {
+        let response = fetch(net, method, &url, body).await?;
+        let location = response.headers.get("Location");
+        let (next_method, location) = match (redirect_method(response.status, method), location) {
+            (Some(next_method), Some(location)) => (next_method, location),
+            _ => return Ok(response),
+        };
+
+        debug!(
+            "Following {} redirect to {}",
+            u16::from(response.status),
+            location
+        );
+        url = url.join(location)?;
+        if next_method != method {
+            method = next_method;
+            body = None;
+        }
+    }
+    Err(ClientError::TooManyRedirects)
+}
+
+// One request and its response, on a connection of its own
+async fn fetch<I: NetworkInterface>(
+    net: &NetStack<I>,
+    method: Method,
+    url: &Url,
+    body: Option<(&str, &[u8])>,
+) -> Result<Response, ClientError> {
+    let connection = net
+        .tcp()
+        .connect(url.ip, url.port)
+        .await
+        .map_err(ClientError::Connect)?;
+    connection.write(request_bytes(method, url, body)).await?;
+
+    let mut data = Vec::new();
+    let mut closed = false;
+    loop {
+        match parser::parse_response(&data, method, closed)? {
+            // Whatever follows isn't http anymore
+            Some((response, _)) if response.status == StatusCode::SwitchingProtocols => {
+                return Err(ClientError::UnexpectedUpgrade)
+            }
+            // Interim responses, e.g. a 100 Continue, come before the real one
+            Some((response, used)) if u16::from(response.status) < 200 => {
+                data.drain(..used);
+                continue;
+            }
+            Some((response, _)) => return Ok(response),
+            None if closed => return Err(ClientError::Incomplete),
+            None => (),
+        }
+
+        let mut buf = vec![0; READ_SIZE];
+        match connection.read(&mut buf).await? {
+            0 => closed = true,
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+// Asks the server to close the connection after answering, since it isn't used again
+fn request_bytes(method: Method, url: &Url, body: Option<(&str, &[u8])>) -> Vec<u8> {
+    let mut head = String::new();
+    // Writing to a String can't fail
+    let _ = write!(
+        head,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: stream-os\r\nConnection: close\r\n",
+        method.as_str(),
+        url.target,
+        url.host()
+    );
+    if let Some((content_type, data)) = body {
+        let _ = write!(
+            head,
+            "Content-Type: {}\r\nContent-Length: {}\r\n",
+            content_type,
+            data.len()
+        );
+    }
+    head.push_str("\r\n");
+
+    let mut request = head.into_bytes();
+    if let Some((_, data)) = body {
+        request.extend_from_slice(data);
+    }
+    request
+}
+
+// Method to follow a redirect with, None if status isn't a redirect. 307 and 308 repeat the
+// request as it was, the older ones turn a POST into a GET like browsers do (RFC 9110 15.4)
+fn redirect_method(status: StatusCode, method: Method) -> Option<Method> {
+    match status {
+        StatusCode::MovedPermanently | StatusCode::Found if method == Method::Post => {
+            Some(Method::Get)
+        }
+        StatusCode::SeeOther if method != Method::Head => Some(Method::Get),
+        StatusCode::MovedPermanently
+        | StatusCode::Found
+        | StatusCode::SeeOther
+        | StatusCode::TemporaryRedirect
+        | StatusCode::PermanentRedirect => Some(method),
+        _ => None,
+    }
+}
+
+fn parse_ip(host: &str) -> Option<IpAddr> {
+    let mut ip = [0; 4];
+    let mut parts = host.split('.');
+    for byte in &mut ip {
+        let part = parts.next()?;
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        *byte = part.parse().ok()?;
+    }
+
+    match parts.next() {
+        Some(_) => None,
+        None => Some(ip),
+    }
+}
+
+// Whether url starts with a scheme, e.g. http: or mailto:
+fn has_scheme(url: &str) -> bool {
+    match url.split_once(':') {
+        Some((scheme, _)) => {
+            !scheme.is_empty()
+                && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"+-.".contains(&b))
+        }
+        None => false,
+    }
+}
+
+// The fragment is only for the client, it isn't sent
+fn without_fragment(url: &str) -> &str {
+    match url.split_once('#') {
+        Some((url, _)) => url,
+        None => url,
+    }
+}
+
+fn validate_target(target: &str) -> Result<(), ClientError> {
+    if target.bytes().all(|b| b.is_ascii_graphic()) {
+        Ok(())
+    } else {
+        Err(ClientError::InvalidUrl)
+    }
+}
+
+// A crlf in a header value would let it add headers of its own
+fn validate_content_type(content_type: &str) -> Result<(), ClientError> {
+    if content_type
+        .bytes()
+        .all(|b| b.is_ascii_graphic() || b == b' ' || b == b'\t')
+    {
+        Ok(())
+    } else {
+        Err(ClientError::InvalidContentType)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    fn url(ip: IpAddr, port: u16, target: &str) -> Url {
+        Url {
+            ip,
+            port,
+            target: target.to_string(),
+        }
+    }
+
+    create_test!(test_parse_url, {
+        test_eq!(
+            Url::parse("http://192.168.2.1:8000/results?run=1#top").ok(),
+            Some(url([192, 168, 2, 1], 8000, "/results?run=1"))
+        );
+        test_eq!(
+            Url::parse("HTTP://10.0.0.1").ok(),
+            Some(url([10, 0, 0, 1], 80, "/"))
+        );
+        test_eq!(
+            Url::parse("http://10.0.0.1?a=b").ok(),
+            Some(url([10, 0, 0, 1], 80, "/?a=b"))
+        );
+
+        for invalid in [
+            "https://10.0.0.1/",
+            "http://example.com/",
+            "http://10.0.0.256/",
+            "http://10.0.0.1:99999/",
+            "http://10.0.0/",
+            "http://10.0.0.1/a b",
+        ] {
+            test_eq!(Url::parse(invalid).err(), Some(ClientError::InvalidUrl));
+        }
+
+        let base = url([10, 0, 0, 1], 8000, "/files/a.txt?x=1");
+        test_eq!(base.host(), "10.0.0.1:8000");
+        test_eq!(
+            base.join("/other").ok(),
+            Some(url([10, 0, 0, 1], 8000, "/other"))
+        );
+        test_eq!(
+            base.join("b.txt#frag").ok(),
+            Some(url([10, 0, 0, 1], 8000, "/files/b.txt"))
+        );
+        test_eq!(
+            base.join("?x=2").ok(),
+            Some(url([10, 0, 0, 1], 8000, "/files/a.txt?x=2"))
+        );
+        test_eq!(
+            base.join("//10.0.0.2/").ok(),
+            Some(url([10, 0, 0, 2], 80, "/"))
+        );
+        test_eq!(
+            base.join("http://10.0.0.3:81/c").ok(),
+            Some(url([10, 0, 0, 3], 81, "/c"))
+        );
+        test_eq!(
+            base.join("https://10.0.0.3/").err(),
+            Some(ClientError::InvalidUrl)
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_client_requests, {
+        let target = url([10, 0, 0, 1], 80, "/report");
+        test_eq!(
+            request_bytes(Method::Post, &target, Some(("text/plain", b"ok"))),
+            b"POST /report HTTP/1.1\r\n\
+              Host: 10.0.0.1\r\n\
+              User-Agent: stream-os\r\n\
+              Connection: close\r\n\
+              Content-Type: text/plain\r\n\
+              Content-Length: 2\r\n\
+              \r\n\
+              ok"
+        );
+
+        test_true!(validate_content_type("text/plain; charset=utf-8").is_ok());
+        test_eq!(
+            validate_content_type("text/plain\r\nX-Injected: 1").err(),
+            Some(ClientError::InvalidContentType)
+        );
+
+        test_eq!(
+            redirect_method(StatusCode::Found, Method::Post),
+            Some(Method::Get)
+        );
+        test_eq!(
+            redirect_method(StatusCode::SeeOther, Method::Head),
+            Some(Method::Head)
+        );
+        test_eq!(
+            redirect_method(StatusCode::TemporaryRedirect, Method::Post),
+            Some(Method::Post)
+        );
+        test_eq!(
+            redirect_method(StatusCode::NotModified, Method::Get),
+            None::<Method>
+        );
+
+        Ok(())
+    });
+}
@@ -0,0 +1,311 @@
+use super::StatusCode;
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FormError {
+    // A % not followed by two hex digits, or a multipart body that doesn't follow its boundary
+    Malformed,
+    InvalidUtf8,
+    // The body isn't in one of the form encodings
+    UnsupportedContentType,
+    // A field didn't parse as the type it was asked for as
+    InvalidValue,
+}
+
+impl FormError {
+    /// Status to answer the request with
+    pub fn status(&self) -> StatusCode {
+        match self {
+            FormError::UnsupportedContentType => StatusCode::UnsupportedMediaType,
+            _ => StatusCode::BadRequest,
+        }
+    }
+}
+
+/// File sent in a multipart/form-data body
+pub struct FormFile {
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// Fields of a submitted form or query string, already decoded
+#[derive(Default)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+    files: Vec<(String, FormFile)>,
+}
+
+impl Form {
+    /// Value of the first field called name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of the first field called name parsed as a T, None if there's no such field
+    pub fn value<T: FromStr>(&self, name: &str) -> Result<Option<T>, FormError> {
+        self.get(name)
+            .map(|value| value.trim().parse().map_err(|_| FormError::InvalidValue))
+            .transpose()
+    }
+
+    pub fn file(&self, name: &str) -> Option<&FormFile> {
+        self.files
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, file)| file)
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+/// Replaces %XX escapes with the byte they stand for, and + with a space if plus_as_space is
+/// set, as it is in form bodies and query strings
+pub fn percent_decode(data: &[u8], plus_as_space: bool) -> Result<Vec<u8>, FormError> {
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [
+                    *bytes.next().ok_or(FormError::Malformed)?,
+                    *bytes.next().ok_or(FormError::Malformed)?,
+                ];
+                let hex = core::str::from_utf8(&hex).map_err(|_| FormError::Malformed)?;
+                decoded.push(u8::from_str_radix(hex, 16).map_err(|_| FormError::Malformed)?);
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(*b),
+        }
+    }
+    Ok(decoded)
+}
+
+/// Parses name=value pairs separated by &, as sent in query strings and
+/// application/x-www-form-urlencoded bodies
+pub fn parse_urlencoded(data: &[u8]) -> Result<Form, FormError> {
+    let mut form = Form::default();
+    for pair in data.split(|b| *b == b'&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = match pair.iter().position(|b| *b == b'=') {
+            Some(pos) => (&pair[..pos], &pair[pos + 1..]),
+            None => (pair, &b""[..]),
+        };
+        form.fields
+            .push((decode_string(name, true)?, decode_string(value, true)?));
+    }
+    Ok(form)
+}
+
+/// Parses a multipart/form-data body (RFC 7578) whose parts are separated by boundary. Parts
+/// with a filename are files, the rest must be text
+pub fn parse_multipart(data: &[u8], boundary: &str) -> Result<Form, FormError> {
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    let mut form = Form::default();
+
+    // The first delimiter doesn't need the crlf in front of it, anything before it is ignored
+    let mut rest = match find(data, &delimiter[2..]) {
+        Some(0) => &data[delimiter.len() - 2..],
+        _ => {
+            let start = find(data, &delimiter).ok_or(FormError::Malformed)?;
+            &data[start + delimiter.len()..]
+        }
+    };
+
+    while !rest.starts_with(b"--") {
+        rest = rest.strip_prefix(b"\r\n").ok_or(FormError::Malformed)?;
+        let end = find(rest, &delimiter).ok_or(FormError::Malformed)?;
+        let part = &rest[..end];
+        rest = &rest[end + delimiter.len()..];
+
+        // Parts are allowed to have no headers at all, leaving just the empty line
+        let (head, content) = match part.strip_prefix(b"\r\n") {
+            Some(content) => (&b""[..], content),
+            None => {
+                let head_end = find(part, b"\r\n\r\n").ok_or(FormError::Malformed)?;
+                (&part[..head_end], &part[head_end + 4..])
+            }
+        };
+
+        let head = core::str::from_utf8(head).map_err(|_| FormError::InvalidUtf8)?;
+        let mut name = None;
+        let mut filename = None;
+        let mut content_type = None;
+        for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+            let (key, value) = line.split_once(':').ok_or(FormError::Malformed)?;
+            if key.eq_ignore_ascii_case("Content-Disposition") {
+                for (param, param_value) in parameters(value) {
+                    if param.eq_ignore_ascii_case("name") {
+                        name = Some(param_value);
+                    } else if param.eq_ignore_ascii_case("filename") {
+                        filename = Some(param_value);
+                    }
+                }
+            } else if key.eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.trim().to_string());
+            }
+        }
+
+        let name = name.ok_or(FormError::Malformed)?;
+        match filename {
+            Some(filename) => form.files.push((
+                name,
+                FormFile {
+                    filename,
+                    content_type,
+                    data: content.to_vec(),
+                },
+            )),
+            None => {
+                let value =
+                    String::from_utf8(content.to_vec()).map_err(|_| FormError::InvalidUtf8)?;
+                form.fields.push((name, value));
+            }
+        }
+    }
+
+    Ok(form)
+}
+
+/// The key=value parameters after the first ; in a header value, e.g. the boundary in
+/// Content-Type or the name in Content-Disposition. Quotes around values are removed
+pub fn parameters(header_value: &str) -> Vec<(String, String)> {
+    // Split on semicolons that aren't inside a quoted value
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in header_value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                items.push(&header_value[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    items.push(&header_value[start..]);
+
+    items
+        .into_iter()
+        .skip(1)
+        .filter_map(|item| {
+            let (key, value) = item.split_once('=')?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            Some((key.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+fn decode_string(data: &[u8], plus_as_space: bool) -> Result<String, FormError> {
+    String::from_utf8(percent_decode(data, plus_as_space)?).map_err(|_| FormError::InvalidUtf8)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    create_test!(test_urlencoded_form, {
+        test_eq!(
+            percent_decode(b"a%20b+c%2fd", true).ok(),
+            Some(b"a b c/d".to_vec())
+        );
+        test_eq!(percent_decode(b"a+b", false).ok(), Some(b"a+b".to_vec()));
+        test_eq!(
+            percent_decode(b"%4", false).err(),
+            Some(FormError::Malformed)
+        );
+        test_eq!(
+            percent_decode(b"%zz", false).err(),
+            Some(FormError::Malformed)
+        );
+
+        let form = parse_urlencoded(b"data=hello+world%21&n=%2042&empty&&n=7")
+            .map_err(|e| format!("{:?}", e))?;
+        test_eq!(form.get("data"), Some("hello world!"));
+        test_eq!(form.get("empty"), Some(""));
+        test_eq!(form.value::<u32>("n").ok(), Some(Some(42)));
+        test_eq!(form.value::<u32>("missing").ok(), Some(None::<u32>));
+        test_eq!(
+            form.value::<u32>("data").err(),
+            Some(FormError::InvalidValue)
+        );
+
+        test_eq!(
+            parse_urlencoded(b"data=%ff").err(),
+            Some(FormError::InvalidUtf8)
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_multipart_form, {
+        let boundary = "----b0undary";
+        let data = b"preamble\r\n\
+                     ------b0undary\r\n\
+                     Content-Disposition: form-data; name=\"data\"\r\n\
+                     \r\n\
+                     hi there\r\n\
+                     ------b0undary\r\n\
+                     Content-Disposition: form-data; name=\"file\"; filename=\"a;b.bin\"\r\n\
+                     Content-Type: application/octet-stream\r\n\
+                     \r\n\
+                     \x00\xff\r\n--\r\n\
+                     ------b0undary--\r\n";
+
+        let form = parse_multipart(data, boundary).map_err(|e| format!("{:?}", e))?;
+        test_eq!(form.get("data"), Some("hi there"));
+        let file = form.file("file").ok_or("no file")?;
+        test_eq!(file.filename, "a;b.bin");
+        test_eq!(
+            file.content_type.as_deref(),
+            Some("application/octet-stream")
+        );
+        test_eq!(file.data, b"\x00\xff\r\n--");
+
+        // Text fields have to be utf-8, the last delimiter has to be there
+        let bad_text = b"--b\r\nContent-Disposition: form-data; name=x\r\n\r\n\xff\r\n--b--";
+        test_eq!(
+            parse_multipart(bad_text, "b").err(),
+            Some(FormError::InvalidUtf8)
+        );
+        let unterminated = b"--b\r\nContent-Disposition: form-data; name=x\r\n\r\nabc";
+        test_eq!(
+            parse_multipart(unterminated, "b").err(),
+            Some(FormError::Malformed)
+        );
+
+        test_eq!(
+            parameters("multipart/form-data; boundary=\"x;y\"; charset=utf-8"),
+            [
+                ("boundary".to_string(), "x;y".to_string()),
+                ("charset".to_string(), "utf-8".to_string())
+            ]
+        );
+
+        Ok(())
+    });
+}
@@ -0,0 +1,418 @@
+pub mod assets;
+pub mod client;
+pub mod form;
+pub mod parser;
+mod router;
+mod server;
+pub mod sse;
+pub mod websocket;
+
+pub use client::{get, post};
+pub use router::Router;
+pub use server::serve;
+
+use form::{Form, FormError};
+
+use crate::{net::tcp::TcpConnection, util::json::ToJson};
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{fmt::Write, future::Future, pin::Pin};
+
+type Upgrade = Box<dyn FnOnce(TcpConnection) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+}
+
+impl Method {
+    pub fn parse(data: &[u8]) -> Option<Method> {
+        let method = match data {
+            b"GET" => Method::Get,
+            b"HEAD" => Method::Head,
+            b"POST" => Method::Post,
+            b"PUT" => Method::Put,
+            b"DELETE" => Method::Delete,
+            b"OPTIONS" => Method::Options,
+            b"PATCH" => Method::Patch,
+            _ => return None,
+        };
+        Some(method)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StatusCode {
+    Continue,
+    SwitchingProtocols,
+    Ok,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    LengthRequired,
+    PayloadTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    UpgradeRequired,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    HttpVersionNotSupported,
+    Unknown(u16),
+}
+
+impl StatusCode {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            StatusCode::Continue => "Continue",
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Ok => "OK",
+            StatusCode::NoContent => "No Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::Found => "Found",
+            StatusCode::SeeOther => "See Other",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::LengthRequired => "Length Required",
+            StatusCode::PayloadTooLarge => "Payload Too Large",
+            StatusCode::UriTooLong => "URI Too Long",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::UpgradeRequired => "Upgrade Required",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::NotImplemented => "Not Implemented",
+            StatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
+            StatusCode::Unknown(_) => "Unknown",
+        }
+    }
+
+    // Informational responses, 204 and 304 never carry a body (RFC 9112 6.3)
+    fn allows_body(&self) -> bool {
+        let code = u16::from(*self);
+        code >= 200 && code != 204 && code != 304
+    }
+}
+
+impl core::convert::From<u16> for StatusCode {
+    fn from(value: u16) -> Self {
+        match value {
+            100 => StatusCode::Continue,
+            101 => StatusCode::SwitchingProtocols,
+            200 => StatusCode::Ok,
+            204 => StatusCode::NoContent,
+            206 => StatusCode::PartialContent,
+            301 => StatusCode::MovedPermanently,
+            302 => StatusCode::Found,
+            303 => StatusCode::SeeOther,
+            304 => StatusCode::NotModified,
+            307 => StatusCode::TemporaryRedirect,
+            308 => StatusCode::PermanentRedirect,
+            400 => StatusCode::BadRequest,
+            404 => StatusCode::NotFound,
+            405 => StatusCode::MethodNotAllowed,
+            408 => StatusCode::RequestTimeout,
+            411 => StatusCode::LengthRequired,
+            413 => StatusCode::PayloadTooLarge,
+            414 => StatusCode::UriTooLong,
+            415 => StatusCode::UnsupportedMediaType,
+            416 => StatusCode::RangeNotSatisfiable,
+            426 => StatusCode::UpgradeRequired,
+            431 => StatusCode::RequestHeaderFieldsTooLarge,
+            500 => StatusCode::InternalServerError,
+            501 => StatusCode::NotImplemented,
+            505 => StatusCode::HttpVersionNotSupported,
+            v => StatusCode::Unknown(v),
+        }
+    }
+}
+
+impl core::convert::From<StatusCode> for u16 {
+    fn from(value: StatusCode) -> Self {
+        match value {
+            StatusCode::Continue => 100,
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::Ok => 200,
+            StatusCode::NoContent => 204,
+            StatusCode::PartialContent => 206,
+            StatusCode::MovedPermanently => 301,
+            StatusCode::Found => 302,
+            StatusCode::SeeOther => 303,
+            StatusCode::NotModified => 304,
+            StatusCode::TemporaryRedirect => 307,
+            StatusCode::PermanentRedirect => 308,
+            StatusCode::BadRequest => 400,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::LengthRequired => 411,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::UriTooLong => 414,
+            StatusCode::UnsupportedMediaType => 415,
+            StatusCode::RangeNotSatisfiable => 416,
+            StatusCode::UpgradeRequired => 426,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::HttpVersionNotSupported => 505,
+            StatusCode::Unknown(v) => v,
+        }
+    }
+}
+
+/// Header fields in the order they were sent. Names are matched case insensitively
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// Value of the first header called name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All values of headers called name, repeated headers are allowed for lists
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether any header called name has token in its comma separated list of values, e.g.
+    /// "close" in Connection
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_string(), value.to_string()));
+    }
+
+    /// Replaces any headers called name
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.0.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.append(name, value);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+}
+
+pub struct Request {
+    pub method: Method,
+    // Percent decoded
+    pub path: String,
+    // As sent, see query_form
+    pub query: Option<String>,
+    pub version: Version,
+    pub headers: Headers,
+    // With any chunked transfer coding already removed
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Whether the client wants to send more requests on the connection after this one
+    /// (RFC 9112 9.3)
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http10 => self.headers.contains_token("Connection", "keep-alive"),
+            Version::Http11 => !self.headers.contains_token("Connection", "close"),
+        }
+    }
+
+    /// Fields in the query string
+    pub fn query_form(&self) -> Result<Form, FormError> {
+        form::parse_urlencoded(self.query.as_deref().unwrap_or("").as_bytes())
+    }
+
+    /// Fields of a submitted form. GET and HEAD forms come in the query string, others in a
+    /// urlencoded or multipart body
+    pub fn form(&self) -> Result<Form, FormError> {
+        if matches!(self.method, Method::Get | Method::Head) {
+            return self.query_form();
+        }
+
+        let content_type = self.headers.get("Content-Type").unwrap_or("");
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        if media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            form::parse_urlencoded(&self.body)
+        } else if media_type.eq_ignore_ascii_case("multipart/form-data") {
+            let boundary = form::parameters(content_type)
+                .into_iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case("boundary"))
+                .map(|(_, boundary)| boundary)
+                .ok_or(FormError::Malformed)?;
+            form::parse_multipart(&self.body, &boundary)
+        } else {
+            Err(FormError::UnsupportedContentType)
+        }
+    }
+}
+
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    // Takes the connection over once the head has been sent
+    upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    pub fn new<T: Into<Vec<u8>>>(status: StatusCode, content_type: &str, body: T) -> Response {
+        let mut headers = Headers::default();
+        headers.set("Content-Type", content_type);
+        Response {
+            status,
+            headers,
+            body: body.into(),
+            upgrade: None,
+        }
+    }
+
+    /// Plain text response that just states the status, for errors
+    pub fn from_status(status: StatusCode) -> Response {
+        let body = format!("{} {}\n", u16::from(status), status.reason());
+        Response::new(status, "text/plain", body)
+    }
+
+    /// 200 with value serialized as the body
+    pub fn json<T: ToJson + ?Sized>(value: &T) -> Response {
+        Response::new(StatusCode::Ok, "application/json", value.to_json())
+    }
+
+    /// Hands the connection to f once the head has been sent, f sends whatever comes after it.
+    /// The connection is closed when f is done
+    pub fn with_upgrade<F, Fut>(mut self, f: F) -> Response
+    where
+        F: FnOnce(TcpConnection) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.upgrade = Some(Box::new(move |connection| Box::pin(f(connection))));
+        self
+    }
+
+    /// Status line, headers and body as sent on the wire. The body is left out for HEAD
+    /// requests, Content-Length still says how long it would have been
+    pub fn to_bytes(&self, include_body: bool, keep_alive: bool) -> Vec<u8> {
+        let mut head = String::new();
+        // Writing to a String can't fail
+        let _ = write!(
+            head,
+            "HTTP/1.1 {} {}\r\n",
+            u16::from(self.status),
+            self.status.reason()
+        );
+        for (name, value) in self.headers.iter() {
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        }
+        // An upgrade's body goes on until it closes the connection
+        if self.status.allows_body()
+            && self.upgrade.is_none()
+            && self.headers.get("Content-Length").is_none()
+        {
+            let _ = write!(head, "Content-Length: {}\r\n", self.body.len());
+        }
+        // Unless the response has its own, e.g. to switch protocols
+        if self.headers.get("Connection").is_none() {
+            let connection = if keep_alive { "keep-alive" } else { "close" };
+            let _ = write!(head, "Connection: {}\r\n", connection);
+        }
+        head.push_str("\r\n");
+
+        let mut data = head.into_bytes();
+        if include_body && self.status.allows_body() {
+            data.extend_from_slice(&self.body);
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    create_test!(test_http_response_bytes, {
+        let response = Response::new(StatusCode::Ok, "text/html", "<p>hi</p>");
+        test_eq!(
+            response.to_bytes(true, true),
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: text/html\r\n\
+              Content-Length: 9\r\n\
+              Connection: keep-alive\r\n\
+              \r\n\
+              <p>hi</p>"
+        );
+
+        // HEAD gets the length of the body it would have had
+        let head = response.to_bytes(false, false);
+        test_true!(head.ends_with(b"Content-Length: 9\r\nConnection: close\r\n\r\n"));
+
+        let not_modified = Response::from_status(StatusCode::NotModified).to_bytes(true, true);
+        test_eq!(
+            not_modified,
+            b"HTTP/1.1 304 Not Modified\r\n\
+              Content-Type: text/plain\r\n\
+              Connection: keep-alive\r\n\
+              \r\n"
+        );
+
+        test_eq!(StatusCode::from(405), StatusCode::MethodNotAllowed);
+        test_eq!(u16::from(StatusCode::Unknown(418)), 418);
+
+        Ok(())
+    });
+}
@@ -0,0 +1,559 @@
+use super::{form::percent_decode, Headers, Method, Request, Response, StatusCode, Version};
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+// Longest request line plus headers we buffer before giving up on a request
+pub const MAX_HEAD_SIZE: usize = 8 * 1024;
+// Largest body we accept, bodies are held in memory whole
+pub const MAX_BODY_SIZE: usize = 256 * 1024;
+// Longest chunk size or trailer line in a chunked body
+const MAX_CHUNK_LINE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ParseError {
+    // Not valid http
+    Malformed,
+    UnknownMethod,
+    UnsupportedVersion,
+    // A transfer coding other than chunked
+    UnsupportedEncoding,
+    HeadTooLarge,
+    BodyTooLarge,
+}
+
+impl ParseError {
+    /// Status to answer the request with
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ParseError::Malformed => StatusCode::BadRequest,
+            ParseError::UnknownMethod => StatusCode::NotImplemented,
+            ParseError::UnsupportedVersion => StatusCode::HttpVersionNotSupported,
+            ParseError::UnsupportedEncoding => StatusCode::NotImplemented,
+            ParseError::HeadTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            ParseError::BodyTooLarge => StatusCode::PayloadTooLarge,
+        }
+    }
+}
+
+/// Parses requests out of a buffer that they arrive in bit by bit. The head is only parsed once
+/// and the body carries on from where the last call got to, so a large body isn't parsed again
+/// after every read
+#[derive(Default)]
+pub struct RequestParser {
+    // Request whose body is still arriving, with the length of its head
+    partial: Option<(Request, usize, BodyParser)>,
+}
+
+impl RequestParser {
+    /// Parses the request at the start of data. Returns None until all of it has arrived, then
+    /// the request and how much of data it took up. Anything after that is the next pipelined
+    /// request. Until a request is returned, data has to be what was passed last time with more
+    /// added to the end
+    pub fn parse(&mut self, data: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+        let (mut request, head_len, mut body_parser) = match self.partial.take() {
+            Some(v) => v,
+            None => match parse_head(data)? {
+                Some((request, head_len)) => {
+                    let body_parser = BodyParser::new(&request.headers)?;
+                    (request, head_len, body_parser)
+                }
+                None => return Ok(None),
+            },
+        };
+
+        match body_parser.parse(&data[head_len..])? {
+            Some((body, body_len)) => {
+                request.body = body;
+                Ok(Some((request, head_len + body_len)))
+            }
+            None => {
+                self.partial = Some((request, head_len, body_parser));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Request line and headers of the request whose body is still arriving
+    pub fn head(&self) -> Option<&Request> {
+        self.partial.as_ref().map(|(request, _, _)| request)
+    }
+}
+
+/// Parses the request line and headers at the start of data, leaving the body empty. Returns
+/// None until the empty line that ends them has arrived, otherwise the request and the length
+/// of the head
+pub fn parse_head(data: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+    let head_len = match head_len(data)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let head = core::str::from_utf8(&data[..head_len - 4]).map_err(|_| ParseError::Malformed)?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().ok_or(ParseError::Malformed)?;
+
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(ParseError::Malformed),
+    };
+
+    let method = Method::parse(method.as_bytes()).ok_or(ParseError::UnknownMethod)?;
+
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        v if v.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion),
+        _ => return Err(ParseError::Malformed),
+    };
+
+    // Only origin form targets, we aren't a proxy
+    if !target.starts_with('/') || !target.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(ParseError::Malformed);
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+    let path = percent_decode(path.as_bytes(), false).map_err(|_| ParseError::Malformed)?;
+    let path = String::from_utf8(path).map_err(|_| ParseError::Malformed)?;
+
+    let request = Request {
+        method,
+        path,
+        query,
+        version,
+        headers: parse_headers(lines)?,
+        body: Vec::new(),
+    };
+    Ok(Some((request, head_len)))
+}
+
+/// Parses the response to a method request at the start of data, for the client side. Returns
+/// None until all of it has arrived. A response with neither a length nor chunked encoding
+/// ends when the server closes the connection, closed says whether it has
+pub fn parse_response(
+    data: &[u8],
+    method: Method,
+    closed: bool,
+) -> Result<Option<(Response, usize)>, ParseError> {
+    let (mut response, head_len) = match parse_response_head(data)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let data = &data[head_len..];
+
+    let framed = response.headers.get("Transfer-Encoding").is_some()
+        || response.headers.get("Content-Length").is_some();
+    // RFC 9112 6.3
+    let (body, body_len) = if method == Method::Head || !response.status.allows_body() {
+        (Vec::new(), 0)
+    } else if framed {
+        match parse_body(&response.headers, data)? {
+            Some(v) => v,
+            None => return Ok(None),
+        }
+    } else if data.len() > MAX_BODY_SIZE {
+        return Err(ParseError::BodyTooLarge);
+    } else if closed {
+        (data.to_vec(), data.len())
+    } else {
+        return Ok(None);
+    };
+
+    response.body = body;
+    Ok(Some((response, head_len + body_len)))
+}
+
+// Status line and headers at the start of data, like parse_head
+fn parse_response_head(data: &[u8]) -> Result<Option<(Response, usize)>, ParseError> {
+    let head_len = match head_len(data)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let head = core::str::from_utf8(&data[..head_len - 4]).map_err(|_| ParseError::Malformed)?;
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().ok_or(ParseError::Malformed)?;
+
+    // The reason phrase is only for people, and can be left out
+    let mut parts = status_line.splitn(3, ' ');
+    let (version, code) = match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) => (version, code),
+        _ => return Err(ParseError::Malformed),
+    };
+
+    match version {
+        "HTTP/1.1" | "HTTP/1.0" => (),
+        v if v.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion),
+        _ => return Err(ParseError::Malformed),
+    }
+
+    if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ParseError::Malformed);
+    }
+    let status = code.parse::<u16>().map_err(|_| ParseError::Malformed)?;
+
+    let response = Response {
+        status: StatusCode::from(status),
+        headers: parse_headers(lines)?,
+        body: Vec::new(),
+        upgrade: None,
+    };
+    Ok(Some((response, head_len)))
+}
+
+// Length of the start line and headers including the empty line after them, once it's arrived
+fn head_len(data: &[u8]) -> Result<Option<usize>, ParseError> {
+    let head_len = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None if data.len() > MAX_HEAD_SIZE => return Err(ParseError::HeadTooLarge),
+        None => return Ok(None),
+    };
+    if head_len > MAX_HEAD_SIZE {
+        return Err(ParseError::HeadTooLarge);
+    }
+    Ok(Some(head_len))
+}
+
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> Result<Headers, ParseError> {
+    let mut headers = Headers::default();
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(ParseError::Malformed)?;
+        // No whitespace before the colon, and no obsolete line folding (RFC 9112 5)
+        if name.is_empty() || name.bytes().any(|b| !b.is_ascii_graphic()) {
+            return Err(ParseError::Malformed);
+        }
+        headers.append(name, value.trim_matches(|c| c == ' ' || c == '\t'));
+    }
+    Ok(headers)
+}
+
+// Body that follows headers at the start of data, and how much of data it took up
+fn parse_body(headers: &Headers, data: &[u8]) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+    BodyParser::new(headers)?.parse(data)
+}
+
+// How far into a body we've got, for bodies that arrive over several reads
+enum BodyParser {
+    Length(usize),
+    Chunked(ChunkedParser),
+}
+
+impl BodyParser {
+    // Checks how the body after headers is framed
+    fn new(headers: &Headers) -> Result<BodyParser, ParseError> {
+        if let Some(encoding) = headers.get("Transfer-Encoding") {
+            // Both would let a proxy in front of us and us disagree on where the request ends
+            if headers.get("Content-Length").is_some() {
+                return Err(ParseError::Malformed);
+            }
+            if !encoding.eq_ignore_ascii_case("chunked") {
+                return Err(ParseError::UnsupportedEncoding);
+            }
+            return Ok(BodyParser::Chunked(ChunkedParser::default()));
+        }
+
+        let mut lengths = headers.get_all("Content-Length");
+        let length = match lengths.next() {
+            Some(length) => length,
+            None => return Ok(BodyParser::Length(0)),
+        };
+        if lengths.any(|other| other != length) || !length.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::Malformed);
+        }
+        let length = length
+            .parse::<usize>()
+            .map_err(|_| ParseError::BodyTooLarge)?;
+        if length > MAX_BODY_SIZE {
+            return Err(ParseError::BodyTooLarge);
+        }
+        Ok(BodyParser::Length(length))
+    }
+
+    // Body at the start of data once all of it has arrived, and how much of data it took up
+    fn parse(&mut self, data: &[u8]) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+        match self {
+            BodyParser::Length(length) => match data.get(..*length) {
+                Some(body) => Ok(Some((body.to_vec(), *length))),
+                None => Ok(None),
+            },
+            BodyParser::Chunked(chunked) => chunked.parse(data),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChunkedParser {
+    // Chunks decoded so far
+    body: Vec<u8>,
+    // Start of the next chunk size or trailer line
+    pos: usize,
+    // Past the last chunk, only trailers are left
+    in_trailers: bool,
+}
+
+impl ChunkedParser {
+    // Decodes a chunked body (RFC 9112 7.1), carrying on after the chunks decoded by earlier
+    // calls. Chunk extensions and trailers are ignored
+    fn parse(&mut self, data: &[u8]) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+        while !self.in_trailers {
+            let line = match chunk_line(&data[self.pos..])? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+            let chunk_start = self.pos + line.len() + 2;
+
+            let size = line.split(|b| *b == b';').next().unwrap_or(line);
+            let size = core::str::from_utf8(size).map_err(|_| ParseError::Malformed)?;
+            let size = usize::from_str_radix(size.trim_end_matches([' ', '\t']), 16)
+                .map_err(|_| ParseError::Malformed)?;
+
+            if size == 0 {
+                self.pos = chunk_start;
+                self.in_trailers = true;
+                break;
+            }
+
+            if size > MAX_BODY_SIZE - self.body.len() {
+                return Err(ParseError::BodyTooLarge);
+            }
+            let chunk = match data.get(chunk_start..chunk_start + size + 2) {
+                Some(chunk) => chunk,
+                None => return Ok(None),
+            };
+            if !chunk.ends_with(b"\r\n") {
+                return Err(ParseError::Malformed);
+            }
+            self.body.extend_from_slice(&chunk[..size]);
+            self.pos = chunk_start + size + 2;
+        }
+
+        loop {
+            let trailer = match chunk_line(&data[self.pos..])? {
+                Some(trailer) => trailer,
+                None => return Ok(None),
+            };
+            self.pos += trailer.len() + 2;
+            if trailer.is_empty() {
+                return Ok(Some((core::mem::take(&mut self.body), self.pos)));
+            }
+        }
+    }
+}
+
+// Line at the start of data without its crlf
+fn chunk_line(data: &[u8]) -> Result<Option<&[u8]>, ParseError> {
+    match data.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end <= MAX_CHUNK_LINE => Ok(Some(&data[..end])),
+        None if data.len() <= MAX_CHUNK_LINE => Ok(None),
+        _ => Err(ParseError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::vec;
+
+    fn parse_request(data: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+        RequestParser::default().parse(data)
+    }
+
+    create_test!(test_parse_request, {
+        let data = b"GET /form?data=hi HTTP/1.1\r\n\
+                     Host: 192.168.2.2\r\n\
+                     connection:  Keep-Alive, close\r\n\
+                     \r\n\
+                     POST /form HTTP/1.0\r\n\
+                     Content-Length: 7\r\n\
+                     \r\n\
+                     data=hiGET";
+
+        let (request, used) = parse_request(data).ok().flatten().ok_or("no request")?;
+        test_eq!(request.method, Method::Get);
+        test_eq!(request.path, "/form");
+        test_eq!(request.query.as_deref(), Some("data=hi"));
+        test_eq!(request.headers.get("HOST"), Some("192.168.2.2"));
+        test_false!(request.keep_alive());
+        test_eq!(request.body, b"".to_vec());
+
+        // Pipelined right behind the first
+        let (request, body_end) = parse_request(&data[used..])
+            .ok()
+            .flatten()
+            .ok_or("no second request")?;
+        test_eq!(request.method, Method::Post);
+        test_eq!(request.version, Version::Http10);
+        test_false!(request.keep_alive());
+        test_eq!(request.body, b"data=hi");
+        test_eq!(&data[used + body_end..], b"GET");
+
+        // Incomplete heads and bodies wait for more data
+        test_eq!(
+            parse_request(b"GET / HTTP/1.1\r\nHost")
+                .ok()
+                .map(|r| r.is_none()),
+            Some(true)
+        );
+        test_eq!(
+            parse_request(b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nab")
+                .ok()
+                .map(|r| r.is_none()),
+            Some(true)
+        );
+
+        // Paths are decoded, and have to decode to utf-8
+        let (request, _) = parse_request(b"GET /a%20b?c%20d HTTP/1.1\r\n\r\n")
+            .ok()
+            .flatten()
+            .ok_or("no request")?;
+        test_eq!(request.path, "/a b");
+        test_eq!(request.query.as_deref(), Some("c%20d"));
+        test_eq!(
+            parse_request(b"GET /%ff HTTP/1.1\r\n\r\n").err(),
+            Some(ParseError::Malformed)
+        );
+
+        test_eq!(
+            parse_request(b"GET / HTTP/1.1\r\nBad Header: 1\r\n\r\n").err(),
+            Some(ParseError::Malformed)
+        );
+        test_eq!(
+            parse_request(b"BREW / HTTP/1.1\r\n\r\n").err(),
+            Some(ParseError::UnknownMethod)
+        );
+        test_eq!(
+            parse_request(b"GET / HTTP/2.0\r\n\r\n").err(),
+            Some(ParseError::UnsupportedVersion)
+        );
+        test_eq!(
+            parse_request(b"POST / HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n").err(),
+            Some(ParseError::BodyTooLarge)
+        );
+        test_eq!(
+            parse_request(&vec![b'a'; MAX_HEAD_SIZE + 1]).err(),
+            Some(ParseError::HeadTooLarge)
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_parse_chunked, {
+        let data = b"POST /upload HTTP/1.1\r\n\
+                     Transfer-Encoding: chunked\r\n\
+                     \r\n\
+                     5;name=value\r\nhello\r\n\
+                     7\r\n, world\r\n\
+                     0\r\n\
+                     Trailer: ignored\r\n\
+                     \r\n";
+
+        let (request, used) = parse_request(data).ok().flatten().ok_or("no request")?;
+        test_eq!(request.body, b"hello, world");
+        test_eq!(used, data.len());
+
+        // Nothing until the last chunk and trailers are in
+        for end in [data.len() - 1, data.len() - 30] {
+            test_eq!(
+                parse_request(&data[..end]).ok().map(|r| r.is_none()),
+                Some(true)
+            );
+        }
+
+        // Same result when it arrives a few bytes at a time
+        let mut parser = RequestParser::default();
+        let mut parsed = None;
+        for end in (0..data.len()).step_by(3).chain([data.len()]) {
+            test_true!(parsed.is_none());
+            parsed = parser
+                .parse(&data[..end])
+                .map_err(|e| alloc::format!("{:?}", e))?;
+        }
+        let (request, used) = parsed.ok_or("no request from parts")?;
+        test_eq!(request.body, b"hello, world");
+        test_eq!(used, data.len());
+        test_true!(parser.head().is_none());
+
+        test_eq!(
+            parse_request(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcd\r\n")
+                .err(),
+            Some(ParseError::Malformed)
+        );
+        test_eq!(
+            parse_request(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n").err(),
+            Some(ParseError::UnsupportedEncoding)
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_parse_response, {
+        let data = b"HTTP/1.1 200 OK\r\n\
+                     Content-Length: 5\r\n\
+                     \r\n\
+                     hello";
+        let (response, used) = parse_response(data, Method::Get, false)
+            .ok()
+            .flatten()
+            .ok_or("no response")?;
+        test_eq!(response.status, StatusCode::Ok);
+        test_eq!(response.body, b"hello");
+        test_eq!(used, data.len());
+
+        // HEAD gets the headers the GET would have, but no body
+        let (response, used) = parse_response(data, Method::Head, false)
+            .ok()
+            .flatten()
+            .ok_or("no head response")?;
+        test_eq!(response.body, b"".to_vec());
+        test_eq!(used, data.len() - 5);
+
+        let chunked = b"HTTP/1.1 404 \r\n\
+                        Transfer-Encoding: chunked\r\n\
+                        \r\n\
+                        3\r\nabc\r\n0\r\n\r\n";
+        let (response, _) = parse_response(chunked, Method::Get, false)
+            .ok()
+            .flatten()
+            .ok_or("no chunked response")?;
+        test_eq!(response.status, StatusCode::NotFound);
+        test_eq!(response.body, b"abc");
+
+        // Without a length the body goes on until the connection closes
+        let unframed = b"HTTP/1.0 200 OK\r\n\r\nuntil close";
+        test_eq!(
+            parse_response(unframed, Method::Get, false)
+                .ok()
+                .map(|r| r.is_none()),
+            Some(true)
+        );
+        let (response, _) = parse_response(unframed, Method::Get, true)
+            .ok()
+            .flatten()
+            .ok_or("no unframed response")?;
+        test_eq!(response.body, b"until close");
+
+        // Except when it can't have one
+        let (response, _) = parse_response(b"HTTP/1.1 204 No Content\r\n\r\n", Method::Get, false)
+            .ok()
+            .flatten()
+            .ok_or("no 204 response")?;
+        test_eq!(response.status, StatusCode::NoContent);
+
+        test_eq!(
+            parse_response(b"HTTP/1.1 20 OK\r\n\r\n", Method::Get, true).err(),
+            Some(ParseError::Malformed)
+        );
+        test_eq!(
+            parse_response(b"HTTP/2 200 OK\r\n\r\n", Method::Get, true).err(),
+            Some(ParseError::UnsupportedVersion)
+        );
+
+        Ok(())
+    });
+}
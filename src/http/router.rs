@@ -0,0 +1,149 @@
+use super::{Method, Request, Response, StatusCode};
+
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{future::Future, pin::Pin};
+
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Response> + Send + 'a>>;
+type Handler<'a> = Box<dyn Fn(Request) -> HandlerFuture<'a> + Send + Sync + 'a>;
+
+struct Route<'a> {
+    method: Method,
+    // A trailing * matches the rest of the path
+    path: String,
+    handler: Handler<'a>,
+}
+
+impl Route<'_> {
+    fn matches(&self, path: &str) -> bool {
+        match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => self.path == path,
+        }
+    }
+}
+
+/// Picks the handler for a request by its method and path
+#[derive(Default)]
+pub struct Router<'a> {
+    routes: Vec<Route<'a>>,
+}
+
+impl<'a> Router<'a> {
+    pub fn new() -> Router<'a> {
+        Router::default()
+    }
+
+    /// Answers method requests for path with handler. A path ending in * takes every path that
+    /// starts with the rest of it, routes added first win. HEAD requests go to the GET handler,
+    /// the server drops the body
+    pub fn route<F>(mut self, method: Method, path: &str, handler: F) -> Router<'a>
+    where
+        F: Fn(Request) -> HandlerFuture<'a> + Send + Sync + 'a,
+    {
+        self.routes.push(Route {
+            method,
+            path: path.to_string(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub async fn handle(&self, request: Request) -> Response {
+        let method = match request.method {
+            Method::Head => Method::Get,
+            method => method,
+        };
+
+        let route = self
+            .routes
+            .iter()
+            .find(|route| route.method == method && route.matches(&request.path));
+        if let Some(route) = route {
+            return (route.handler)(request).await;
+        }
+
+        let mut allowed: Vec<&str> = self
+            .routes
+            .iter()
+            .filter(|route| route.matches(&request.path))
+            .map(|route| route.method.as_str())
+            .collect();
+        if allowed.is_empty() {
+            return Response::from_status(StatusCode::NotFound);
+        }
+
+        if allowed.contains(&Method::Get.as_str()) {
+            allowed.push(Method::Head.as_str());
+        }
+        let mut response = Response::from_status(StatusCode::MethodNotAllowed);
+        response.headers.set("Allow", &allowed.join(", "));
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        http::{Headers, Version},
+        testing::*,
+    };
+
+    fn request(method: Method, path: &str) -> Request {
+        Request {
+            method,
+            path: path.to_string(),
+            query: None,
+            version: Version::Http11,
+            headers: Headers::default(),
+            body: Vec::new(),
+        }
+    }
+
+    create_test!(test_router, {
+        // Handlers can borrow from outside the router
+        let greeting = String::from("hello");
+        let greeting = greeting.as_str();
+        let router = Router::new()
+            .route(Method::Get, "/", move |_| {
+                Box::pin(async move { Response::new(StatusCode::Ok, "text/plain", greeting) })
+            })
+            .route(Method::Post, "/echo", |request| {
+                Box::pin(async move { Response::new(StatusCode::Ok, "text/plain", request.body) })
+            })
+            .route(Method::Get, "/files/*", |request| {
+                Box::pin(async move { Response::new(StatusCode::Ok, "text/plain", request.path) })
+            });
+
+        let response = router.handle(request(Method::Get, "/")).await;
+        test_eq!(response.status, StatusCode::Ok);
+        test_eq!(response.body, b"hello");
+
+        let mut echo = request(Method::Post, "/echo");
+        echo.body = b"abc".to_vec();
+        let response = router.handle(echo).await;
+        test_eq!(response.body, b"abc");
+
+        test_eq!(
+            router.handle(request(Method::Head, "/")).await.status,
+            StatusCode::Ok
+        );
+        test_eq!(
+            router.handle(request(Method::Get, "/missing")).await.status,
+            StatusCode::NotFound
+        );
+
+        let response = router.handle(request(Method::Get, "/files/a/b")).await;
+        test_eq!(response.body, b"/files/a/b");
+
+        let response = router.handle(request(Method::Delete, "/")).await;
+        test_eq!(response.status, StatusCode::MethodNotAllowed);
+        test_eq!(response.headers.get("Allow"), Some("GET, HEAD"));
+
+        Ok(())
+    });
+}
@@ -0,0 +1,176 @@
+use super::{
+    parser::{ParseError, RequestParser},
+    Method, Request, Response, Router, Version,
+};
+
+use crate::{
+    net::tcp::{Keepalive, TcpConnection, TcpListener},
+    util::async_io::AsyncRead,
+};
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// Most connections served at once, later ones wait in the listen backlog. Upgraded connections
+// don't count, they can stay open for as long as the client likes
+const MAX_CONNECTIONS: usize = 16;
+// How long a connection can go without any traffic before we close it
+const KEEP_ALIVE_TIMEOUT_S: f32 = 30.0;
+// Upgraded connections can be quiet for a long time, e.g. an event stream with nothing to send,
+// so clients that went away are noticed by probing them instead
+const UPGRADE_KEEPALIVE: Keepalive = Keepalive {
+    idle_s: 60.0,
+    interval_s: 10.0,
+    probes: 6,
+};
+const READ_SIZE: usize = 2048;
+
+type UpgradedFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+// Resolves to what's left of the connection if it was upgraded
+type ConnectionFuture<'a> = Pin<Box<dyn Future<Output = Option<UpgradedFuture>> + Send + 'a>>;
+
+// Drives the open connections until the listener has a new one for us
+struct Accept<'a, 'b> {
+    listener: &'b TcpListener,
+    connections: &'b mut Vec<ConnectionFuture<'a>>,
+    upgraded: &'b mut Vec<UpgradedFuture>,
+}
+
+impl Future for Accept<'_, '_> {
+    type Output = TcpConnection;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.connections
+            .retain_mut(|connection| match connection.as_mut().poll(cx) {
+                Poll::Ready(Some(upgraded)) => {
+                    this.upgraded.push(upgraded);
+                    false
+                }
+                Poll::Ready(None) => false,
+                Poll::Pending => true,
+            });
+        this.upgraded
+            .retain_mut(|upgraded| upgraded.as_mut().poll(cx).is_pending());
+
+        if this.connections.len() >= MAX_CONNECTIONS {
+            return Poll::Pending;
+        }
+        core::pin::pin!(this.listener.connection()).poll(cx)
+    }
+}
+
+/// Answers requests on the listener's connections with router. Connections are served
+/// concurrently, each one stays open for as many requests as its client wants
+pub async fn serve(listener: &TcpListener, router: &Router<'_>) {
+    let mut connections = Vec::new();
+    let mut upgraded = Vec::new();
+    loop {
+        let connection = Accept {
+            listener,
+            connections: &mut connections,
+            upgraded: &mut upgraded,
+        }
+        .await;
+        connections.push(Box::pin(handle_connection(connection, router)) as ConnectionFuture);
+    }
+}
+
+// Serves requests until the connection closes, or until it's upgraded and the rest is up to
+// the returned future
+async fn handle_connection(
+    connection: TcpConnection,
+    router: &Router<'_>,
+) -> Option<UpgradedFuture> {
+    connection.set_idle_timeout(Some(KEEP_ALIVE_TIMEOUT_S));
+
+    // Whatever has arrived past the requests answered so far
+    let mut data = Vec::new();
+    let mut request_parser = RequestParser::default();
+    let mut sent_continue = false;
+    loop {
+        let (request, used) = match request_parser.parse(&data) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                if !sent_continue && request_parser.head().is_some_and(expects_continue) {
+                    sent_continue = true;
+                    if connection
+                        .write(&b"HTTP/1.1 100 Continue\r\n\r\n"[..])
+                        .await
+                        .is_err()
+                    {
+                        return None;
+                    }
+                }
+
+                let mut buf = vec![0; READ_SIZE];
+                match connection.read(&mut buf).await {
+                    Ok(0) => return None,
+                    Ok(n) => data.extend_from_slice(&buf[..n]),
+                    Err(e) => {
+                        debug!("Closing http connection: {:?}", e);
+                        return None;
+                    }
+                }
+                continue;
+            }
+            Err(e) => {
+                warn!("Rejecting http request: {:?}", e);
+                send_error(&connection, e).await;
+                return None;
+            }
+        };
+        data.drain(..used);
+        sent_continue = false;
+
+        info!(
+            "{} {} on cpu {}",
+            request.method.as_str(),
+            request.path,
+            crate::multiprocessing::cpuid()
+        );
+
+        let keep_alive = request.keep_alive();
+        let include_body = request.method != Method::Head;
+        let mut response = router.handle(request).await;
+        // An upgrade keeps the connection to itself
+        let keep_alive = keep_alive && response.upgrade.is_none();
+        if let Err(e) = connection
+            .write(response.to_bytes(include_body, keep_alive))
+            .await
+        {
+            debug!("Failed to send http response: {:?}", e);
+            return None;
+        }
+
+        if let Some(upgrade) = response.upgrade.take() {
+            if !include_body {
+                return None;
+            }
+            connection.set_idle_timeout(None);
+            connection.set_keepalive(Some(UPGRADE_KEEPALIVE));
+            return Some(upgrade(connection));
+        }
+
+        if !keep_alive {
+            return None;
+        }
+    }
+}
+
+// Whether the client is holding back the body until we say it's welcome (RFC 9110 10.1.1)
+fn expects_continue(request: &Request) -> bool {
+    request.version == Version::Http11 && request.headers.contains_token("Expect", "100-continue")
+}
+
+async fn send_error(connection: &TcpConnection, error: ParseError) {
+    let response = Response::from_status(error.status());
+    // We don't know where the bad request ends, so there's no finding the next one
+    if let Err(e) = connection.write(response.to_bytes(true, false)).await {
+        debug!("Failed to send http error response: {:?}", e);
+    }
+}
@@ -0,0 +1,69 @@
+use super::{Response, StatusCode};
+
+use crate::net::tcp::{TcpConnection, TcpError};
+
+use alloc::{string::String, vec::Vec};
+use core::future::Future;
+
+/// Sending side of a Server-Sent Events stream
+pub struct EventStream {
+    connection: TcpConnection,
+}
+
+impl EventStream {
+    /// Sends data as an event of type event, or as a plain message if there's none. Fails once
+    /// the client is gone
+    pub async fn send(&self, event: Option<&str>, data: &str) -> Result<(), TcpError> {
+        self.connection
+            .write(format_event(event, data).into_bytes())
+            .await
+    }
+}
+
+/// Answers with a text/event-stream that f sends events on, until it returns
+pub fn response<F, Fut>(f: F) -> Response
+where
+    F: FnOnce(EventStream) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut response = Response::new(StatusCode::Ok, "text/event-stream", Vec::new());
+    response.headers.set("Cache-Control", "no-cache");
+    response.with_upgrade(move |connection| f(EventStream { connection }))
+}
+
+// Every line of data goes in its own data field, the client joins them back up with newlines
+fn format_event(event: Option<&str>, data: &str) -> String {
+    let mut out = String::new();
+    if let Some(event) = event {
+        out.push_str("event: ");
+        out.push_str(event);
+        out.push('\n');
+    }
+
+    // \r\n, \r and \n all end a line in an event stream
+    let data = data.replace("\r\n", "\n");
+    for line in data.split(['\r', '\n']) {
+        out.push_str("data: ");
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    create_test!(test_format_event, {
+        test_eq!(format_event(None, "hi"), "data: hi\n\n");
+        test_eq!(
+            format_event(Some("log"), "a\nb\r\nc\rd"),
+            "event: log\ndata: a\ndata: b\ndata: c\ndata: d\n\n"
+        );
+        test_eq!(format_event(None, ""), "data: \n\n");
+
+        Ok(())
+    });
+}
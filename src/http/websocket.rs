@@ -0,0 +1,533 @@
+use super::{Headers, Method, Request, Response, StatusCode, Version};
+
+use crate::{
+    net::tcp::{TcpConnection, TcpError},
+    util::{async_io::AsyncRead, async_mutex::Mutex, base64, sha1::sha1},
+};
+
+use alloc::{string::String, vec, vec::Vec};
+use core::{
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Appended to the client's key to show it was understood as a websocket handshake (RFC 6455 1.3)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Largest message we put back together, bigger ones close the connection
+const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+const READ_SIZE: usize = 2048;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum WebSocketError {
+    Tcp(TcpError),
+    // We've sent a close frame, nothing else can go after it
+    Closed,
+    // The peer broke the framing rules, e.g. an unmasked frame or a fragmented ping
+    Protocol,
+    InvalidUtf8,
+    MessageTooBig,
+}
+
+impl WebSocketError {
+    // Status code to close the connection with (RFC 6455 7.4.1), if it's still there to close
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            WebSocketError::Tcp(_) | WebSocketError::Closed => None,
+            WebSocketError::Protocol => Some(1002),
+            WebSocketError::InvalidUtf8 => Some(1007),
+            WebSocketError::MessageTooBig => Some(1009),
+        }
+    }
+}
+
+impl From<TcpError> for WebSocketError {
+    fn from(error: TcpError) -> Self {
+        WebSocketError::Tcp(error)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Opcode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xa,
+}
+
+impl Opcode {
+    fn from_u8(value: u8) -> Option<Opcode> {
+        match value {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xa => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn is_control(&self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    // Last frame of its message
+    fin: bool,
+    opcode: Opcode,
+    // Already unmasked
+    payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Reader {
+    // Received bytes that don't make up a whole frame yet
+    data: Vec<u8>,
+    // Type and data so far of a message that's arriving in fragments
+    fragmented: Option<(Opcode, Vec<u8>)>,
+    // Nothing more is coming, the peer sent a close frame or broke the protocol
+    closed: bool,
+}
+
+/// Message based connection to a client that did a websocket handshake (RFC 6455). Sending and
+/// receiving take &self, so one task can push messages while another waits for them
+pub struct WebSocket {
+    connection: TcpConnection,
+    reader: Mutex<Reader>,
+    close_sent: AtomicBool,
+}
+
+impl WebSocket {
+    fn new(connection: TcpConnection) -> WebSocket {
+        WebSocket {
+            connection,
+            reader: Mutex::new(Reader::default()),
+            close_sent: AtomicBool::new(false),
+        }
+    }
+
+    /// Next message from the client. Pings are answered along the way. None once the
+    /// connection has been closed, errors close it with the matching status code
+    pub async fn recv(&self) -> Result<Option<Message>, WebSocketError> {
+        let mut reader = self.reader.lock().await;
+        loop {
+            if reader.closed {
+                return Ok(None);
+            }
+
+            let (frame, used) = match parse_frame(&reader.data) {
+                Ok(Some(v)) => v,
+                Ok(None) => {
+                    let mut buf = vec![0; READ_SIZE];
+                    match self.connection.read(&mut buf).await {
+                        // Gone without a close frame
+                        Ok(0) => {
+                            let error = WebSocketError::Tcp(TcpError::UnexpectedEof);
+                            return Err(self.fail(&mut reader, error).await);
+                        }
+                        Ok(n) => reader.data.extend_from_slice(&buf[..n]),
+                        Err(e) => return Err(self.fail(&mut reader, e.into()).await),
+                    }
+                    continue;
+                }
+                Err(e) => return Err(self.fail(&mut reader, e).await),
+            };
+            reader.data.drain(..used);
+
+            match self.handle_frame(&mut reader, frame).await {
+                Ok(Some(message)) => return Ok(Some(message)),
+                Ok(None) => (),
+                Err(e) => return Err(self.fail(&mut reader, e).await),
+            }
+        }
+    }
+
+    pub async fn send_text(&self, text: &str) -> Result<(), WebSocketError> {
+        self.send_message(Opcode::Text, text.as_bytes()).await
+    }
+
+    pub async fn send_binary(&self, data: &[u8]) -> Result<(), WebSocketError> {
+        self.send_message(Opcode::Binary, data).await
+    }
+
+    /// Starts the closing handshake, recv returns None once the client has answered. reason
+    /// can be at most 123 bytes, to fit in a control frame
+    pub async fn close(&self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        if reason.len() > 123 {
+            return Err(WebSocketError::MessageTooBig);
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.send_close(&payload).await
+    }
+
+    async fn send_message(&self, opcode: Opcode, payload: &[u8]) -> Result<(), WebSocketError> {
+        if self.close_sent.load(Ordering::Acquire) {
+            return Err(WebSocketError::Closed);
+        }
+        self.connection
+            .write(encode_frame(opcode, payload, None))
+            .await?;
+        Ok(())
+    }
+
+    // Only the first close frame goes out, either side can start the closing handshake
+    async fn send_close(&self, payload: &[u8]) -> Result<(), WebSocketError> {
+        if self.close_sent.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        self.connection
+            .write(encode_frame(Opcode::Close, payload, None))
+            .await?;
+        Ok(())
+    }
+
+    async fn handle_frame(
+        &self,
+        reader: &mut Reader,
+        frame: Frame,
+    ) -> Result<Option<Message>, WebSocketError> {
+        match frame.opcode {
+            Opcode::Ping => {
+                // The write waits while the client leaves our send buffer full, and we stop
+                // reading its pings until then. A client that pings without reading can't
+                // queue up pongs without limit
+                if !self.close_sent.load(Ordering::Acquire) {
+                    self.connection
+                        .write(encode_frame(Opcode::Pong, &frame.payload, None))
+                        .await?;
+                }
+                Ok(None)
+            }
+            // Nothing asks for them, but a client can send one unprompted as a heartbeat
+            Opcode::Pong => Ok(None),
+            Opcode::Close => {
+                let code = parse_close(&frame.payload)?;
+                reader.closed = true;
+                // Answering with the same code completes the closing handshake, after which
+                // the server is the one to close the tcp connection (RFC 6455 7.1.1)
+                let payload = match code {
+                    Some(code) => code.to_be_bytes().to_vec(),
+                    None => Vec::new(),
+                };
+                self.send_close(&payload).await?;
+                self.connection.shutdown();
+                Ok(None)
+            }
+            Opcode::Text | Opcode::Binary => {
+                if reader.fragmented.is_some() {
+                    return Err(WebSocketError::Protocol);
+                }
+                if frame.fin {
+                    return to_message(frame.opcode, frame.payload).map(Some);
+                }
+                reader.fragmented = Some((frame.opcode, frame.payload));
+                Ok(None)
+            }
+            Opcode::Continuation => {
+                let (opcode, mut data) =
+                    reader.fragmented.take().ok_or(WebSocketError::Protocol)?;
+                if data.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                    return Err(WebSocketError::MessageTooBig);
+                }
+                data.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    return to_message(opcode, data).map(Some);
+                }
+                reader.fragmented = Some((opcode, data));
+                Ok(None)
+            }
+        }
+    }
+
+    async fn fail(&self, reader: &mut Reader, error: WebSocketError) -> WebSocketError {
+        reader.closed = true;
+        if let Some(code) = error.close_code() {
+            // The connection is going away either way
+            let _ = self.close(code, "").await;
+        }
+        self.connection.shutdown();
+        error
+    }
+}
+
+/// Accepts a websocket handshake (RFC 6455 4.2.2), f gets the connection once the 101 has gone
+/// out. Anything that isn't a handshake gets a 400, a handshake for a version we don't speak a
+/// 426
+pub fn upgrade<F, Fut>(request: &Request, f: F) -> Response
+where
+    F: FnOnce(WebSocket) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let key = match request.headers.get("Sec-WebSocket-Key") {
+        Some(key) if valid_key(key.trim()) => key.trim(),
+        _ => return Response::from_status(StatusCode::BadRequest),
+    };
+    if request.method != Method::Get
+        || request.version != Version::Http11
+        || !request.headers.contains_token("Upgrade", "websocket")
+        || !request.headers.contains_token("Connection", "Upgrade")
+    {
+        return Response::from_status(StatusCode::BadRequest);
+    }
+
+    if request.headers.get("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        let mut response = Response::from_status(StatusCode::UpgradeRequired);
+        response.headers.set("Sec-WebSocket-Version", "13");
+        return response;
+    }
+
+    let mut headers = Headers::default();
+    headers.set("Upgrade", "websocket");
+    headers.set("Connection", "Upgrade");
+    headers.set("Sec-WebSocket-Accept", &accept_key(key));
+    let response = Response {
+        status: StatusCode::SwitchingProtocols,
+        headers,
+        body: Vec::new(),
+        upgrade: None,
+    };
+    response.with_upgrade(move |connection| f(WebSocket::new(connection)))
+}
+
+// The key is 16 random bytes in base64
+fn valid_key(key: &str) -> bool {
+    key.len() == 24 && key.ends_with("==") && key.bytes().take(22).all(base64::is_alphabet)
+}
+
+fn accept_key(key: &str) -> String {
+    let mut data = String::from(key);
+    data.push_str(ACCEPT_GUID);
+    base64::encode(&sha1(data.as_bytes()))
+}
+
+// Frames from clients are always masked (RFC 6455 5.2)
+fn parse_frame(data: &[u8]) -> Result<Option<(Frame, usize)>, WebSocketError> {
+    if data.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = data[0] & 0x80 != 0;
+    // No extensions were agreed on, so the reserved bits can't be set
+    if data[0] & 0x70 != 0 {
+        return Err(WebSocketError::Protocol);
+    }
+    let opcode = Opcode::from_u8(data[0] & 0x0f).ok_or(WebSocketError::Protocol)?;
+    if data[1] & 0x80 == 0 {
+        return Err(WebSocketError::Protocol);
+    }
+
+    let (len, mut pos) = match data[1] & 0x7f {
+        126 => match data.get(2..4) {
+            Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match data.get(2..10) {
+            Some(len) => (
+                u64::from_be_bytes(len.try_into().expect("Length is 8 bytes")),
+                10,
+            ),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+
+    if opcode.is_control() && (!fin || len > 125) {
+        return Err(WebSocketError::Protocol);
+    }
+    if len > MAX_MESSAGE_SIZE as u64 {
+        return Err(WebSocketError::MessageTooBig);
+    }
+    let len = len as usize;
+    if data.len() < pos + 4 + len {
+        return Ok(None);
+    }
+
+    let mask = &data[pos..pos + 4];
+    pos += 4;
+    let payload = data[pos..pos + len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+
+    let frame = Frame {
+        fin,
+        opcode,
+        payload,
+    };
+    Ok(Some((frame, pos + len)))
+}
+
+// Whole message in one frame. Servers don't mask, mask is for writing client frames
+fn encode_frame(opcode: Opcode, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode as u8];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => frame.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    frame
+}
+
+// Status code of a close frame, followed by a utf-8 reason
+fn parse_close(payload: &[u8]) -> Result<Option<u16>, WebSocketError> {
+    if payload.is_empty() {
+        return Ok(None);
+    }
+    if payload.len() == 1 {
+        return Err(WebSocketError::Protocol);
+    }
+
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    // The rest are reserved, or only for reporting a failure locally (RFC 6455 7.4)
+    if !matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999) {
+        return Err(WebSocketError::Protocol);
+    }
+    core::str::from_utf8(&payload[2..]).map_err(|_| WebSocketError::InvalidUtf8)?;
+    Ok(Some(code))
+}
+
+fn to_message(opcode: Opcode, data: Vec<u8>) -> Result<Message, WebSocketError> {
+    match opcode {
+        Opcode::Text => String::from_utf8(data)
+            .map(Message::Text)
+            .map_err(|_| WebSocketError::InvalidUtf8),
+        _ => Ok(Message::Binary(data)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::string::ToString;
+
+    fn handshake(headers: &[(&str, &str)]) -> Request {
+        let mut request = Request {
+            method: Method::Get,
+            path: "/chat".to_string(),
+            query: None,
+            version: Version::Http11,
+            headers: Headers::default(),
+            body: Vec::new(),
+        };
+        for (name, value) in headers {
+            request.headers.append(name, value);
+        }
+        request
+    }
+
+    create_test!(test_websocket_handshake, {
+        // The example from RFC 6455 1.3
+        test_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let headers = [
+            ("Upgrade", "websocket"),
+            ("Connection", "keep-alive, Upgrade"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+            ("Sec-WebSocket-Version", "13"),
+        ];
+        let response = upgrade(&handshake(&headers), |_| async {});
+        test_eq!(response.status, StatusCode::SwitchingProtocols);
+        test_eq!(
+            response.headers.get("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        // No body, and the connection isn't kept for http
+        test_eq!(
+            response.to_bytes(true, false),
+            b"HTTP/1.1 101 Switching Protocols\r\n\
+              Upgrade: websocket\r\n\
+              Connection: Upgrade\r\n\
+              Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+              \r\n"
+        );
+
+        let response = upgrade(&handshake(&headers[1..]), |_| async {});
+        test_eq!(response.status, StatusCode::BadRequest);
+        let response = upgrade(&handshake(&headers[..3]), |_| async {});
+        test_eq!(response.status, StatusCode::UpgradeRequired);
+        test_eq!(response.headers.get("Sec-WebSocket-Version"), Some("13"));
+        test_false!(valid_key("short=="));
+
+        Ok(())
+    });
+
+    create_test!(test_websocket_frames, {
+        // A masked "Hello" from RFC 6455 5.7
+        let hello = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (frame, used) = parse_frame(&hello).ok().flatten().ok_or("no frame")?;
+        test_true!(frame.fin);
+        test_eq!(frame.opcode, Opcode::Text);
+        test_eq!(frame.payload, b"Hello");
+        test_eq!(used, hello.len());
+        test_true!(matches!(parse_frame(&hello[..10]), Ok(None)));
+        test_eq!(
+            encode_frame(Opcode::Text, b"Hello", Some([0x37, 0xfa, 0x21, 0x3d])),
+            hello
+        );
+        test_eq!(encode_frame(Opcode::Text, b"Hello", None), b"\x81\x05Hello");
+
+        // Clients have to mask, control frames can't be split up
+        test_eq!(
+            parse_frame(b"\x81\x05Hello").err(),
+            Some(WebSocketError::Protocol)
+        );
+        // A ping without fin set
+        test_eq!(
+            parse_frame(b"\x09\x80\x01\x02\x03\x04").err(),
+            Some(WebSocketError::Protocol)
+        );
+
+        let long = vec![7; 300];
+        let frame = encode_frame(Opcode::Binary, &long, Some([9, 8, 7, 6]));
+        test_eq!(&frame[1..4], &[0x80 | 126, 0x01, 0x2c][..]);
+        let (frame, used) = parse_frame(&frame).ok().flatten().ok_or("no frame")?;
+        test_eq!(frame.payload, long);
+        test_eq!(used, 4 + 4 + 300);
+
+        test_eq!(parse_close(b"").ok(), Some(None::<u16>));
+        test_eq!(parse_close(b"\x03\xe8bye").ok(), Some(Some(1000)));
+        test_eq!(
+            parse_close(b"\x03\xed").err(),
+            Some(WebSocketError::Protocol)
+        );
+        test_eq!(
+            parse_close(b"\x03\xe8\xff").err(),
+            Some(WebSocketError::InvalidUtf8)
+        );
+
+        Ok(())
+    });
+}
@@ -194,6 +194,17 @@ pub struct PciInterfaceId {
 #[derive(Debug)]
 pub struct InvalidIrq;
 
+/// Where a device sits and what it is, kept for listing devices once they're handed to drivers
+#[derive(Debug)]
+pub struct PciDeviceInfo {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub interface_id: PciInterfaceId,
+}
+
 #[derive(Debug)]
 pub struct GeneralPciDevice {
     addr: PciAddress,
@@ -217,6 +228,18 @@ impl GeneralPciDevice {
         }
     }
 
+    pub fn info(&mut self, pci: &mut Pci) -> PciDeviceInfo {
+        let (vendor_id, device_id) = self.id(pci);
+        PciDeviceInfo {
+            bus: self.addr.bus,
+            slot: self.addr.slot,
+            function: self.addr.function,
+            vendor_id,
+            device_id,
+            interface_id: self.interface_id(pci),
+        }
+    }
+
     #[allow(unused)]
     pub fn find_io_base(&mut self, pci: &mut Pci) -> Option<u32> {
         for i in 0..=5 {
@@ -180,7 +180,7 @@ impl Rtc {
         Ok(Rtc { cmos_io })
     }
 
-    pub fn write(&mut self, date_time: &DateTime) -> Result<(), WriteError> {
+    pub fn write(&self, date_time: &DateTime) -> Result<(), WriteError> {
         update_guarded_op(&mut self.cmos_io.lock(), |cmos_io| {
             write_cmos_reg(cmos_io, NMI_ENABLE, 0x00, date_time.seconds)
                 .map_err(WriteError::Seconds)?;
@@ -200,7 +200,7 @@ impl Rtc {
         })
     }
 
-    pub fn read(&mut self) -> Result<DateTime, ReadError> {
+    pub fn read(&self) -> Result<DateTime, ReadError> {
         update_guarded_op(&mut self.cmos_io.lock(), |cmos_io| {
             let seconds = read_cmos_reg(cmos_io, NMI_ENABLE, 0x00).map_err(ReadError::Seconds)?;
             let minutes = read_cmos_reg(cmos_io, NMI_ENABLE, 0x02).map_err(ReadError::Minutes)?;
@@ -1,9 +1,18 @@
-use crate::util::{
-    async_mutex::Mutex,
-    atomic_cell::AtomicCell,
-    lock_free_queue::{self, Receiver, Sender},
+use crate::{
+    future::poll_fn,
+    util::{
+        async_mutex::Mutex,
+        atomic_cell::AtomicCell,
+        lock_free_queue::{self, Receiver, Sender},
+        spinlock::SpinLock,
+    },
+};
+use alloc::{
+    collections::VecDeque,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
 };
-use alloc::string::String;
 use core::{
     cell::UnsafeCell,
     ops::Deref,
@@ -156,11 +165,71 @@ impl core::future::Future for LogWaiter<'_> {
     }
 }
 
+// Logs a subscriber can fall behind by before it starts missing them
+const SUBSCRIBER_QUEUE_LEN: usize = 256;
+
+pub enum LogEvent {
+    Log(Arc<Log>),
+    // This many logs were skipped because the subscriber wasn't keeping up
+    Dropped(usize),
+}
+
+#[derive(Default)]
+struct SubscriberQueue {
+    events: VecDeque<LogEvent>,
+    // Logs missed since the last one that made it into events
+    dropped: usize,
+    waker: Option<Waker>,
+}
+
+impl SubscriberQueue {
+    fn push(&mut self, log: &Arc<Log>) {
+        if self.events.len() >= SUBSCRIBER_QUEUE_LEN {
+            self.dropped += 1;
+            return;
+        }
+
+        if self.dropped != 0 {
+            self.events.push_back(LogEvent::Dropped(self.dropped));
+            self.dropped = 0;
+        }
+        self.events.push_back(LogEvent::Log(Arc::clone(log)));
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Receives every log pushed after it subscribed. A subscriber that falls behind misses logs
+/// instead of holding up the logger
+pub struct LogSubscriber {
+    queue: Arc<SpinLock<SubscriberQueue>>,
+}
+
+impl LogSubscriber {
+    pub async fn recv(&self) -> LogEvent {
+        poll_fn(|cx| {
+            let mut queue = self.queue.lock();
+            match queue.events.pop_front() {
+                Some(event) => Poll::Ready(event),
+                None => {
+                    queue.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
 pub struct Logger {
     levels: HashMap<String, LogLevel>,
     log_tx: Sender<Log>,
     log_rx: Mutex<Receiver<Log>>,
     waker: AtomicCell<Waker>,
+    // Dropped subscribers are cleaned up on the next log
+    subscribers: SpinLock<Vec<Weak<SpinLock<SubscriberQueue>>>>,
 }
 
 impl Logger {
@@ -173,6 +242,7 @@ impl Logger {
             log_tx,
             log_rx,
             waker,
+            subscribers: SpinLock::new(Vec::new()),
         }
     }
 
@@ -190,6 +260,12 @@ impl Logger {
         }
     }
 
+    pub fn subscribe(&self) -> LogSubscriber {
+        let queue = Arc::new(SpinLock::new(SubscriberQueue::default()));
+        self.subscribers.lock().push(Arc::downgrade(&queue));
+        LogSubscriber { queue }
+    }
+
     pub async fn service(&self) {
         loop {
             let log = LogWaiter {
@@ -198,6 +274,17 @@ impl Logger {
             }
             .await;
             println!("{}", log);
+
+            let log = Arc::new(log);
+            self.subscribers
+                .lock()
+                .retain(|queue| match queue.upgrade() {
+                    Some(queue) => {
+                        queue.lock().push(&log);
+                        true
+                    }
+                    None => false,
+                });
         }
     }
 }
@@ -208,6 +295,11 @@ pub fn init(log_levels: HashMap<String, LogLevel>) {
     }
 }
 
+/// Every log from here on, for streaming somewhere other than the serial port
+pub fn subscribe() -> LogSubscriber {
+    LOGGER.subscribe()
+}
+
 pub async fn service() {
     LOGGER.service().await;
 }
@@ -21,9 +21,11 @@ mod logger;
 #[cfg(test)]
 mod testing;
 mod allocator;
+mod api;
 mod future;
 mod game;
 mod gdt;
+mod http;
 #[macro_use]
 mod interrupts;
 mod acpi;
@@ -43,51 +45,45 @@ mod usb;
 mod util;
 
 use acpi::MadtEntry;
-use alloc::{
-    boxed::Box,
-    format,
-    string::{String, ToString},
-    sync::Arc,
-    vec,
-    vec::Vec,
-};
+use alloc::{boxed::Box, format, string::String, sync::Arc, vec, vec::Vec};
 use multiboot2::Multiboot2;
 use multiprocessing::Apic;
 
+use core::{arch::global_asm, panic::PanicInfo};
+#[cfg(test)]
 use core::{
-    arch::global_asm,
-    panic::PanicInfo,
     pin::Pin,
     task::{Context, Poll},
 };
-use hashbrown::HashMap;
 
 use crate::{
     acpi::AcpiTable,
+    allocator::ALLOC,
+    api::ApiSources,
     cursor::Cursor,
     framebuffer::FrameBuffer,
-    future::{Either, Executor},
+    future::Executor,
+    http::{
+        websocket::{self, Message, WebSocket},
+        Method, Request, Response, Router, StatusCode,
+    },
     interrupts::{InitInterruptError, InterruptHandlerData},
     io::{
         io_allocator::IoAllocator,
-        pci::{Pci, PciDevice},
+        pci::{Pci, PciDevice, PciDeviceInfo},
         ps2::Ps2Keyboard,
         rtc::Rtc,
         serial::Serial,
     },
     mouse::Mouse,
     multiprocessing::CpuFnDispatcher,
-    net::{
-        tcp::Tcp, ArpFrame, ArpFrameParams, ArpOperation, EtherType, EthernetFrameParams,
-        ParsedIpv4Frame, ParsedPacket, UnknownArpOperation,
-    },
+    net::{tcp::Keepalive, udp::UdpSocket, Ipv4Config, NetStack},
     rng::Rng,
     rtl8139::Rtl8139,
     sleep::{WakeupRequester, WakeupService},
     time::MonotonicTime,
     usb::{uhci::Uhci, Usb, UsbDescriptor},
-    util::async_mutex::Mutex,
-    util::interrupt_guard::InterruptGuarded,
+    util::{async_io::AsyncRead, interrupt_guard::InterruptGuarded, json::ToJson},
 };
 
 // Include boot.s which defines _start as inline assembly in main. This allows us to do more fine
@@ -95,7 +91,13 @@ use crate::{
 // naked function + some inline asm, but this seems much more straight forward.
 global_asm!(include_str!("boot.s"), options(att_syntax));
 
-const STATIC_IP: [u8; 4] = [192, 168, 2, 2];
+// Used if no dhcp server answers
+const STATIC_CONFIG: Ipv4Config = Ipv4Config {
+    ip: [192, 168, 2, 2],
+    netmask: [255, 255, 255, 0],
+    gateway: None,
+    dns_server: None,
+};
 
 extern "C" {
     static KERNEL_START: u32;
@@ -149,70 +151,21 @@ unsafe fn interrupt_guarded_init(
 type IpAddr = [u8; 4];
 type MacAddr = [u8; 6];
 
-struct ArpReadyFuture<'a> {
-    ip: &'a IpAddr,
-    table: &'a Mutex<HashMap<IpAddr, MacAddr>>,
-}
-
-impl<'a> core::future::Future for ArpReadyFuture<'a> {
-    type Output = MacAddr;
-
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let guard = core::pin::pin!(self.table.lock());
-        let guard = match guard.poll(cx) {
-            Poll::Ready(v) => v,
-            Poll::Pending => {
-                return Poll::Pending;
-            }
-        };
-
-        match guard.get(self.ip) {
-            Some(v) => Poll::Ready(*v),
-            None => Poll::Pending,
-        }
-    }
-}
-
-struct ArpTable {
-    table: Mutex<HashMap<IpAddr, MacAddr>>,
-}
-
-impl ArpTable {
-    fn new() -> ArpTable {
-        let table = Mutex::new(HashMap::new());
-        ArpTable { table }
-    }
-
-    async fn write_mac(&self, ip: &IpAddr, mac: &MacAddr) {
-        let mut table = self.table.lock().await;
-        table.insert(*ip, *mac);
-    }
-
-    async fn wait_for(&self, ip: &[u8; 4]) -> [u8; 6] {
-        ArpReadyFuture {
-            ip,
-            table: &self.table,
-        }
-        .await
-    }
-}
-
 #[allow(unused)]
 struct Kernel {
     cpu_dispatcher: CpuFnDispatcher,
     io_allocator: IoAllocator,
     interrupt_handlers: &'static InterruptHandlerData,
-    rng: Mutex<Rng>,
     rtc: Rtc,
     pci: Pci,
+    // Everything found on the bus at boot
+    pci_devices: Vec<PciDeviceInfo>,
     ps2: Ps2Keyboard,
-    rtl8139: Rtl8139,
+    net: NetStack<Rtl8139>,
     usb: Usb,
-    arp_table: ArpTable,
     serial: Arc<Serial>,
     framebuffer: FrameBuffer,
     cursor: Cursor,
-    tcp: Tcp,
     monotonic_time: Arc<MonotonicTime>,
     wakeup_requester: WakeupRequester,
     wakeup_service: WakeupService,
@@ -249,7 +202,7 @@ impl Kernel {
             )
             .expect("Failed to register empty interrupt handler");
 
-        let mut rtc = io::rtc::Rtc::new(&mut io_allocator, interrupt_handlers, on_tick)
+        let rtc = io::rtc::Rtc::new(&mut io_allocator, interrupt_handlers, on_tick)
             .expect("Failed to construct rtc");
 
         let mut pci = Pci::new(&mut io_allocator).expect("Failed to initialize pci");
@@ -264,10 +217,12 @@ impl Kernel {
 
         let mut rtl8139 = None;
         let mut uhci = None;
+        let mut pci_device_info = Vec::new();
 
         for mut device in pci_devices {
-            let id = device.id(&mut pci);
-            let interface_id = device.interface_id(&mut pci);
+            let info = device.info(&mut pci);
+            let id = (info.vendor_id, info.device_id);
+            let interface_id = &info.interface_id;
 
             debug!(
                 "PCI device: {:?} with id {:#x}, {:#x} and interface: {:?}",
@@ -292,6 +247,8 @@ impl Kernel {
                     interrupt_handlers,
                 ));
             }
+
+            pci_device_info.push(info);
         }
 
         let rtl8139 = rtl8139.expect("Failed to find pci device id for rtl8139");
@@ -299,9 +256,14 @@ impl Kernel {
 
         let usb = Usb::new(uhci);
 
-        let arp_table = ArpTable::new();
-        let rng = Mutex::new(Rng::new(rtc.read().unwrap().seconds as u64));
-        let tcp = Tcp::new(Arc::clone(&monotonic_time), wakeup_requester.clone());
+        let rng = Rng::new(rtc.read().unwrap().seconds as u64);
+        let net = NetStack::new(
+            rtl8139,
+            Ipv4Config::UNCONFIGURED,
+            rng,
+            Arc::clone(&monotonic_time),
+            wakeup_requester.clone(),
+        );
 
         let framebuffer_info = info
             .get_framebuffer_info()
@@ -345,15 +307,13 @@ impl Kernel {
             interrupt_handlers,
             io_allocator,
             rtc,
-            rng,
             pci,
+            pci_devices: pci_device_info,
             ps2,
-            arp_table,
-            rtl8139,
+            net,
             cursor,
             usb,
             serial,
-            tcp,
             framebuffer,
             monotonic_time,
             wakeup_service,
@@ -376,111 +336,137 @@ impl Kernel {
             let date = self.rtc.read().expect("failed to read date");
             info!("Current date modified in cmos: {:?}", date);
 
-            self.rtl8139.log_mac().await;
+            self.net.interface().log_mac().await;
         };
 
         let send_udp = async {
-            let mac = self.rtl8139.get_mac();
             const REMOTE_IP: [u8; 4] = [192, 168, 2, 1];
-            let arp_frame: Vec<u8> = net::generate_arp_request(&REMOTE_IP, &STATIC_IP, &mac);
-            let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
-                dest_mac: [0xff; 6],
-                source_mac: mac,
-                ether_type: EtherType::Arp,
-                payload: &arp_frame,
-            });
-            self.rtl8139.write(&ethernet_frame).await.unwrap();
+            self.net.wait_for_config().await;
 
-            let sleep_fut = sleep::sleep(1.0, &self.monotonic_time, &self.wakeup_requester);
-            let sleep_fut = core::pin::pin!(sleep_fut);
-            let arp_lookup = self.arp_table.wait_for(&REMOTE_IP);
-            let arp_lookup = core::pin::pin!(arp_lookup);
-
-            let mac = match crate::future::select(arp_lookup, sleep_fut).await {
-                Either::Left((mac, _)) => mac,
-                Either::Right(_) => {
-                    warn!("ARP lookup for {:?} failed", REMOTE_IP);
+            let socket = match UdpSocket::bind(&self.net, 6000) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Failed to bind udp demo socket: {:?}", e);
                     return;
                 }
             };
 
-            info!("Resolved mac address!: {:?}", mac);
-
-            let udp_frame = net::generate_udp_frame(6000, b"hello from inside the os\n");
-            let ipv4_frame = net::generate_ipv4_frame(
-                &udp_frame,
-                net::Ipv4Protocol::Udp,
-                &STATIC_IP,
-                &REMOTE_IP,
-            );
-            let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
-                dest_mac: mac,
-                source_mac: self.rtl8139.get_mac(),
-                ether_type: EtherType::Ipv4,
-                payload: &ipv4_frame,
-            });
+            if let Err(e) = socket
+                .send_to(b"hello from inside the os\n", &REMOTE_IP, 6000)
+                .await
+            {
+                warn!("Failed to send udp demo message: {:?}", e);
+                return;
+            }
 
-            self.rtl8139.write(&ethernet_frame).await.unwrap();
+            info!("Listening for udp messages on port {}", socket.local_port());
 
-            info!("Sleeping for 5 seconds to wait for incoming connections");
-        };
+            match self.net.ping(&REMOTE_IP, 1.0).await {
+                Ok(rtt) => info!("Ping to {:?} took {}ms", REMOTE_IP, rtt * 1000.0),
+                Err(e) => warn!("Ping to {:?} failed: {:?}", REMOTE_IP, e),
+            }
 
-        let echo_tcp = async {
-            let listener = self.tcp.listen(STATIC_IP, 80).await;
             loop {
-                let connection = listener.connection().await;
-                let data = connection.read().await;
-
+                let datagram = socket.recv_from().await;
                 info!(
-                    "Received TCP data: \"{}\" on cpu {}",
-                    core::str::from_utf8_unchecked(&data),
-                    multiprocessing::cpuid()
+                    "Received UDP message from {:?}:{}: {}",
+                    datagram.source_ip,
+                    datagram.source_port,
+                    String::from_utf8_lossy(&datagram.data)
                 );
 
-                match handle_http_request(&data) {
-                    Ok(response) => {
-                        connection.write(response.to_string().into_bytes()).await;
+                if datagram.data == b"exit\n" {
+                    io::exit(0);
+                }
+            }
+        };
+
+        let connect_tcp = async {
+            const REMOTE_IP: [u8; 4] = [192, 168, 2, 1];
+            self.net.wait_for_config().await;
+
+            match self.net.tcp().connect(REMOTE_IP, 6000).await {
+                Ok(connection) => {
+                    info!("Connected to {:?}:6000", REMOTE_IP);
+                    // Request/response, there's nothing to batch
+                    connection.set_nodelay(true);
+                    // Don't wait on the answer forever if the peer goes away
+                    connection.set_keepalive(Some(Keepalive::default()));
+                    if let Err(e) = connection.write(&b"hello over tcp\n"[..]).await {
+                        warn!("Failed to write to {:?}:6000: {:?}", REMOTE_IP, e);
                     }
-                    Err(_) => {
-                        connection
-                            .write(
-                                "HTTP/1.1 500 Internal servrer error\r\n\
-                            Content-Length: 0
-                            \r\n\
-                            \r\n"
-                                    .to_string()
-                                    .into_bytes(),
-                            )
-                            .await;
+                    let mut data = Vec::new();
+                    match connection.read_until(b'\n', &mut data).await {
+                        Ok(_) => info!("Received TCP data: \"{}\"", String::from_utf8_lossy(&data)),
+                        Err(e) => warn!("Failed to read from {:?}:6000: {:?}", REMOTE_IP, e),
                     }
+                    connection.close();
                 }
+                Err(e) => warn!("Failed to connect to {:?}:6000: {:?}", REMOTE_IP, e),
             }
         };
 
-        let tcp_service = async {
-            loop {
-                let outgoing_data = self.tcp.service().await;
-                let ipv4_frame = net::generate_ipv4_frame(
-                    &outgoing_data.payload,
-                    net::Ipv4Protocol::Tcp,
-                    &outgoing_data.local_ip,
-                    &outgoing_data.remote_ip,
-                );
+        let fetch_http = async {
+            const REMOTE_URL: &str = "http://192.168.2.1:8000/";
+            self.net.wait_for_config().await;
 
-                // FIXME: Generate arp request if needed?
-                let ethernet_frame = net::generate_ethernet_frame(&EthernetFrameParams {
-                    dest_mac: self.arp_table.wait_for(&outgoing_data.remote_ip).await,
-                    source_mac: self.rtl8139.get_mac(),
-                    ether_type: EtherType::Ipv4,
-                    payload: &ipv4_frame,
-                });
+            match http::get(&self.net, REMOTE_URL).await {
+                Ok(response) => info!(
+                    "GET {} returned {} with {} bytes",
+                    REMOTE_URL,
+                    u16::from(response.status),
+                    response.body.len()
+                ),
+                Err(e) => {
+                    warn!("Failed to GET {}: {:?}", REMOTE_URL, e);
+                    return;
+                }
+            }
 
-                self.rtl8139.write(&ethernet_frame).await.unwrap();
+            // Report back how the heap is doing
+            let report_url = format!("{}heap", REMOTE_URL);
+            let stats = ALLOC.stats().to_json();
+            match http::post(&self.net, &report_url, "application/json", stats.as_bytes()).await {
+                Ok(response) => info!(
+                    "POST {} returned {}",
+                    report_url,
+                    u16::from(response.status)
+                ),
+                Err(e) => warn!("Failed to POST {}: {:?}", report_url, e),
             }
         };
 
-        let recv = async {
-            recv_loop(&self.rtl8139, &self.arp_table, &self.tcp, &self.rng).await;
+        let dhcp = async {
+            if let Err(e) = self.net.run_dhcp(STATIC_CONFIG).await {
+                error!("Failed to start dhcp client: {:?}", e);
+            }
+        };
+
+        let api_sources = ApiSources {
+            net: &self.net,
+            pci_devices: &self.pci_devices,
+            usb: self.usb.handle(),
+            cpu_dispatcher: &self.cpu_dispatcher,
+            rtc: &self.rtc,
+            time: &self.monotonic_time,
+        };
+        let http_server = async {
+            let listener = self.net.tcp().listen([0; 4], 80).await;
+            let router = Router::new()
+                .route(Method::Get, "/form", |request| {
+                    Box::pin(async move { handle_form(request) })
+                })
+                .route(Method::Post, "/form", |request| {
+                    Box::pin(async move { handle_form(request) })
+                })
+                .route(Method::Get, "/echo", |request| {
+                    Box::pin(async move { websocket::upgrade(&request, echo_websocket) })
+                });
+            // Before the assets, the first matching route wins
+            let router = api::add_routes(router, api_sources).route(Method::Get, "/*", |request| {
+                Box::pin(async move { http::assets::handle(&request) })
+            });
+            http::serve(&listener, &router).await;
         };
 
         let mouse_move_tx = self.cursor.get_movement_writer();
@@ -521,13 +507,15 @@ impl Kernel {
         let mut executor = Executor::new(Some(&self.cpu_dispatcher));
         executor.spawn(logger::service());
         executor.spawn(init_demo);
-        executor.spawn(recv);
-        executor.spawn(echo_tcp);
-        executor.spawn(tcp_service);
+        executor.spawn(self.net.service());
+        executor.spawn(dhcp);
+        executor.spawn(http_server);
         executor.spawn(send_udp);
+        executor.spawn(connect_tcp);
+        executor.spawn(fetch_http);
         executor.spawn(game.run());
         executor.spawn(self.wakeup_service.service());
-        executor.spawn(self.rtl8139.service());
+        executor.spawn(self.net.interface().service());
         executor.spawn(self.cpu_dispatcher.service());
         executor.spawn(self.usb.service());
         executor.spawn(usb_driver_dispatch);
@@ -538,238 +526,54 @@ impl Kernel {
     }
 }
 
-struct IncompleteHttpRequest;
-
-struct HttpResponse {
-    headers: HashMap<String, String>,
-    body: String,
-}
-
-impl HttpResponse {
-    fn new(body: String, content_type: String) -> HttpResponse {
-        let mut headers = HashMap::new();
-        headers.insert("Content-Length".to_string(), body.len().to_string());
-        headers.insert("Content-Type".to_string(), content_type);
-        headers.insert("Connection".to_string(), "close".into());
-
-        HttpResponse { headers, body }
-    }
-}
-
-impl core::fmt::Display for HttpResponse {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "HTTP/1.1 200 OK\r\n")?;
-        for (key, value) in &self.headers {
-            write!(f, "{}: {}\r\n", key, value)?;
-        }
-        write!(f, "\r\n{}", self.body)?;
-        Ok(())
-    }
-}
-
-fn handle_http_request(data: &[u8]) -> Result<HttpResponse, IncompleteHttpRequest> {
-    let first_line_end = data
-        .windows(2)
-        .position(|d| d == b"\r\n")
-        .ok_or(IncompleteHttpRequest)?;
-
-    let request_line = &data[..first_line_end];
-
-    let uri = request_line
-        .split(|b| *b == b' ')
-        .nth(1)
-        .ok_or(IncompleteHttpRequest)?;
-
-    let mut uri_items = uri.splitn(2, |b| *b == b'?');
-
-    let path = uri_items
-        .next()
-        .expect("Should always get at least 1 element");
-    let params = uri_items.next();
-
-    let ret = match path {
-        b"/" => HttpResponse::new(
-            include_str!("../res/index.html").to_string(),
-            "text/html".into(),
-        ),
-        b"/form" => unsafe {
-            HttpResponse::new(
-                format!(
-                    "Got form request with params: {}",
-                    core::str::from_utf8_unchecked(params.unwrap())
-                ),
-                "text/html".into(),
-            )
-        },
-        _ => HttpResponse::new("Uh oh".to_string(), "text/html".into()),
+// Echoes the submitted form, from the query for GET and the body for POST
+fn handle_form(request: Request) -> Response {
+    let form = match request.form() {
+        Ok(v) => v,
+        Err(e) => return Response::from_status(e.status()),
+    };
+    let repeat = match form.value::<usize>("repeat") {
+        Ok(v) => v.unwrap_or(1).min(100),
+        Err(e) => return Response::from_status(e.status()),
     };
 
-    Ok(ret)
-}
-
-async fn handle_arp_frame(
-    arp_frame: &ArpFrame<'_>,
-    rtl8139: &Rtl8139,
-    mac: &[u8; 6],
-    arp_table: &ArpTable,
-) {
-    debug!("Received arp frame: {:?}", arp_frame);
-
-    match arp_frame.operation() {
-        Ok(ArpOperation::Request) => (),
-        Ok(ArpOperation::Reply) => {
-            let mac = arp_frame
-                .sender_hardware_address()
-                .try_into()
-                .expect("Arp mac address not the right size");
-            let ip = arp_frame
-                .sender_protocol_address()
-                .try_into()
-                .expect("Arp ip address not the right size");
-            arp_table.write_mac(&ip, &mac).await;
-            return;
-        }
-        Err(UnknownArpOperation(v)) => {
-            debug!("Received unknown arp operation, {}", v);
-        }
+    let mut body = String::from("Got form request with params:\n");
+    for (name, value) in form.fields() {
+        body += &format!("{}: {}\n", name, value);
     }
-
-    if arp_frame.operation() != Ok(ArpOperation::Request) {
-        return;
+    if let Some(data) = form.get("data") {
+        // Repeats never make the echo bigger than the biggest body we'd accept
+        let repeat = repeat.min(http::parser::MAX_BODY_SIZE / data.len().max(1));
+        body += &format!("{}\n", data.repeat(repeat));
     }
-
-    if arp_frame.target_hardware_address() != mac
-        && arp_frame.target_protocol_address() != STATIC_IP
-    {
-        return;
+    if let Some(file) = form.file("upload") {
+        body += &format!(
+            "Uploaded {} ({}, {} bytes)\n",
+            file.filename,
+            file.content_type.as_deref().unwrap_or("unknown type"),
+            file.data.len()
+        );
     }
 
-    let mut params =
-        ArpFrameParams::try_from(arp_frame).expect("Arp frame should be validated above");
-
-    core::mem::swap(
-        &mut params.target_protocol_address,
-        &mut params.sender_protocol_address,
-    );
-    core::mem::swap(
-        &mut params.target_hardware_address,
-        &mut params.sender_hardware_address,
-    );
-    params.operation = ArpOperation::Reply;
-    params.sender_hardware_address = *mac;
-    params.sender_protocol_address = STATIC_IP;
-
-    let response = net::generate_arp_frame(&params);
-
-    let response_frame = net::generate_ethernet_frame(&EthernetFrameParams {
-        dest_mac: arp_frame
-            .sender_hardware_address()
-            .try_into()
-            .expect("Invalid length for dest mac"),
-        source_mac: *mac,
-        ether_type: EtherType::Arp,
-        payload: &response,
-    });
-
-    rtl8139.write(&response_frame).await.unwrap();
+    Response::new(StatusCode::Ok, "text/plain", body)
 }
 
-// FIXME: Where does this belong?
-async fn handle_packet(
-    packet: Vec<u8>,
-    rtl8139: &Rtl8139,
-    mac: &[u8; 6],
-    arp_table: &ArpTable,
-    tcp: &Tcp,
-    rng: &Mutex<Rng>,
-) {
-    let packet = net::parse_packet(&packet);
-
-    let packet = match packet {
-        Ok(v) => v,
-        Err(e) => {
-            debug!("Received invalid packet: {:?}", e);
-            return;
-        }
-    };
-
-    match packet.inner {
-        ParsedPacket::Arp(arp_frame) => {
-            handle_arp_frame(&arp_frame, rtl8139, mac, arp_table).await;
-        }
-        ParsedPacket::Ipv4(ipv4_frame) => {
-            debug!("Received IPV4 frame");
-            let frame = net::parse_ipv4(&ipv4_frame);
-            match frame {
-                Ok(ParsedIpv4Frame::Udp(udp_frame)) => {
-                    unsafe {
-                        debug!(
-                            "Received UDP message: {}",
-                            core::str::from_utf8_unchecked(udp_frame.data())
-                        );
-                    }
-                    if udp_frame.data() == b"exit\n" {
-                        unsafe {
-                            io::exit(0);
-                        }
-                    }
-                }
-                Ok(ParsedIpv4Frame::Tcp(tcp_frame)) => {
-                    //if rng.lock().await.normalized() < 0.1 {
-                    //    info!("Dropping packet");
-                    //    return
-                    //}
-                    let response_tcp_frame = tcp
-                        .handle_frame(&tcp_frame, &ipv4_frame.source_ip(), &STATIC_IP, rng)
-                        .await;
-                    if let Some(response_tcp_frame) = response_tcp_frame {
-                        let response_ipv4_frame = net::generate_ipv4_frame(
-                            &response_tcp_frame,
-                            net::Ipv4Protocol::Tcp,
-                            &STATIC_IP,
-                            &ipv4_frame.source_ip(),
-                        );
-
-                        let response_ethernet_frame =
-                            net::generate_ethernet_frame(&EthernetFrameParams {
-                                dest_mac: packet
-                                    .ethernet
-                                    .source_mac()
-                                    .try_into()
-                                    .expect("invalid source mac length"),
-                                source_mac: rtl8139.get_mac(),
-                                ether_type: EtherType::Ipv4,
-                                payload: &response_ipv4_frame,
-                            });
-
-                        rtl8139.write(&response_ethernet_frame).await.unwrap();
-                    }
-                }
-                Ok(ParsedIpv4Frame::Unknown(p)) => {
-                    debug!("Unknown ipv4 protocol {:?}", p);
-                }
-                Err(e) => {
-                    debug!("Invalid ipv4 packet: {:?}", e);
-                }
+// Sends every message back until the client closes the connection
+async fn echo_websocket(socket: WebSocket) {
+    loop {
+        let sent = match socket.recv().await {
+            Ok(Some(Message::Text(text))) => socket.send_text(&text).await,
+            Ok(Some(Message::Binary(data))) => socket.send_binary(&data).await,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("Websocket echo failed: {:?}", e);
+                return;
             }
+        };
+        if let Err(e) = sent {
+            debug!("Failed to echo websocket message: {:?}", e);
+            return;
         }
-        ParsedPacket::Unknown(t) => {
-            debug!("Found unknown packet type: {:#06x}", t);
-        }
-    }
-}
-
-async fn recv_loop(rtl8139: &Rtl8139, arp_table: &ArpTable, tcp: &Tcp, rng: &Mutex<Rng>) {
-    let mac = rtl8139.get_mac();
-
-    loop {
-        debug!("Waiting for a packet");
-        rtl8139
-            .read(|packet| {
-                // FIXME: Avoid copying but types are hard
-                handle_packet(packet.to_vec(), rtl8139, &mac, arp_table, tcp, rng)
-            })
-            .await;
     }
 }
 
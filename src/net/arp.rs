@@ -0,0 +1,176 @@
+use crate::{time::MonotonicTime, util::async_mutex::Mutex, IpAddr, MacAddr};
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use hashbrown::HashMap;
+
+// Time an entry stays valid after we last heard from the remote
+const ENTRY_LIFETIME_S: f32 = 60.0;
+
+#[derive(Debug)]
+pub struct ArpTimeout(pub IpAddr);
+
+/// An unexpired entry, for listing the table
+#[derive(Debug)]
+pub struct ArpTableEntry {
+    pub ip: IpAddr,
+    pub mac: MacAddr,
+    pub expires_in_s: f32,
+}
+
+struct ArpEntry {
+    mac: MacAddr,
+    expiry: usize,
+}
+
+struct ArpTableInner {
+    entries: HashMap<IpAddr, ArpEntry>,
+    waiters: Vec<Waker>,
+}
+
+impl ArpTableInner {
+    fn get(&mut self, ip: &IpAddr, now: usize) -> Option<MacAddr> {
+        let entry = self.entries.get(ip)?;
+        if entry.expiry < now {
+            debug!("Arp entry for {:?} expired", ip);
+            self.entries.remove(ip);
+            return None;
+        }
+
+        Some(entry.mac)
+    }
+}
+
+struct ArpReadyFuture<'a> {
+    ip: &'a IpAddr,
+    table: &'a ArpTable,
+}
+
+impl Future for ArpReadyFuture<'_> {
+    type Output = MacAddr;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let guard = core::pin::pin!(self.table.inner.lock());
+        let mut guard = match guard.poll(cx) {
+            Poll::Ready(v) => v,
+            Poll::Pending => {
+                return Poll::Pending;
+            }
+        };
+
+        match guard.get(self.ip, self.table.time.get()) {
+            Some(v) => Poll::Ready(v),
+            None => {
+                if !guard.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                    guard.waiters.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub struct ArpTable {
+    inner: Mutex<ArpTableInner>,
+    time: Arc<MonotonicTime>,
+}
+
+impl ArpTable {
+    pub fn new(time: Arc<MonotonicTime>) -> ArpTable {
+        let inner = Mutex::new(ArpTableInner {
+            entries: HashMap::new(),
+            waiters: Vec::new(),
+        });
+        ArpTable { inner, time }
+    }
+
+    pub async fn write_mac(&self, ip: &IpAddr, mac: &MacAddr) {
+        let now = self.time.get();
+        let expiry = now + (ENTRY_LIFETIME_S * self.time.tick_freq()) as usize;
+
+        let mut inner = self.inner.lock().await;
+        inner.entries.retain(|_, entry| entry.expiry >= now);
+        inner.entries.insert(*ip, ArpEntry { mac: *mac, expiry });
+
+        for waker in inner.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Updates the mac for ip only if we already know about it. Returns whether the entry was
+    /// updated
+    pub async fn refresh_mac(&self, ip: &IpAddr, mac: &MacAddr) -> bool {
+        if self.get(ip).await.is_none() {
+            return false;
+        }
+
+        self.write_mac(ip, mac).await;
+        true
+    }
+
+    pub async fn get(&self, ip: &IpAddr) -> Option<MacAddr> {
+        self.inner.lock().await.get(ip, self.time.get())
+    }
+
+    pub async fn wait_for(&self, ip: &IpAddr) -> MacAddr {
+        ArpReadyFuture { ip, table: self }.await
+    }
+
+    pub async fn entries(&self) -> Vec<ArpTableEntry> {
+        let now = self.time.get();
+        let inner = self.inner.lock().await;
+        inner
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expiry >= now)
+            .map(|(ip, entry)| ArpTableEntry {
+                ip: *ip,
+                mac: entry.mac,
+                expires_in_s: (entry.expiry - now) as f32 / self.time.tick_freq(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    create_test!(test_arp_entry_expiry, {
+        const IP: IpAddr = [192, 168, 2, 1];
+        const MAC: MacAddr = [1, 2, 3, 4, 5, 6];
+
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let table = ArpTable::new(Arc::clone(&time));
+
+        test_eq!(table.get(&IP).await, None::<MacAddr>);
+        test_false!(table.refresh_mac(&IP, &MAC).await);
+
+        table.write_mac(&IP, &MAC).await;
+        test_eq!(table.get(&IP).await, Some(MAC));
+        test_eq!(
+            crate::future::poll_immediate(table.wait_for(&IP)).await,
+            Some(MAC)
+        );
+
+        let entries = table.entries().await;
+        test_eq!(entries.len(), 1);
+        test_eq!(entries[0].mac, MAC);
+        test_eq!(entries[0].expires_in_s, ENTRY_LIFETIME_S);
+
+        time.set_tick((ENTRY_LIFETIME_S * time.tick_freq()) as usize + 1);
+        test_eq!(table.get(&IP).await, None::<MacAddr>);
+        test_true!(table.entries().await.is_empty());
+        test_true!(crate::future::poll_immediate(table.wait_for(&IP))
+            .await
+            .is_none());
+
+        Ok(())
+    });
+}
@@ -0,0 +1,619 @@
+use crate::{
+    future::Either,
+    net::{
+        udp::{PortInUse, UdpSocket},
+        Ipv4Config, NetStack, NetworkInterface,
+    },
+    sleep, IpAddr, MacAddr,
+};
+
+use alloc::vec::Vec;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const HEADER_LENGTH: usize = 236;
+const OPTIONS_OFFSET: usize = HEADER_LENGTH + MAGIC_COOKIE.len();
+
+const OP_BOOT_REQUEST: u8 = 1;
+const OP_BOOT_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+// Ask the server to broadcast its replies, we can't receive unicast before we have an address
+const FLAG_BROADCAST: u16 = 0x8000;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+// Discover is sent this many times, doubling the wait each time, before we give up and use the
+// fallback configuration
+const DISCOVER_ATTEMPTS: usize = 3;
+const INITIAL_TIMEOUT_S: f32 = 1.0;
+// Discovery keeps being retried after falling back, doubling the wait each time up to the max
+const FALLBACK_RETRY_S: f32 = 10.0;
+const MAX_FALLBACK_RETRY_S: f32 = 600.0;
+// Minimum time between request retransmissions while renewing/rebinding
+const MIN_RENEW_RETRANSMIT_S: f32 = 60.0;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum DhcpMessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct UnknownDhcpMessageType(pub u8);
+
+impl TryFrom<u8> for DhcpMessageType {
+    type Error = UnknownDhcpMessageType;
+
+    fn try_from(value: u8) -> Result<Self, UnknownDhcpMessageType> {
+        let ret = match value {
+            1 => DhcpMessageType::Discover,
+            2 => DhcpMessageType::Offer,
+            3 => DhcpMessageType::Request,
+            4 => DhcpMessageType::Decline,
+            5 => DhcpMessageType::Ack,
+            6 => DhcpMessageType::Nak,
+            7 => DhcpMessageType::Release,
+            8 => DhcpMessageType::Inform,
+            v => return Err(UnknownDhcpMessageType(v)),
+        };
+        Ok(ret)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidDhcpFrame;
+
+pub struct DhcpFrame<'a> {
+    packet: &'a [u8],
+}
+
+impl<'a> DhcpFrame<'a> {
+    pub fn new(packet: &'a [u8]) -> Result<DhcpFrame<'a>, InvalidDhcpFrame> {
+        if packet.len() < OPTIONS_OFFSET || packet[HEADER_LENGTH..OPTIONS_OFFSET] != MAGIC_COOKIE {
+            return Err(InvalidDhcpFrame);
+        }
+
+        Ok(DhcpFrame { packet })
+    }
+
+    pub fn op(&self) -> u8 {
+        self.packet[0]
+    }
+
+    pub fn xid(&self) -> u32 {
+        u32::from_be_bytes(self.packet[4..8].try_into().expect("Invalid xid length"))
+    }
+
+    pub fn yiaddr(&self) -> IpAddr {
+        self.packet[16..20]
+            .try_into()
+            .expect("Invalid yiaddr length")
+    }
+
+    pub fn chaddr(&self) -> &[u8] {
+        // Hardware address field is 16 bytes, but only the first 6 are used for ethernet
+        &self.packet[28..34]
+    }
+
+    pub fn options(&self) -> DhcpOptionIter<'a> {
+        DhcpOptionIter {
+            data: &self.packet[OPTIONS_OFFSET..],
+        }
+    }
+
+    pub fn option(&self, code: u8) -> Option<&'a [u8]> {
+        self.options()
+            .find(|(option_code, _)| *option_code == code)
+            .map(|(_, data)| data)
+    }
+
+    pub fn message_type(&self) -> Option<DhcpMessageType> {
+        let data = self.option(OPTION_MESSAGE_TYPE)?;
+        DhcpMessageType::try_from(*data.first()?).ok()
+    }
+
+    fn ip_option(&self, code: u8) -> Option<IpAddr> {
+        self.option(code)?.get(0..4)?.try_into().ok()
+    }
+
+    fn u32_option(&self, code: u8) -> Option<u32> {
+        let data = self.option(code)?.get(0..4)?;
+        Some(u32::from_be_bytes(data.try_into().ok()?))
+    }
+}
+
+impl core::fmt::Debug for DhcpFrame<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "op: {}", self.op())?;
+        writeln!(f, "xid: {:#x}", self.xid())?;
+        writeln!(f, "yiaddr: {:?}", self.yiaddr())?;
+        writeln!(f, "chaddr: {:x?}", self.chaddr())?;
+        writeln!(f, "message_type: {:?}", self.message_type())?;
+        Ok(())
+    }
+}
+
+pub struct DhcpOptionIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for DhcpOptionIter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let code = *self.data.first()?;
+            match code {
+                OPTION_PAD => {
+                    self.data = &self.data[1..];
+                }
+                OPTION_END => {
+                    self.data = &[];
+                    return None;
+                }
+                _ => {
+                    let length = *self.data.get(1)? as usize;
+                    let option_data = self.data.get(2..2 + length)?;
+                    self.data = &self.data[2 + length..];
+                    return Some((code, option_data));
+                }
+            }
+        }
+    }
+}
+
+pub struct DhcpMessageParams {
+    pub message_type: DhcpMessageType,
+    pub xid: u32,
+    pub mac: MacAddr,
+    pub ciaddr: IpAddr,
+    pub requested_ip: Option<IpAddr>,
+    pub server_id: Option<IpAddr>,
+}
+
+pub fn generate_dhcp_message(params: &DhcpMessageParams) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(OPTIONS_OFFSET + 32);
+
+    ret.push(OP_BOOT_REQUEST);
+    ret.push(HTYPE_ETHERNET);
+    ret.push(params.mac.len() as u8);
+    // Hops
+    ret.push(0);
+    ret.extend_from_slice(&params.xid.to_be_bytes());
+    // Seconds elapsed
+    ret.extend_from_slice(&0u16.to_be_bytes());
+    ret.extend_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    ret.extend_from_slice(&params.ciaddr);
+    // yiaddr, siaddr, giaddr
+    ret.extend_from_slice(&[0; 12]);
+    ret.extend_from_slice(&params.mac);
+    // Rest of chaddr + sname + file
+    ret.resize(HEADER_LENGTH, 0);
+    ret.extend_from_slice(&MAGIC_COOKIE);
+
+    ret.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, params.message_type as u8]);
+
+    if let Some(requested_ip) = &params.requested_ip {
+        ret.extend_from_slice(&[OPTION_REQUESTED_IP, 4]);
+        ret.extend_from_slice(requested_ip);
+    }
+
+    if let Some(server_id) = &params.server_id {
+        ret.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+        ret.extend_from_slice(server_id);
+    }
+
+    ret.extend_from_slice(&[
+        OPTION_PARAMETER_REQUEST_LIST,
+        3,
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS_SERVER,
+    ]);
+    ret.push(OPTION_END);
+
+    ret
+}
+
+struct DhcpLease {
+    config: Ipv4Config,
+    server_id: IpAddr,
+    // Ticks at which we should start renewing, start rebinding, and give up
+    renew: usize,
+    rebind: usize,
+    expiry: Option<usize>,
+}
+
+struct DhcpClient<'a, I> {
+    net: &'a NetStack<I>,
+    socket: UdpSocket<'a, I>,
+    mac: MacAddr,
+    xid: u32,
+}
+
+impl<I: NetworkInterface> DhcpClient<'_, I> {
+    async fn send(&self, message: &DhcpMessageParams, dest_ip: &IpAddr) {
+        let payload = generate_dhcp_message(message);
+        let res = self.socket.send_to(&payload, dest_ip, SERVER_PORT).await;
+
+        if let Err(e) = res {
+            warn!("Failed to send dhcp message: {:?}", e);
+        }
+    }
+
+    /// Waits for a reply to our current transaction with one of the given message types
+    async fn wait_for_reply(
+        &self,
+        message_types: &[DhcpMessageType],
+        timeout_s: f32,
+    ) -> Option<Vec<u8>> {
+        let time = &self.net.time;
+        let deadline = time.get() + (timeout_s * time.tick_freq()) as usize;
+
+        loop {
+            let now = time.get();
+            if now >= deadline {
+                return None;
+            }
+
+            let remaining_s = (deadline - now) as f32 / time.tick_freq();
+            let sleep_fut = sleep::sleep(remaining_s, time, &self.net.wakeup_requester);
+            let sleep_fut = core::pin::pin!(sleep_fut);
+            let recv = core::pin::pin!(self.socket.recv_from());
+
+            let data = match crate::future::select(recv, sleep_fut).await {
+                Either::Left((datagram, _)) => datagram.data,
+                Either::Right(_) => return None,
+            };
+
+            let frame = match DhcpFrame::new(&data) {
+                Ok(v) => v,
+                Err(e) => {
+                    debug!("Invalid dhcp frame: {:?}", e);
+                    continue;
+                }
+            };
+
+            if frame.op() != OP_BOOT_REPLY || frame.xid() != self.xid || frame.chaddr() != self.mac
+            {
+                continue;
+            }
+
+            match frame.message_type() {
+                Some(t) if message_types.contains(&t) => return Some(data),
+                t => debug!("Ignoring dhcp message of type {:?}", t),
+            }
+        }
+    }
+
+    async fn discover(&self) -> Option<(IpAddr, IpAddr)> {
+        let mut timeout_s = INITIAL_TIMEOUT_S;
+
+        for _ in 0..DISCOVER_ATTEMPTS {
+            self.send(
+                &DhcpMessageParams {
+                    message_type: DhcpMessageType::Discover,
+                    xid: self.xid,
+                    mac: self.mac,
+                    ciaddr: [0; 4],
+                    requested_ip: None,
+                    server_id: None,
+                },
+                &[255; 4],
+            )
+            .await;
+
+            if let Some(offer) = self
+                .wait_for_reply(&[DhcpMessageType::Offer], timeout_s)
+                .await
+            {
+                let offer = DhcpFrame::new(&offer).expect("Offer validated in wait_for_reply");
+                match offer.ip_option(OPTION_SERVER_ID) {
+                    Some(server_id) => return Some((offer.yiaddr(), server_id)),
+                    None => warn!("Dhcp offer without server id"),
+                }
+            }
+
+            timeout_s *= 2.0;
+        }
+
+        None
+    }
+
+    async fn request(&self, offered_ip: &IpAddr, server_id: &IpAddr) -> Option<DhcpLease> {
+        let mut timeout_s = INITIAL_TIMEOUT_S;
+
+        for _ in 0..DISCOVER_ATTEMPTS {
+            self.send(
+                &DhcpMessageParams {
+                    message_type: DhcpMessageType::Request,
+                    xid: self.xid,
+                    mac: self.mac,
+                    ciaddr: [0; 4],
+                    requested_ip: Some(*offered_ip),
+                    server_id: Some(*server_id),
+                },
+                &[255; 4],
+            )
+            .await;
+
+            let reply = self
+                .wait_for_reply(&[DhcpMessageType::Ack, DhcpMessageType::Nak], timeout_s)
+                .await;
+
+            if let Some(reply) = reply {
+                return self.lease_from_ack(&reply);
+            }
+
+            timeout_s *= 2.0;
+        }
+
+        None
+    }
+
+    /// Extends an existing lease. Unicasts to the server that gave us the lease until the
+    /// rebind time, and then broadcasts to anyone until the lease expires
+    async fn extend(&self, lease: &DhcpLease) -> Option<DhcpLease> {
+        let time = &self.net.time;
+        let expiry = lease.expiry?;
+
+        loop {
+            let now = time.get();
+            if now >= expiry {
+                return None;
+            }
+
+            let (dest_ip, deadline) = if now < lease.rebind {
+                (lease.server_id, lease.rebind)
+            } else {
+                ([255; 4], expiry)
+            };
+
+            let remaining_s = (deadline - now) as f32 / time.tick_freq();
+            let timeout_s = (remaining_s / 2.0)
+                .max(MIN_RENEW_RETRANSMIT_S)
+                .min(remaining_s);
+
+            self.send(
+                &DhcpMessageParams {
+                    message_type: DhcpMessageType::Request,
+                    xid: self.xid,
+                    mac: self.mac,
+                    ciaddr: lease.config.ip,
+                    requested_ip: None,
+                    server_id: None,
+                },
+                &dest_ip,
+            )
+            .await;
+
+            let reply = self
+                .wait_for_reply(&[DhcpMessageType::Ack, DhcpMessageType::Nak], timeout_s)
+                .await;
+
+            if let Some(reply) = reply {
+                return self.lease_from_ack(&reply);
+            }
+        }
+    }
+
+    fn lease_from_ack(&self, data: &[u8]) -> Option<DhcpLease> {
+        let ack = DhcpFrame::new(data).ok()?;
+        if ack.message_type() != Some(DhcpMessageType::Ack) {
+            warn!("Dhcp request refused");
+            return None;
+        }
+
+        let server_id = ack.ip_option(OPTION_SERVER_ID)?;
+        let config = Ipv4Config {
+            ip: ack.yiaddr(),
+            netmask: ack
+                .ip_option(OPTION_SUBNET_MASK)
+                .unwrap_or([255, 255, 255, 0]),
+            gateway: ack.ip_option(OPTION_ROUTER),
+            dns_server: ack.ip_option(OPTION_DNS_SERVER),
+        };
+
+        let time = &self.net.time;
+        let now = time.get();
+        // Long times after a long uptime would overflow, those are as good as never anyway
+        let to_ticks = |s: u32| now.saturating_add((s as f32 * time.tick_freq()) as usize);
+
+        // 0xffffffff is an infinite lease (RFC 2132 9.2)
+        let lease_time = ack.u32_option(OPTION_LEASE_TIME).unwrap_or(u32::MAX);
+        let (renew, rebind, expiry) = if lease_time == u32::MAX {
+            (usize::MAX, usize::MAX, None)
+        } else {
+            let renew = ack
+                .u32_option(OPTION_RENEWAL_TIME)
+                .unwrap_or(lease_time / 2);
+            let rebind = ack
+                .u32_option(OPTION_REBINDING_TIME)
+                .unwrap_or(lease_time / 8 * 7);
+            (
+                to_ticks(renew),
+                to_ticks(rebind),
+                Some(to_ticks(lease_time)),
+            )
+        };
+
+        info!(
+            "Dhcp lease acquired from {:?} for {}s: {:?}",
+            server_id, lease_time, config
+        );
+
+        Some(DhcpLease {
+            config,
+            server_id,
+            renew,
+            rebind,
+            expiry,
+        })
+    }
+
+    async fn acquire(&self) -> Option<DhcpLease> {
+        let (offered_ip, server_id) = self.discover().await?;
+        debug!("Dhcp offer of {:?} from {:?}", offered_ip, server_id);
+        self.request(&offered_ip, &server_id).await
+    }
+}
+
+pub(super) async fn run<I: NetworkInterface>(
+    net: &NetStack<I>,
+    fallback: Ipv4Config,
+) -> Result<(), PortInUse> {
+    let mut client = DhcpClient {
+        net,
+        socket: UdpSocket::bind(net, CLIENT_PORT)?,
+        mac: net.mac,
+        xid: 0,
+    };
+
+    let mut retry_s = FALLBACK_RETRY_S;
+    loop {
+        client.xid = net.rng.lock().await.u64() as u32;
+
+        let mut lease = match client.acquire().await {
+            Some(v) => v,
+            None => {
+                if net.config() != fallback {
+                    warn!("No dhcp server answered, using {:?}", fallback);
+                    net.set_config(fallback).await;
+                }
+
+                // A server that comes up later still gets picked up
+                sleep::sleep(retry_s, &net.time, &net.wakeup_requester).await;
+                retry_s = (retry_s * 2.0).min(MAX_FALLBACK_RETRY_S);
+                continue;
+            }
+        };
+        retry_s = FALLBACK_RETRY_S;
+
+        net.set_config(lease.config).await;
+
+        loop {
+            let expiry = match lease.expiry {
+                Some(v) => v,
+                None => {
+                    // Infinite lease, nothing left to do
+                    return Ok(());
+                }
+            };
+
+            let now = net.time.get();
+            if lease.renew > now {
+                let wait_s = (lease.renew - now) as f32 / net.time.tick_freq();
+                sleep::sleep(wait_s, &net.time, &net.wakeup_requester).await;
+            }
+
+            client.xid = net.rng.lock().await.u64() as u32;
+            match client.extend(&lease).await {
+                Some(v) => {
+                    if v.config != lease.config {
+                        net.set_config(v.config).await;
+                    }
+                    lease = v;
+                }
+                None => {
+                    warn!("Dhcp lease lost at tick {}", expiry);
+                    net.set_config(Ipv4Config::UNCONFIGURED).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::string::ToString;
+
+    create_test!(test_dhcp_message_generation, {
+        const MAC: MacAddr = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+        let message = generate_dhcp_message(&DhcpMessageParams {
+            message_type: DhcpMessageType::Request,
+            xid: 0xdeadbeef,
+            mac: MAC,
+            ciaddr: [0; 4],
+            requested_ip: Some([192, 168, 2, 50]),
+            server_id: Some([192, 168, 2, 1]),
+        });
+
+        let frame = DhcpFrame::new(&message).map_err(|_| "Invalid dhcp frame".to_string())?;
+        test_eq!(frame.op(), OP_BOOT_REQUEST);
+        test_eq!(frame.xid(), 0xdeadbeefu32);
+        test_eq!(frame.chaddr(), &MAC);
+        test_eq!(frame.message_type(), Some(DhcpMessageType::Request));
+        test_eq!(
+            frame.ip_option(OPTION_REQUESTED_IP),
+            Some([192, 168, 2, 50])
+        );
+        test_eq!(frame.ip_option(OPTION_SERVER_ID), Some([192, 168, 2, 1]));
+        test_eq!(frame.ip_option(OPTION_ROUTER), None::<IpAddr>);
+
+        Ok(())
+    });
+
+    create_test!(test_dhcp_option_parsing, {
+        let mut message = [0u8; OPTIONS_OFFSET].to_vec();
+        message[HEADER_LENGTH..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+        message.extend_from_slice(&[
+            OPTION_MESSAGE_TYPE,
+            1,
+            DhcpMessageType::Ack as u8,
+            OPTION_PAD,
+            OPTION_LEASE_TIME,
+            4,
+            0,
+            0,
+            0x0e,
+            0x10,
+            OPTION_END,
+            OPTION_ROUTER,
+            4,
+            1,
+            2,
+            3,
+            4,
+        ]);
+
+        let frame = DhcpFrame::new(&message).map_err(|_| "Invalid dhcp frame".to_string())?;
+        test_eq!(frame.message_type(), Some(DhcpMessageType::Ack));
+        test_eq!(frame.u32_option(OPTION_LEASE_TIME), Some(3600));
+        // Options after the end marker should be ignored
+        test_eq!(frame.ip_option(OPTION_ROUTER), None::<IpAddr>);
+
+        // Truncated options should not be returned
+        message.truncate(OPTIONS_OFFSET + 8);
+        let frame = DhcpFrame::new(&message).map_err(|_| "Invalid dhcp frame".to_string())?;
+        test_eq!(frame.message_type(), Some(DhcpMessageType::Ack));
+        test_eq!(frame.u32_option(OPTION_LEASE_TIME), None::<u32>);
+
+        message[HEADER_LENGTH] = 0;
+        test_err!(DhcpFrame::new(&message));
+
+        Ok(())
+    });
+}
@@ -0,0 +1,262 @@
+use crate::{
+    net::{self, arp::ArpTimeout},
+    util::{
+        async_mutex::Mutex,
+        oneshot::{self, Receiver, Sender},
+    },
+};
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use hashbrown::HashMap;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IcmpType {
+    EchoReply,
+    DestinationUnreachable,
+    EchoRequest,
+    Unknown(u8),
+}
+
+impl From<u8> for IcmpType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => IcmpType::EchoReply,
+            3 => IcmpType::DestinationUnreachable,
+            8 => IcmpType::EchoRequest,
+            v => IcmpType::Unknown(v),
+        }
+    }
+}
+
+impl From<IcmpType> for u8 {
+    fn from(value: IcmpType) -> Self {
+        match value {
+            IcmpType::EchoReply => 0,
+            IcmpType::DestinationUnreachable => 3,
+            IcmpType::EchoRequest => 8,
+            IcmpType::Unknown(v) => v,
+        }
+    }
+}
+
+// Destination unreachable codes
+pub const PROTOCOL_UNREACHABLE: u8 = 2;
+pub const PORT_UNREACHABLE: u8 = 3;
+
+#[derive(Debug)]
+pub struct InvalidIcmpFrame;
+
+#[derive(Debug)]
+pub enum PingError {
+    Arp(ArpTimeout),
+    Timeout,
+}
+
+pub struct IcmpFrame<'a> {
+    packet: &'a [u8],
+}
+
+impl<'a> IcmpFrame<'a> {
+    const HEADER_LENGTH: usize = 8;
+
+    pub(super) fn new(packet: &'a [u8]) -> Result<IcmpFrame<'a>, InvalidIcmpFrame> {
+        if packet.len() < Self::HEADER_LENGTH {
+            return Err(InvalidIcmpFrame);
+        }
+
+        // Checksum over a packet with a valid checksum in it ends up as 0
+        if net::calculate_ipv4_checksum(packet) != 0 {
+            return Err(InvalidIcmpFrame);
+        }
+
+        Ok(IcmpFrame { packet })
+    }
+
+    pub fn icmp_type(&self) -> IcmpType {
+        self.packet[0].into()
+    }
+
+    pub fn code(&self) -> u8 {
+        self.packet[1]
+    }
+
+    pub fn checksum(&self) -> u16 {
+        u16::from_be_bytes(
+            self.packet[2..4]
+                .try_into()
+                .expect("icmp checksum length wrong"),
+        )
+    }
+
+    pub fn identifier(&self) -> u16 {
+        u16::from_be_bytes(
+            self.packet[4..6]
+                .try_into()
+                .expect("icmp identifier length wrong"),
+        )
+    }
+
+    pub fn sequence_number(&self) -> u16 {
+        u16::from_be_bytes(
+            self.packet[6..8]
+                .try_into()
+                .expect("icmp sequence number length wrong"),
+        )
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.packet[Self::HEADER_LENGTH..]
+    }
+}
+
+impl core::fmt::Debug for IcmpFrame<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "type: {:?}", self.icmp_type())?;
+        writeln!(f, "code: {}", self.code())?;
+        writeln!(f, "checksum: {:x}", self.checksum())?;
+        writeln!(f, "payload: {:x?}", self.payload())?;
+        Ok(())
+    }
+}
+
+pub fn generate_icmp_frame(
+    icmp_type: IcmpType,
+    code: u8,
+    rest_of_header: [u8; 4],
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(IcmpFrame::HEADER_LENGTH + payload.len());
+    ret.push(icmp_type.into());
+    ret.push(code);
+    let checksum_idx = ret.len();
+    ret.extend_from_slice(&0u16.to_be_bytes());
+    ret.extend_from_slice(&rest_of_header);
+    ret.extend_from_slice(payload);
+
+    let checksum = net::calculate_ipv4_checksum(&ret);
+    ret[checksum_idx..checksum_idx + 2].copy_from_slice(&checksum.to_be_bytes());
+    ret
+}
+
+pub fn generate_echo(
+    icmp_type: IcmpType,
+    identifier: u16,
+    sequence_number: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut rest_of_header = [0; 4];
+    rest_of_header[0..2].copy_from_slice(&identifier.to_be_bytes());
+    rest_of_header[2..4].copy_from_slice(&sequence_number.to_be_bytes());
+    generate_icmp_frame(icmp_type, 0, rest_of_header, payload)
+}
+
+/// Destination unreachable message for the given ipv4 packet. Per RFC 792 we echo back the ip
+/// header and the first 8 bytes of the payload so the sender can tell which packet failed
+pub fn generate_destination_unreachable(
+    code: u8,
+    ipv4_packet: &[u8],
+    header_length: usize,
+) -> Vec<u8> {
+    let end = ipv4_packet.len().min(header_length + 8);
+    generate_icmp_frame(
+        IcmpType::DestinationUnreachable,
+        code,
+        [0; 4],
+        &ipv4_packet[..end],
+    )
+}
+
+pub struct Icmp {
+    identifier: u16,
+    next_sequence_number: AtomicU16,
+    // Sequence number -> sender for the tick the reply arrived at
+    pending_echos: Mutex<HashMap<u16, Sender<usize>>>,
+}
+
+impl Icmp {
+    pub fn new(identifier: u16) -> Icmp {
+        Icmp {
+            identifier,
+            next_sequence_number: AtomicU16::new(0),
+            pending_echos: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(super) async fn register_echo(&self) -> (Vec<u8>, u16, Receiver<usize>) {
+        let sequence_number = self.next_sequence_number.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending_echos.lock().await.insert(sequence_number, tx);
+
+        const PAYLOAD: &[u8] = b"stream-os ping";
+        let request = generate_echo(
+            IcmpType::EchoRequest,
+            self.identifier,
+            sequence_number,
+            PAYLOAD,
+        );
+
+        (request, sequence_number, rx)
+    }
+
+    pub(super) async fn cancel_echo(&self, sequence_number: u16) {
+        self.pending_echos.lock().await.remove(&sequence_number);
+    }
+
+    pub(super) async fn handle_echo_reply(&self, frame: &IcmpFrame<'_>, tick: usize) {
+        if frame.identifier() != self.identifier {
+            return;
+        }
+
+        let tx = self
+            .pending_echos
+            .lock()
+            .await
+            .remove(&frame.sequence_number());
+
+        match tx {
+            Some(tx) => tx.send(tick).await,
+            None => debug!("Unexpected echo reply {}", frame.sequence_number()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::string::ToString;
+
+    create_test!(test_icmp_echo_back_and_forth, {
+        // Odd length payload to exercise checksum of odd length data
+        let echo = generate_echo(IcmpType::EchoRequest, 0x1234, 7, b"hello");
+        let frame = IcmpFrame::new(&echo).map_err(|_| "Invalid icmp frame".to_string())?;
+
+        test_eq!(frame.icmp_type(), IcmpType::EchoRequest);
+        test_eq!(frame.code(), 0);
+        test_eq!(frame.identifier(), 0x1234);
+        test_eq!(frame.sequence_number(), 7);
+        test_eq!(frame.payload(), b"hello");
+
+        let mut corrupted = echo.clone();
+        corrupted[9] ^= 0xff;
+        test_err!(IcmpFrame::new(&corrupted));
+
+        test_err!(IcmpFrame::new(&echo[0..7]));
+
+        Ok(())
+    });
+
+    create_test!(test_destination_unreachable_truncation, {
+        let ipv4_packet: Vec<u8> = (0..64).collect();
+        let unreachable = generate_destination_unreachable(PORT_UNREACHABLE, &ipv4_packet, 20);
+        let frame = IcmpFrame::new(&unreachable).map_err(|_| "Invalid icmp frame".to_string())?;
+
+        test_eq!(frame.icmp_type(), IcmpType::DestinationUnreachable);
+        test_eq!(frame.code(), PORT_UNREACHABLE);
+        test_eq!(frame.payload(), &ipv4_packet[0..28]);
+
+        Ok(())
+    });
+}
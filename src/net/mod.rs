@@ -1,11 +1,33 @@
+pub mod arp;
+pub mod dhcp;
+pub mod icmp;
+pub mod reassembly;
 pub mod tcp;
-
-use alloc::vec::Vec;
-use tcp::TcpFrame;
-
-use core::convert::From;
-
-use crate::{util::bit_manipulation::GetBits, IpAddr};
+pub mod udp;
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use arp::{ArpTable, ArpTableEntry, ArpTimeout};
+use icmp::{Icmp, IcmpFrame, IcmpType, InvalidIcmpFrame, PingError};
+use reassembly::Reassembler;
+use tcp::{InvalidTcpFrame, Tcp, TcpFrame};
+use udp::{SendError, Udp, UdpDatagram};
+
+use core::{
+    convert::From,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU16, AtomicUsize, Ordering},
+    task::{Poll, Waker},
+};
+
+use crate::{
+    future::Either,
+    rng::Rng,
+    sleep::{self, WakeupRequester},
+    time::MonotonicTime,
+    util::{async_mutex::Mutex, bit_manipulation::GetBits, spinlock::SpinLock},
+    IpAddr, MacAddr,
+};
 
 #[derive(Copy, Clone)]
 #[repr(u16)]
@@ -347,7 +369,10 @@ impl core::fmt::Debug for ArpFrame<'_> {
 }
 
 #[derive(Debug)]
-pub struct InvalidIpv4Frame;
+pub enum InvalidIpv4Frame {
+    Length,
+    Checksum,
+}
 
 #[derive(Debug)]
 pub struct Ipv4Frame<'a> {
@@ -357,12 +382,18 @@ pub struct Ipv4Frame<'a> {
 impl<'a> Ipv4Frame<'a> {
     fn new(packet: &[u8]) -> Result<Ipv4Frame, InvalidIpv4Frame> {
         let frame = Ipv4Frame { packet };
+        const MIN_HEADER_LENGTH: usize = 20;
 
-        if packet.is_empty()
+        if packet.len() < MIN_HEADER_LENGTH
+            || frame.header_length() < MIN_HEADER_LENGTH
             || frame.header_length() > packet.len()
             || frame.total_length() > packet.len()
         {
-            return Err(InvalidIpv4Frame);
+            return Err(InvalidIpv4Frame::Length);
+        }
+
+        if calculate_ipv4_checksum(&packet[..frame.header_length()]) != 0 {
+            return Err(InvalidIpv4Frame::Checksum);
         }
 
         Ok(frame)
@@ -382,6 +413,7 @@ impl<'a> Ipv4Frame<'a> {
 
     fn protocol(&self) -> Ipv4Protocol {
         match self.packet[9] {
+            0x01 => Ipv4Protocol::Icmp,
             0x06 => Ipv4Protocol::Tcp,
             0x11 => Ipv4Protocol::Udp,
             v => Ipv4Protocol::Unknown(v),
@@ -399,66 +431,195 @@ impl<'a> Ipv4Frame<'a> {
             .expect("Invalid length for ipv4 source ip")
     }
 
+    pub fn dest_ip(&self) -> IpAddr {
+        self.packet[16..20]
+            .try_into()
+            .expect("Invalid length for ipv4 dest ip")
+    }
+
     fn header_length(&self) -> usize {
         (self.ihl() as usize) * 4
     }
-}
 
-fn calculate_ipv4_checksum(data: &[u8]) -> u16 {
-    assert_eq!(data.len() % 2, 0);
+    fn identification(&self) -> u16 {
+        u16::from_be_bytes(
+            self.packet[4..6]
+                .try_into()
+                .expect("Invalid length for ipv4 identification"),
+        )
+    }
 
-    let mut checksum = 0u16;
+    fn flags_and_offset(&self) -> u16 {
+        u16::from_be_bytes(
+            self.packet[6..8]
+                .try_into()
+                .expect("Invalid length for ipv4 flags"),
+        )
+    }
+
+    fn more_fragments(&self) -> bool {
+        self.flags_and_offset() & FLAG_MORE_FRAGMENTS != 0
+    }
+
+    /// Offset of this fragment's payload in the original datagram, in bytes
+    fn fragment_offset(&self) -> usize {
+        (self.flags_and_offset() & FRAGMENT_OFFSET_MASK) as usize * 8
+    }
+
+    fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+}
+
+fn ones_complement_sum(data: &[u8], initial: u16) -> u16 {
+    let mut sum = initial;
     for slice in data.chunks(2) {
-        // Ones compliment add, maybe should be factored out
-        let val = u16::from_be_bytes(slice.try_into().expect("Slice should be 2 bytes"));
+        // A trailing odd byte is treated as if it were padded with a zero
+        let val = match *slice {
+            [high, low] => u16::from_be_bytes([high, low]),
+            [high] => u16::from_be_bytes([high, 0]),
+            _ => unreachable!(),
+        };
+
         let overflow_res;
-        (checksum, overflow_res) = checksum.overflowing_add(val);
+        (sum, overflow_res) = sum.overflowing_add(val);
 
         if overflow_res {
-            checksum += 1;
+            sum += 1;
         }
     }
 
-    !checksum
+    sum
 }
 
-pub fn generate_ipv4_frame(
-    payload: &[u8],
-    protocol: Ipv4Protocol,
+fn calculate_ipv4_checksum(data: &[u8]) -> u16 {
+    !ones_complement_sum(data, 0)
+}
+
+/// Checksum used by udp and tcp, which also covers a pseudo header built from the addresses,
+/// protocol and length of the segment
+fn calculate_pseudo_header_checksum(
     source_ip: &IpAddr,
     dest_ip: &IpAddr,
-) -> Vec<u8> {
-    // FIXME: capacity?
-    let mut ret: Vec<u8> = Vec::new();
+    protocol: Ipv4Protocol,
+    data: &[u8],
+) -> u16 {
+    let mut pseudo_header = [0u8; 12];
+    pseudo_header[0..4].copy_from_slice(source_ip);
+    pseudo_header[4..8].copy_from_slice(dest_ip);
+    pseudo_header[9] = protocol.into();
+    pseudo_header[10..12].copy_from_slice(&(data.len() as u16).to_be_bytes());
+
+    let sum = ones_complement_sum(&pseudo_header, 0);
+    !ones_complement_sum(data, sum)
+}
+
+const IPV4_HEADER_SIZE: usize = 20;
+// Largest ipv4 packet we can put in a single ethernet frame
+const ETHERNET_MTU: usize = 1500;
+
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET_MASK: u16 = (1 << 13) - 1;
+
+pub struct Ipv4FrameParams<'a> {
+    pub payload: &'a [u8],
+    pub protocol: Ipv4Protocol,
+    pub source_ip: IpAddr,
+    pub dest_ip: IpAddr,
+    pub identification: u16,
+    pub more_fragments: bool,
+    // In bytes, has to be a multiple of 8
+    pub fragment_offset: usize,
+}
+
+pub fn generate_ipv4_frame(params: &Ipv4FrameParams<'_>) -> Vec<u8> {
+    assert_eq!(params.fragment_offset % 8, 0);
+
+    let mut ret: Vec<u8> = Vec::with_capacity(IPV4_HEADER_SIZE + params.payload.len());
 
-    const HEADER_SIZE: u16 = 20;
     // Version + IHL
     ret.push(0x45);
     // DSCP ECN
     ret.push(0x0);
-    // FIXME: usize -> u16 truncation
-    ret.extend_from_slice(&(HEADER_SIZE + payload.len() as u16).to_be_bytes());
-    // Identification
-    ret.extend_from_slice(&0u16.to_be_bytes());
-    // Flags + fragment offset
-    ret.extend_from_slice(&0u16.to_be_bytes());
+    let total_length: u16 = (IPV4_HEADER_SIZE + params.payload.len())
+        .try_into()
+        .expect("Ipv4 payload too large for a single frame");
+    ret.extend_from_slice(&total_length.to_be_bytes());
+    ret.extend_from_slice(&params.identification.to_be_bytes());
+
+    let mut flags_and_offset: u16 = (params.fragment_offset / 8)
+        .try_into()
+        .expect("Fragment offset too large");
+    assert_eq!(flags_and_offset & !FRAGMENT_OFFSET_MASK, 0);
+    if params.more_fragments {
+        flags_and_offset |= FLAG_MORE_FRAGMENTS;
+    }
+    ret.extend_from_slice(&flags_and_offset.to_be_bytes());
     // TTL (copied from wireshark incoming packet)
     ret.push(64);
-    ret.push(protocol.into());
+    ret.push(params.protocol.into());
 
     let checksum_loc = ret.len();
     ret.extend_from_slice(&0u16.to_be_bytes());
-    ret.extend_from_slice(source_ip);
-    ret.extend_from_slice(dest_ip);
+    ret.extend_from_slice(&params.source_ip);
+    ret.extend_from_slice(&params.dest_ip);
 
     let checksum = calculate_ipv4_checksum(&ret);
     ret[checksum_loc..checksum_loc + 2].copy_from_slice(&checksum.to_be_bytes());
 
-    ret.extend_from_slice(payload);
+    ret.extend_from_slice(params.payload);
 
     ret
 }
 
+#[derive(Debug)]
+pub struct Ipv4PayloadTooLarge(pub usize);
+
+/// Splits payload into as many ipv4 frames as needed to keep each one within mtu
+pub fn generate_ipv4_fragments(
+    payload: &[u8],
+    protocol: Ipv4Protocol,
+    source_ip: &IpAddr,
+    dest_ip: &IpAddr,
+    identification: u16,
+    mtu: usize,
+) -> Result<Vec<Vec<u8>>, Ipv4PayloadTooLarge> {
+    if payload.len() > u16::MAX as usize - IPV4_HEADER_SIZE {
+        return Err(Ipv4PayloadTooLarge(payload.len()));
+    }
+
+    // All but the last fragment have to be a multiple of 8 bytes
+    let max_fragment_size = (mtu - IPV4_HEADER_SIZE) / 8 * 8;
+    assert_ne!(max_fragment_size, 0);
+
+    let mut ret = Vec::new();
+    let mut fragment_offset = 0;
+    loop {
+        let end = (fragment_offset + max_fragment_size).min(payload.len());
+        let more_fragments = end < payload.len();
+
+        ret.push(generate_ipv4_frame(&Ipv4FrameParams {
+            payload: &payload[fragment_offset..end],
+            protocol,
+            source_ip: *source_ip,
+            dest_ip: *dest_ip,
+            identification,
+            more_fragments,
+            fragment_offset,
+        }));
+
+        if !more_fragments {
+            break;
+        }
+        fragment_offset = end;
+    }
+
+    Ok(ret)
+}
+
+// Most data a datagram can hold, its length has to fit in the ipv4 total length
+pub const MAX_UDP_PAYLOAD: usize = u16::MAX as usize - IPV4_HEADER_SIZE - UdpFrame::HEADER_LENGTH;
+
 #[derive(Debug)]
 pub struct InvalidUdpFrame(usize, usize);
 
@@ -473,13 +634,35 @@ impl UdpFrame<'_> {
     fn new(packet: &[u8]) -> Result<UdpFrame, InvalidUdpFrame> {
         let frame = UdpFrame { packet };
 
-        if packet.len() < Self::HEADER_LENGTH || packet.len() < frame.length() as usize {
-            return Err(InvalidUdpFrame(packet.len(), frame.length() as usize));
+        if packet.len() < Self::HEADER_LENGTH {
+            return Err(InvalidUdpFrame(packet.len(), Self::HEADER_LENGTH));
+        }
+
+        // The length covers the header too, so anything shorter can't be a datagram
+        let length = frame.length() as usize;
+        if length < Self::HEADER_LENGTH || packet.len() < length {
+            return Err(InvalidUdpFrame(packet.len(), length));
         }
 
         Ok(frame)
     }
 
+    pub fn source_port(&self) -> u16 {
+        u16::from_be_bytes(
+            self.packet[0..2]
+                .try_into()
+                .expect("u16 packet size incorrect"),
+        )
+    }
+
+    pub fn dest_port(&self) -> u16 {
+        u16::from_be_bytes(
+            self.packet[2..4]
+                .try_into()
+                .expect("u16 packet size incorrect"),
+        )
+    }
+
     fn length(&self) -> u16 {
         u16::from_be_bytes(
             self.packet[4..6]
@@ -488,22 +671,58 @@ impl UdpFrame<'_> {
         )
     }
 
+    fn checksum(&self) -> u16 {
+        u16::from_be_bytes(
+            self.packet[6..8]
+                .try_into()
+                .expect("u16 packet size incorrect"),
+        )
+    }
+
+    fn checksum_valid(&self, source_ip: &IpAddr, dest_ip: &IpAddr) -> bool {
+        // Sender didn't compute a checksum
+        if self.checksum() == 0 {
+            return true;
+        }
+
+        let datagram = &self.packet[..self.length() as usize];
+        calculate_pseudo_header_checksum(source_ip, dest_ip, Ipv4Protocol::Udp, datagram) == 0
+    }
+
     pub fn data(&self) -> &[u8] {
         &self.packet[Self::HEADER_LENGTH..self.length() as usize]
     }
 }
-pub fn generate_udp_frame(dest_port: u16, payload: &[u8]) -> Vec<u8> {
-    let length: u16 = UdpFrame::HEADER_LENGTH as u16 + payload.len() as u16;
+pub struct UdpFrameParams<'a> {
+    pub source_ip: IpAddr,
+    pub dest_ip: IpAddr,
+    pub source_port: u16,
+    pub dest_port: u16,
+    pub payload: &'a [u8],
+}
+
+pub fn generate_udp_frame(params: &UdpFrameParams<'_>) -> Vec<u8> {
+    let length: u16 = UdpFrame::HEADER_LENGTH as u16 + params.payload.len() as u16;
 
     let mut ret = Vec::with_capacity(length.into());
 
-    const SOURCE_PORT: &[u8] = &0u16.to_be_bytes();
-    ret.extend_from_slice(SOURCE_PORT);
-    ret.extend_from_slice(&dest_port.to_be_bytes());
+    ret.extend_from_slice(&params.source_port.to_be_bytes());
+    ret.extend_from_slice(&params.dest_port.to_be_bytes());
     ret.extend_from_slice(&length.to_be_bytes());
-    const CHECKSUM: &[u8] = &0u16.to_be_bytes();
-    ret.extend_from_slice(CHECKSUM);
-    ret.extend_from_slice(payload);
+    let checksum_idx = ret.len();
+    ret.extend_from_slice(&0u16.to_be_bytes());
+    ret.extend_from_slice(params.payload);
+
+    let checksum = calculate_pseudo_header_checksum(
+        &params.source_ip,
+        &params.dest_ip,
+        Ipv4Protocol::Udp,
+        &ret,
+    );
+    // A checksum of 0 means no checksum for udp, 0xffff is the same value in ones complement
+    let checksum = if checksum == 0 { 0xffff } else { checksum };
+    ret[checksum_idx..checksum_idx + 2].copy_from_slice(&checksum.to_be_bytes());
+
     ret
 }
 
@@ -547,9 +766,10 @@ pub fn parse_packet(data: &[u8]) -> Result<ParsedEthernetFrame, ParsePacketError
     })
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[repr(u8)]
 pub enum Ipv4Protocol {
+    Icmp,
     Tcp,
     Udp,
     Unknown(u8),
@@ -558,6 +778,7 @@ pub enum Ipv4Protocol {
 impl core::convert::From<Ipv4Protocol> for u8 {
     fn from(value: Ipv4Protocol) -> Self {
         match value {
+            Ipv4Protocol::Icmp => 0x01,
             Ipv4Protocol::Tcp => 0x06,
             Ipv4Protocol::Udp => 0x11,
             Ipv4Protocol::Unknown(v) => v,
@@ -565,30 +786,791 @@ impl core::convert::From<Ipv4Protocol> for u8 {
     }
 }
 
+#[derive(Debug)]
+pub enum ParseIpv4Error {
+    Udp(InvalidUdpFrame),
+    Icmp(InvalidIcmpFrame),
+    Tcp(InvalidTcpFrame),
+    Checksum(Ipv4Protocol),
+}
+
 pub enum ParsedIpv4Frame<'a> {
+    Icmp(IcmpFrame<'a>),
     Udp(UdpFrame<'a>),
     Tcp(TcpFrame<'a>),
     Unknown(Ipv4Protocol),
 }
 
-pub fn parse_ipv4<'a>(frame: &Ipv4Frame<'a>) -> Result<ParsedIpv4Frame<'a>, InvalidUdpFrame> {
+pub fn parse_ipv4<'a>(frame: &Ipv4Frame<'a>) -> Result<ParsedIpv4Frame<'a>, ParseIpv4Error> {
     debug!(
         "Parsing IPV4 packet with protocol {:#04x?}",
         frame.protocol()
     );
     let ret = match frame.protocol() {
-        Ipv4Protocol::Udp => ParsedIpv4Frame::Udp(UdpFrame::new(frame.payload())?),
-        Ipv4Protocol::Tcp => ParsedIpv4Frame::Tcp(TcpFrame::new(frame.payload())),
+        Ipv4Protocol::Icmp => {
+            ParsedIpv4Frame::Icmp(IcmpFrame::new(frame.payload()).map_err(ParseIpv4Error::Icmp)?)
+        }
+        Ipv4Protocol::Udp => {
+            let udp_frame = UdpFrame::new(frame.payload()).map_err(ParseIpv4Error::Udp)?;
+            if !udp_frame.checksum_valid(&frame.source_ip(), &frame.dest_ip()) {
+                return Err(ParseIpv4Error::Checksum(Ipv4Protocol::Udp));
+            }
+            ParsedIpv4Frame::Udp(udp_frame)
+        }
+        Ipv4Protocol::Tcp => {
+            let checksum = calculate_pseudo_header_checksum(
+                &frame.source_ip(),
+                &frame.dest_ip(),
+                Ipv4Protocol::Tcp,
+                frame.payload(),
+            );
+            if checksum != 0 {
+                return Err(ParseIpv4Error::Checksum(Ipv4Protocol::Tcp));
+            }
+            ParsedIpv4Frame::Tcp(TcpFrame::new(frame.payload()).map_err(ParseIpv4Error::Tcp)?)
+        }
         p => ParsedIpv4Frame::Unknown(p),
     };
     Ok(ret)
 }
 
+pub trait NetworkInterface: Send + Sync {
+    type Error: core::fmt::Debug;
+
+    fn mac(&self) -> MacAddr;
+
+    fn send<'a>(
+        &'a self,
+        frame: &'a [u8],
+    ) -> Pin<Box<dyn Future<Output = Result<(), Self::Error>> + Send + 'a>>;
+
+    fn recv(&self) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send + '_>>;
+}
+
+// Wait for the first arp reply, doubling every retry
+const ARP_REQUEST_TIMEOUT_S: f32 = 0.5;
+const ARP_MAX_REQUESTS: usize = 3;
+
+const BROADCAST_IP: IpAddr = [255; 4];
+const BROADCAST_MAC: MacAddr = [0xff; 6];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Ipv4Config {
+    pub ip: IpAddr,
+    pub netmask: IpAddr,
+    pub gateway: Option<IpAddr>,
+    pub dns_server: Option<IpAddr>,
+}
+
+impl Ipv4Config {
+    pub const UNCONFIGURED: Ipv4Config = Ipv4Config {
+        ip: [0; 4],
+        netmask: [0; 4],
+        gateway: None,
+        dns_server: None,
+    };
+
+    fn is_configured(&self) -> bool {
+        self.ip != [0; 4]
+    }
+
+    fn is_local(&self, ip: &IpAddr) -> bool {
+        (0..4).all(|i| ip[i] & self.netmask[i] == self.ip[i] & self.netmask[i])
+    }
+
+    fn is_broadcast(&self, ip: &IpAddr) -> bool {
+        *ip == BROADCAST_IP
+            || (self.is_local(ip) && (0..4).all(|i| ip[i] | self.netmask[i] == 0xff))
+    }
+}
+
+struct ConfigState {
+    config: Ipv4Config,
+    waiters: Vec<Waker>,
+}
+
+/// Counts of received packets dropped because of a bad checksum
+#[derive(Default)]
+pub struct ChecksumDrops {
+    pub ipv4: AtomicUsize,
+    pub udp: AtomicUsize,
+    pub tcp: AtomicUsize,
+}
+
+impl ChecksumDrops {
+    fn record(&self, counter: &AtomicUsize, protocol: &str) {
+        let total = counter.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!(
+            "Dropping {} packet with bad checksum, {} dropped so far",
+            protocol, total
+        );
+    }
+}
+
+pub struct NetStack<I> {
+    interface: I,
+    mac: MacAddr,
+    config: SpinLock<ConfigState>,
+    arp_table: ArpTable,
+    icmp: Icmp,
+    tcp: Tcp,
+    udp: Udp,
+    checksum_drops: ChecksumDrops,
+    // Tcp segments with a header that doesn't fit in the segment
+    malformed_tcp_drops: AtomicUsize,
+    reassembler: Reassembler,
+    next_ipv4_identification: AtomicU16,
+    rng: Mutex<Rng>,
+    time: Arc<MonotonicTime>,
+    wakeup_requester: WakeupRequester,
+}
+
+impl<I: NetworkInterface> NetStack<I> {
+    pub fn new(
+        interface: I,
+        config: Ipv4Config,
+        mut rng: Rng,
+        time: Arc<MonotonicTime>,
+        wakeup_requester: WakeupRequester,
+    ) -> NetStack<I> {
+        let mac = interface.mac();
+        let tcp = Tcp::new(
+            Arc::clone(&time),
+            wakeup_requester.clone(),
+            Rng::new(rng.u64()),
+        );
+        tcp.set_local_ip(config.ip);
+
+        NetStack {
+            interface,
+            mac,
+            config: SpinLock::new(ConfigState {
+                config,
+                waiters: Vec::new(),
+            }),
+            arp_table: ArpTable::new(Arc::clone(&time)),
+            icmp: Icmp::new(rng.u64() as u16),
+            tcp,
+            udp: Udp::new(),
+            checksum_drops: Default::default(),
+            malformed_tcp_drops: AtomicUsize::new(0),
+            reassembler: Reassembler::new(Arc::clone(&time)),
+            next_ipv4_identification: AtomicU16::new(rng.u64() as u16),
+            rng: Mutex::new(rng),
+            time,
+            wakeup_requester,
+        }
+    }
+
+    pub fn interface(&self) -> &I {
+        &self.interface
+    }
+
+    pub fn config(&self) -> Ipv4Config {
+        self.config.lock().config
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.config().ip
+    }
+
+    pub async fn set_config(&self, config: Ipv4Config) {
+        info!("Using ipv4 config: {:?}", config);
+        let waiters = {
+            let mut state = self.config.lock();
+            state.config = config;
+            core::mem::take(&mut state.waiters)
+        };
+        self.tcp.set_local_ip(config.ip);
+
+        if config.is_configured() {
+            for waker in waiters {
+                waker.wake();
+            }
+            self.announce().await;
+        }
+    }
+
+    pub async fn wait_for_config(&self) -> Ipv4Config {
+        crate::future::poll_fn(|cx| {
+            let mut state = self.config.lock();
+            if state.config.is_configured() {
+                return Poll::Ready(state.config);
+            }
+
+            if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                state.waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Configures the interface through dhcp, keeping the lease alive for as long as it runs.
+    /// Uses fallback while no dhcp server answers
+    pub async fn run_dhcp(&self, fallback: Ipv4Config) -> Result<(), udp::PortInUse> {
+        dhcp::run(self, fallback).await
+    }
+
+    pub fn tcp(&self) -> &Tcp {
+        &self.tcp
+    }
+
+    /// Waits time_s on the stack's clock, for timeouts in protocols on top of it
+    pub async fn sleep(&self, time_s: f32) {
+        sleep::sleep(time_s, &self.time, &self.wakeup_requester).await
+    }
+
+    pub async fn arp_entries(&self) -> Vec<ArpTableEntry> {
+        self.arp_table.entries().await
+    }
+
+    pub async fn resolve_mac(&self, ip: &IpAddr) -> Result<MacAddr, ArpTimeout> {
+        if self.config().is_broadcast(ip) {
+            return Ok(BROADCAST_MAC);
+        }
+
+        if let Some(mac) = self.arp_table.get(ip).await {
+            return Ok(mac);
+        }
+
+        let mut timeout_s = ARP_REQUEST_TIMEOUT_S;
+        for _ in 0..ARP_MAX_REQUESTS {
+            self.send_arp_request(ip).await;
+
+            let sleep_fut = sleep::sleep(timeout_s, &self.time, &self.wakeup_requester);
+            let sleep_fut = core::pin::pin!(sleep_fut);
+            let arp_lookup = core::pin::pin!(self.arp_table.wait_for(ip));
+
+            if let Either::Left((mac, _)) = crate::future::select(arp_lookup, sleep_fut).await {
+                return Ok(mac);
+            }
+
+            debug!("No arp reply for {:?} after {}s", ip, timeout_s);
+            timeout_s *= 2.0;
+        }
+
+        Err(ArpTimeout(*ip))
+    }
+
+    pub async fn send_ipv4(
+        &self,
+        payload: &[u8],
+        protocol: Ipv4Protocol,
+        dest_ip: &IpAddr,
+    ) -> Result<(), ArpTimeout> {
+        self.send_ipv4_from(payload, protocol, &self.ip(), dest_ip)
+            .await
+    }
+
+    /// Sends an echo request to ip, returning the round trip time in seconds. timeout_s starts
+    /// counting once the request has been sent
+    pub async fn ping(&self, ip: &IpAddr, timeout_s: f32) -> Result<f32, PingError> {
+        let (request, sequence_number, reply) = self.icmp.register_echo().await;
+
+        if let Err(e) = self.send_ipv4(&request, Ipv4Protocol::Icmp, ip).await {
+            self.icmp.cancel_echo(sequence_number).await;
+            return Err(PingError::Arp(e));
+        }
+        let start = self.time.get();
+
+        let sleep_fut = sleep::sleep(timeout_s, &self.time, &self.wakeup_requester);
+        let sleep_fut = core::pin::pin!(sleep_fut);
+        let reply = core::pin::pin!(reply.recv());
+
+        match crate::future::select(reply, sleep_fut).await {
+            Either::Left((Ok(tick), _)) => {
+                Ok(tick.saturating_sub(start) as f32 / self.time.tick_freq())
+            }
+            _ => {
+                self.icmp.cancel_echo(sequence_number).await;
+                Err(PingError::Timeout)
+            }
+        }
+    }
+
+    /// Gratuitous arp so that anyone with a stale mapping for our ip updates it
+    pub async fn announce(&self) {
+        self.send_arp_request(&self.ip()).await;
+    }
+
+    pub async fn service(&self) {
+        if self.config().is_configured() {
+            self.announce().await;
+        }
+
+        let recv = core::pin::pin!(self.recv_loop());
+        let tcp = core::pin::pin!(self.tcp_service());
+        crate::future::select(recv, tcp).await;
+    }
+
+    async fn recv_loop(&self) {
+        loop {
+            debug!("Waiting for a packet");
+            let packet = self.interface.recv().await;
+            self.handle_packet(&packet).await;
+        }
+    }
+
+    async fn tcp_service(&self) {
+        loop {
+            let outgoing_data = self.tcp.service().await;
+            let res = self
+                .send_ipv4_from(
+                    &outgoing_data.payload,
+                    Ipv4Protocol::Tcp,
+                    &outgoing_data.local_ip,
+                    &outgoing_data.remote_ip,
+                )
+                .await;
+
+            if let Err(e) = res {
+                warn!("Dropping outgoing tcp packet: {:?}", e);
+            }
+        }
+    }
+
+    async fn send_arp_request(&self, remote_ip: &IpAddr) {
+        let arp_frame = generate_arp_request(remote_ip, &self.ip(), &self.mac);
+        self.write_frame(&EthernetFrameParams {
+            dest_mac: BROADCAST_MAC,
+            source_mac: self.mac,
+            ether_type: EtherType::Arp,
+            payload: &arp_frame,
+        })
+        .await;
+    }
+
+    async fn send_ipv4_from(
+        &self,
+        payload: &[u8],
+        protocol: Ipv4Protocol,
+        source_ip: &IpAddr,
+        dest_ip: &IpAddr,
+    ) -> Result<(), ArpTimeout> {
+        let config = self.config();
+        let next_hop = match config.gateway {
+            Some(gateway) if !config.is_local(dest_ip) && *dest_ip != BROADCAST_IP => gateway,
+            _ => *dest_ip,
+        };
+
+        let dest_mac = self.resolve_mac(&next_hop).await?;
+        self.write_ipv4(payload, protocol, source_ip, dest_ip, dest_mac)
+            .await;
+        Ok(())
+    }
+
+    async fn send_udp_from(
+        &self,
+        source_ip: &IpAddr,
+        source_port: u16,
+        dest_ip: &IpAddr,
+        dest_port: u16,
+        payload: &[u8],
+    ) -> Result<(), SendError> {
+        if payload.len() > MAX_UDP_PAYLOAD {
+            return Err(SendError::TooLarge(payload.len()));
+        }
+
+        let udp_frame = generate_udp_frame(&UdpFrameParams {
+            source_ip: *source_ip,
+            dest_ip: *dest_ip,
+            source_port,
+            dest_port,
+            payload,
+        });
+        self.send_ipv4_from(&udp_frame, Ipv4Protocol::Udp, source_ip, dest_ip)
+            .await?;
+        Ok(())
+    }
+
+    async fn write_frame(&self, params: &EthernetFrameParams<'_>) {
+        let frame = generate_ethernet_frame(params);
+        if let Err(e) = self.interface.send(&frame).await {
+            error!("Failed to send frame: {:?}", e);
+        }
+    }
+
+    async fn write_ipv4(
+        &self,
+        payload: &[u8],
+        protocol: Ipv4Protocol,
+        source_ip: &IpAddr,
+        dest_ip: &IpAddr,
+        dest_mac: MacAddr,
+    ) {
+        let identification = self
+            .next_ipv4_identification
+            .fetch_add(1, Ordering::Relaxed);
+        let fragments = generate_ipv4_fragments(
+            payload,
+            protocol,
+            source_ip,
+            dest_ip,
+            identification,
+            ETHERNET_MTU,
+        );
+
+        let fragments = match fragments {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to send ipv4 packet: {:?}", e);
+                return;
+            }
+        };
+
+        for fragment in fragments {
+            self.write_frame(&EthernetFrameParams {
+                dest_mac,
+                source_mac: self.mac,
+                ether_type: EtherType::Ipv4,
+                payload: &fragment,
+            })
+            .await;
+        }
+    }
+
+    async fn handle_packet(&self, packet: &[u8]) {
+        let packet = match parse_packet(packet) {
+            Ok(v) => v,
+            Err(ParsePacketError::Ipv4(InvalidIpv4Frame::Checksum)) => {
+                let drops = &self.checksum_drops;
+                drops.record(&drops.ipv4, "ipv4");
+                return;
+            }
+            Err(e) => {
+                debug!("Received invalid packet: {:?}", e);
+                return;
+            }
+        };
+
+        match packet.inner {
+            ParsedPacket::Arp(arp_frame) => {
+                self.handle_arp_frame(&arp_frame).await;
+            }
+            ParsedPacket::Ipv4(ipv4_frame) => {
+                debug!("Received IPV4 frame");
+                let source_mac = packet
+                    .ethernet
+                    .source_mac()
+                    .try_into()
+                    .expect("invalid source mac length");
+                self.handle_ipv4_frame(&ipv4_frame, source_mac).await;
+            }
+            ParsedPacket::Unknown(t) => {
+                debug!("Found unknown packet type: {:#06x}", t);
+            }
+        }
+    }
+
+    async fn handle_arp_frame(&self, arp_frame: &ArpFrame<'_>) {
+        debug!("Received arp frame: {:?}", arp_frame);
+
+        let sender_mac: MacAddr = arp_frame
+            .sender_hardware_address()
+            .try_into()
+            .expect("Arp mac address not the right size");
+        let sender_ip: IpAddr = arp_frame
+            .sender_protocol_address()
+            .try_into()
+            .expect("Arp ip address not the right size");
+
+        // Ignore our own announcements, and probes from hosts without an address
+        if sender_mac == self.mac || sender_ip == [0; 4] {
+            return;
+        }
+
+        let ip = self.ip();
+
+        match arp_frame.operation() {
+            Ok(ArpOperation::Request) => (),
+            Ok(ArpOperation::Reply) => {
+                self.arp_table.write_mac(&sender_ip, &sender_mac).await;
+                return;
+            }
+            Err(UnknownArpOperation(v)) => {
+                debug!("Received unknown arp operation, {}", v);
+                return;
+            }
+        }
+
+        if arp_frame.target_hardware_address() != self.mac
+            && arp_frame.target_protocol_address() != ip
+        {
+            // Not for us, but anyone we already know about (e.g. a gratuitous arp) may have
+            // moved
+            self.arp_table.refresh_mac(&sender_ip, &sender_mac).await;
+            return;
+        }
+
+        // Whoever is asking is about to talk to us, save the round trip later
+        self.arp_table.write_mac(&sender_ip, &sender_mac).await;
+
+        let mut params =
+            ArpFrameParams::try_from(arp_frame).expect("Arp frame should be validated above");
+
+        core::mem::swap(
+            &mut params.target_protocol_address,
+            &mut params.sender_protocol_address,
+        );
+        core::mem::swap(
+            &mut params.target_hardware_address,
+            &mut params.sender_hardware_address,
+        );
+        params.operation = ArpOperation::Reply;
+        params.sender_hardware_address = self.mac;
+        params.sender_protocol_address = ip;
+
+        let response = generate_arp_frame(&params);
+
+        self.write_frame(&EthernetFrameParams {
+            dest_mac: params.target_hardware_address,
+            source_mac: self.mac,
+            ether_type: EtherType::Arp,
+            payload: &response,
+        })
+        .await;
+    }
+
+    async fn handle_ipv4_frame(&self, ipv4_frame: &Ipv4Frame<'_>, source_mac: MacAddr) {
+        let config = self.config();
+        let dest_ip = ipv4_frame.dest_ip();
+        // Until we have an address, anything could be our dhcp offer
+        if config.is_configured() && dest_ip != config.ip && !config.is_broadcast(&dest_ip) {
+            debug!("Dropping ipv4 packet for {:?}", dest_ip);
+            return;
+        }
+
+        if !ipv4_frame.is_fragment() {
+            self.handle_ipv4_datagram(ipv4_frame, source_mac).await;
+            return;
+        }
+
+        let packet = match self.reassembler.add_fragment(ipv4_frame).await {
+            Some(v) => v,
+            None => return,
+        };
+
+        match Ipv4Frame::new(&packet) {
+            Ok(frame) => self.handle_ipv4_datagram(&frame, source_mac).await,
+            Err(e) => error!("Reassembled invalid ipv4 packet: {:?}", e),
+        }
+    }
+
+    async fn handle_ipv4_datagram(&self, ipv4_frame: &Ipv4Frame<'_>, source_mac: MacAddr) {
+        let dest_ip = ipv4_frame.dest_ip();
+
+        match parse_ipv4(ipv4_frame) {
+            Ok(ParsedIpv4Frame::Icmp(icmp_frame)) => {
+                self.handle_icmp_frame(ipv4_frame, &icmp_frame, source_mac)
+                    .await;
+            }
+            Ok(ParsedIpv4Frame::Udp(udp_frame)) => {
+                let datagram = UdpDatagram {
+                    source_ip: ipv4_frame.source_ip(),
+                    source_port: udp_frame.source_port(),
+                    data: udp_frame.data().to_vec(),
+                };
+
+                if !self
+                    .udp
+                    .handle_datagram(udp_frame.dest_port(), datagram)
+                    .await
+                {
+                    debug!(
+                        "No udp socket bound to port {} for datagram from {:?}:{}",
+                        udp_frame.dest_port(),
+                        ipv4_frame.source_ip(),
+                        udp_frame.source_port()
+                    );
+                    self.send_destination_unreachable(
+                        ipv4_frame,
+                        icmp::PORT_UNREACHABLE,
+                        source_mac,
+                    )
+                    .await;
+                }
+            }
+            Ok(ParsedIpv4Frame::Tcp(tcp_frame)) => {
+                let response = self
+                    .tcp
+                    .handle_frame(&tcp_frame, &ipv4_frame.source_ip(), &dest_ip)
+                    .await;
+
+                if let Some(response) = response {
+                    self.write_ipv4(
+                        &response,
+                        Ipv4Protocol::Tcp,
+                        &dest_ip,
+                        &ipv4_frame.source_ip(),
+                        source_mac,
+                    )
+                    .await;
+                }
+            }
+            Ok(ParsedIpv4Frame::Unknown(p)) => {
+                debug!("Unknown ipv4 protocol {:?}", p);
+                self.send_destination_unreachable(
+                    ipv4_frame,
+                    icmp::PROTOCOL_UNREACHABLE,
+                    source_mac,
+                )
+                .await;
+            }
+            Err(ParseIpv4Error::Checksum(Ipv4Protocol::Udp)) => {
+                let drops = &self.checksum_drops;
+                drops.record(&drops.udp, "udp");
+            }
+            Err(ParseIpv4Error::Checksum(Ipv4Protocol::Tcp)) => {
+                let drops = &self.checksum_drops;
+                drops.record(&drops.tcp, "tcp");
+            }
+            Err(ParseIpv4Error::Tcp(e)) => {
+                let total = self.malformed_tcp_drops.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!(
+                    "Dropping malformed tcp segment {:?}, {} dropped so far",
+                    e, total
+                );
+            }
+            Err(e) => {
+                debug!("Invalid ipv4 packet: {:?}", e);
+            }
+        }
+    }
+
+    async fn handle_icmp_frame(
+        &self,
+        ipv4_frame: &Ipv4Frame<'_>,
+        icmp_frame: &IcmpFrame<'_>,
+        source_mac: MacAddr,
+    ) {
+        match icmp_frame.icmp_type() {
+            IcmpType::EchoRequest => {
+                let dest_ip = ipv4_frame.dest_ip();
+                if dest_ip != self.ip() {
+                    debug!("Ignoring broadcast echo request");
+                    return;
+                }
+
+                let reply = icmp::generate_echo(
+                    IcmpType::EchoReply,
+                    icmp_frame.identifier(),
+                    icmp_frame.sequence_number(),
+                    icmp_frame.payload(),
+                );
+                self.write_ipv4(
+                    &reply,
+                    Ipv4Protocol::Icmp,
+                    &dest_ip,
+                    &ipv4_frame.source_ip(),
+                    source_mac,
+                )
+                .await;
+            }
+            IcmpType::EchoReply => {
+                self.icmp
+                    .handle_echo_reply(icmp_frame, self.time.get())
+                    .await;
+            }
+            IcmpType::DestinationUnreachable => {
+                debug!(
+                    "Destination unreachable (code {}) from {:?}",
+                    icmp_frame.code(),
+                    ipv4_frame.source_ip()
+                );
+            }
+            IcmpType::Unknown(t) => {
+                debug!("Unhandled icmp type {}", t);
+            }
+        }
+    }
+
+    async fn send_destination_unreachable(
+        &self,
+        ipv4_frame: &Ipv4Frame<'_>,
+        code: u8,
+        source_mac: MacAddr,
+    ) {
+        // Errors are only for packets addressed directly to us, never for broadcasts
+        let config = self.config();
+        let source_ip = ipv4_frame.source_ip();
+        if !config.is_configured() || ipv4_frame.dest_ip() != config.ip || source_ip == [0; 4] {
+            return;
+        }
+
+        let message = icmp::generate_destination_unreachable(
+            code,
+            &ipv4_frame.packet[..ipv4_frame.total_length()],
+            ipv4_frame.header_length(),
+        );
+        self.write_ipv4(
+            &message,
+            Ipv4Protocol::Icmp,
+            &config.ip,
+            &source_ip,
+            source_mac,
+        )
+        .await;
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::testing::*;
-    use alloc::string::ToString;
+    use alloc::{
+        format,
+        string::{String, ToString},
+        vec,
+    };
+
+    struct MockInterface {
+        mac: MacAddr,
+        sent: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl NetworkInterface for MockInterface {
+        type Error = ();
+
+        fn mac(&self) -> MacAddr {
+            self.mac
+        }
+
+        fn send<'a>(
+            &'a self,
+            frame: &'a [u8],
+        ) -> Pin<Box<dyn Future<Output = Result<(), ()>> + Send + 'a>> {
+            Box::pin(async move {
+                self.sent.lock().await.push(frame.to_vec());
+                Ok(())
+            })
+        }
+
+        fn recv(&self) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send + '_>> {
+            Box::pin(core::future::pending())
+        }
+    }
+
+    const MOCK_MAC: MacAddr = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+    const MOCK_IP: IpAddr = [192, 168, 2, 2];
+    const REMOTE_MAC: MacAddr = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+    const REMOTE_IP: IpAddr = [192, 168, 2, 1];
+
+    fn gen_net_stack() -> NetStack<MockInterface> {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let interface = MockInterface {
+            mac: MOCK_MAC,
+            sent: Mutex::new(Vec::new()),
+        };
+        let config = Ipv4Config {
+            ip: MOCK_IP,
+            netmask: [255, 255, 255, 0],
+            gateway: None,
+            dns_server: None,
+        };
+        NetStack::new(interface, config, Rng::new(0), time, wakeup_list)
+    }
 
     const ARP_REQUEST: &[u8] = &[
         0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02, 0x08, 0x06, 0x00,
@@ -718,6 +1700,11 @@ mod test {
         let ipv4_frame = Ipv4Frame::new(&[0xff]);
         test_err!(ipv4_frame);
 
+        let mut corrupted = frame.payload().to_vec();
+        corrupted[8] -= 1;
+        let ipv4_frame = Ipv4Frame::new(&corrupted);
+        test_true!(matches!(ipv4_frame, Err(InvalidIpv4Frame::Checksum)));
+
         Ok(())
     });
 
@@ -746,14 +1733,26 @@ mod test {
         let udp_frame = UdpFrame::new(&payload);
         test_err!(udp_frame);
 
-        payload[4..6].copy_from_slice(&4u16.to_be_bytes());
+        payload[4..6].copy_from_slice(&8u16.to_be_bytes());
         let udp_frame = UdpFrame::new(&payload);
         test_ok!(udp_frame);
 
+        // Length shorter than the header, with no checksum to catch it
+        for length in [0u16, 4, 7] {
+            payload[4..6].copy_from_slice(&length.to_be_bytes());
+            payload[6..8].copy_from_slice(&0u16.to_be_bytes());
+            let udp_frame = UdpFrame::new(&payload);
+            test_err!(udp_frame);
+        }
+
         payload.resize(7, 0);
         let udp_frame = UdpFrame::new(&payload);
         test_err!(udp_frame);
 
+        payload.resize(3, 0);
+        let udp_frame = UdpFrame::new(&payload);
+        test_err!(udp_frame);
+
         Ok(())
     });
 
@@ -763,9 +1762,463 @@ mod test {
         let frame =
             Ipv4Frame::new(frame.payload()).map_err(|_| "Invalid ipv4 frame".to_string())?;
         let frame = UdpFrame::new(frame.payload()).map_err(|_| "Invalid UDP frame".to_string())?;
+        test_eq!(frame.source_port(), 38430);
+        test_eq!(frame.dest_port(), 6000);
         test_eq!(frame.length(), 13);
         test_eq!(frame.data(), b"test\n");
 
         Ok(())
     });
+
+    create_test!(test_checksum_odd_length, {
+        test_eq!(calculate_ipv4_checksum(&[0x12, 0x34, 0x56]), 0x97cb);
+        test_eq!(
+            calculate_ipv4_checksum(&[0x12, 0x34, 0x56]),
+            calculate_ipv4_checksum(&[0x12, 0x34, 0x56, 0x00])
+        );
+        Ok(())
+    });
+
+    create_test!(test_udp_checksum, {
+        let frame =
+            EthernetFrame::new(UDP_REQUEST).map_err(|_| "Invalid ethernet frame".to_string())?;
+        let frame =
+            Ipv4Frame::new(frame.payload()).map_err(|_| "Invalid ipv4 frame".to_string())?;
+        let udp_frame =
+            UdpFrame::new(frame.payload()).map_err(|_| "Invalid UDP frame".to_string())?;
+        test_true!(udp_frame.checksum_valid(&frame.source_ip(), &frame.dest_ip()));
+        test_false!(udp_frame.checksum_valid(&frame.dest_ip(), &frame.dest_ip()));
+
+        let generated = generate_udp_frame(&UdpFrameParams {
+            source_ip: REMOTE_IP,
+            dest_ip: MOCK_IP,
+            source_port: 1234,
+            dest_port: 4321,
+            payload: b"odd",
+        });
+        let udp_frame = UdpFrame::new(&generated).map_err(|_| "Invalid UDP frame".to_string())?;
+        test_ne!(udp_frame.checksum(), 0);
+        test_true!(udp_frame.checksum_valid(&REMOTE_IP, &MOCK_IP));
+
+        let mut corrupted = generated.clone();
+        corrupted[9] ^= 0x01;
+        let udp_frame = UdpFrame::new(&corrupted).map_err(|_| "Invalid UDP frame".to_string())?;
+        test_false!(udp_frame.checksum_valid(&REMOTE_IP, &MOCK_IP));
+
+        // Zero means the sender didn't bother
+        corrupted[6..8].copy_from_slice(&[0, 0]);
+        let udp_frame = UdpFrame::new(&corrupted).map_err(|_| "Invalid UDP frame".to_string())?;
+        test_true!(udp_frame.checksum_valid(&REMOTE_IP, &MOCK_IP));
+
+        Ok(())
+    });
+
+    create_test!(test_net_stack_arp_reply, {
+        let net_stack = gen_net_stack();
+
+        let request = generate_arp_request(&MOCK_IP, &REMOTE_IP, &REMOTE_MAC);
+        let request = generate_ethernet_frame(&EthernetFrameParams {
+            dest_mac: [0xff; 6],
+            source_mac: REMOTE_MAC,
+            ether_type: EtherType::Arp,
+            payload: &request,
+        });
+
+        net_stack.handle_packet(&request).await;
+
+        let sent = net_stack.interface().sent.lock().await;
+        test_eq!(sent.len(), 1);
+
+        let reply = parse_packet(&sent[0]).map_err(|_| "Invalid reply".to_string())?;
+        test_eq!(reply.ethernet.destination_mac(), &REMOTE_MAC);
+        let reply = match reply.inner {
+            ParsedPacket::Arp(v) => v,
+            _ => return Err("Reply was not an arp frame".into()),
+        };
+
+        test_eq!(
+            reply.operation(),
+            Ok::<_, UnknownArpOperation>(ArpOperation::Reply)
+        );
+        test_eq!(reply.sender_hardware_address(), &MOCK_MAC);
+        test_eq!(reply.sender_protocol_address(), &MOCK_IP);
+        test_eq!(reply.target_hardware_address(), &REMOTE_MAC);
+        test_eq!(reply.target_protocol_address(), &REMOTE_IP);
+
+        // The requester should have been learned from the request
+        test_eq!(net_stack.arp_table.get(&REMOTE_IP).await, Some(REMOTE_MAC));
+
+        Ok(())
+    });
+
+    fn gen_remote_ipv4_packet(payload: &[u8], protocol: Ipv4Protocol) -> Vec<u8> {
+        let ipv4_frame = generate_ipv4_frame(&Ipv4FrameParams {
+            payload,
+            protocol,
+            source_ip: REMOTE_IP,
+            dest_ip: MOCK_IP,
+            identification: 0,
+            more_fragments: false,
+            fragment_offset: 0,
+        });
+        let mut packet = generate_ethernet_frame(&EthernetFrameParams {
+            dest_mac: MOCK_MAC,
+            source_mac: REMOTE_MAC,
+            ether_type: EtherType::Ipv4,
+            payload: &ipv4_frame,
+        });
+        // Incoming frames have a CRC that we don't generate
+        packet.extend_from_slice(&[0; 4]);
+        packet
+    }
+
+    fn parse_sent_icmp(sent: &[u8]) -> Result<(IcmpType, u8, Vec<u8>), String> {
+        let packet = parse_packet(sent).map_err(|_| "Invalid packet".to_string())?;
+        if packet.ethernet.destination_mac() != REMOTE_MAC {
+            return Err("Icmp sent to wrong mac".into());
+        }
+
+        let ipv4_frame = match packet.inner {
+            ParsedPacket::Ipv4(v) => v,
+            _ => return Err("Sent packet was not ipv4".into()),
+        };
+
+        if ipv4_frame.dest_ip() != REMOTE_IP {
+            return Err("Icmp sent to wrong ip".into());
+        }
+
+        match parse_ipv4(&ipv4_frame) {
+            Ok(ParsedIpv4Frame::Icmp(v)) => Ok((v.icmp_type(), v.code(), v.payload().to_vec())),
+            _ => Err("Sent packet was not icmp".into()),
+        }
+    }
+
+    create_test!(test_net_stack_echo_reply, {
+        let net_stack = gen_net_stack();
+
+        let request = icmp::generate_echo(IcmpType::EchoRequest, 1, 2, b"ping data");
+        let request = gen_remote_ipv4_packet(&request, Ipv4Protocol::Icmp);
+        net_stack.handle_packet(&request).await;
+
+        let sent = net_stack.interface().sent.lock().await;
+        test_eq!(sent.len(), 1);
+        let (icmp_type, code, payload) = parse_sent_icmp(&sent[0])?;
+        test_eq!(icmp_type, IcmpType::EchoReply);
+        test_eq!(code, 0);
+        // identifier + sequence number are at the end of the header, payload follows
+        test_eq!(payload, b"ping data");
+
+        Ok(())
+    });
+
+    create_test!(test_net_stack_destination_unreachable, {
+        let net_stack = gen_net_stack();
+
+        let udp_frame = generate_udp_frame(&UdpFrameParams {
+            source_ip: REMOTE_IP,
+            dest_ip: MOCK_IP,
+            source_port: 1234,
+            dest_port: 4321,
+            payload: b"anyone there?",
+        });
+        let request = gen_remote_ipv4_packet(&udp_frame, Ipv4Protocol::Udp);
+        net_stack.handle_packet(&request).await;
+
+        let request = gen_remote_ipv4_packet(b"???", Ipv4Protocol::Unknown(0xfd));
+        net_stack.handle_packet(&request).await;
+
+        let sent = net_stack.interface().sent.lock().await;
+        test_eq!(sent.len(), 2);
+
+        let (icmp_type, code, payload) = parse_sent_icmp(&sent[0])?;
+        test_eq!(icmp_type, IcmpType::DestinationUnreachable);
+        test_eq!(code, icmp::PORT_UNREACHABLE);
+        // Original ip header + the udp header
+        test_eq!(payload.len(), 28);
+        test_eq!(&payload[20..28], &udp_frame[0..8]);
+
+        let (icmp_type, code, _) = parse_sent_icmp(&sent[1])?;
+        test_eq!(icmp_type, IcmpType::DestinationUnreachable);
+        test_eq!(code, icmp::PROTOCOL_UNREACHABLE);
+
+        Ok(())
+    });
+
+    create_test!(test_net_stack_udp_socket, {
+        let net_stack = gen_net_stack();
+        net_stack.arp_table.write_mac(&REMOTE_IP, &REMOTE_MAC).await;
+
+        let socket = udp::UdpSocket::bind(&net_stack, 4321).map_err(|e| format!("{:?}", e))?;
+
+        let request = generate_udp_frame(&UdpFrameParams {
+            source_ip: REMOTE_IP,
+            dest_ip: MOCK_IP,
+            source_port: 1234,
+            dest_port: 4321,
+            payload: b"hello socket",
+        });
+        net_stack
+            .handle_packet(&gen_remote_ipv4_packet(&request, Ipv4Protocol::Udp))
+            .await;
+
+        let datagram = socket.recv_from().await;
+        test_eq!(datagram.source_ip, REMOTE_IP);
+        test_eq!(datagram.source_port, 1234);
+        test_eq!(datagram.data, b"hello socket");
+
+        socket
+            .send_to(b"hello back", &REMOTE_IP, 1234)
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+
+        {
+            let sent = net_stack.interface().sent.lock().await;
+            test_eq!(sent.len(), 1);
+            let packet = parse_packet(&sent[0]).map_err(|_| "Invalid packet".to_string())?;
+            let ipv4_frame = match packet.inner {
+                ParsedPacket::Ipv4(v) => v,
+                _ => return Err("Sent packet was not ipv4".into()),
+            };
+            let udp_frame = match parse_ipv4(&ipv4_frame) {
+                Ok(ParsedIpv4Frame::Udp(v)) => v,
+                _ => return Err("Sent packet was not udp".into()),
+            };
+            test_eq!(udp_frame.source_port(), 4321);
+            test_eq!(udp_frame.dest_port(), 1234);
+            test_eq!(udp_frame.data(), b"hello back");
+        }
+
+        // Too much for the length field, nothing is sent
+        let oversized = alloc::vec![0; MAX_UDP_PAYLOAD + 1];
+        let res = socket.send_to(&oversized, &REMOTE_IP, 1234).await;
+        test_true!(matches!(res, Err(udp::SendError::TooLarge(_))));
+        test_eq!(net_stack.interface().sent.lock().await.len(), 1);
+
+        // Once the socket is gone the sender should be told nobody is listening
+        drop(socket);
+        net_stack
+            .handle_packet(&gen_remote_ipv4_packet(&request, Ipv4Protocol::Udp))
+            .await;
+        let sent = net_stack.interface().sent.lock().await;
+        test_eq!(sent.len(), 2);
+        let (icmp_type, code, _) = parse_sent_icmp(&sent[1])?;
+        test_eq!(icmp_type, IcmpType::DestinationUnreachable);
+        test_eq!(code, icmp::PORT_UNREACHABLE);
+
+        Ok(())
+    });
+
+    create_test!(test_net_stack_checksum_drops, {
+        let net_stack = gen_net_stack();
+
+        let udp_frame = generate_udp_frame(&UdpFrameParams {
+            source_ip: REMOTE_IP,
+            dest_ip: MOCK_IP,
+            source_port: 1234,
+            dest_port: 4321,
+            payload: b"corrupt me",
+        });
+        let mut packet = gen_remote_ipv4_packet(&udp_frame, Ipv4Protocol::Udp);
+        // Last byte of the udp payload
+        let payload_end = 14 + 20 + udp_frame.len() - 1;
+        packet[payload_end] ^= 0xff;
+        net_stack.handle_packet(&packet).await;
+
+        // Ipv4 header checksum
+        let mut packet = gen_remote_ipv4_packet(&udp_frame, Ipv4Protocol::Udp);
+        packet[14 + 10] ^= 0xff;
+        net_stack.handle_packet(&packet).await;
+
+        let tcp_frame = tcp::generate_tcp_frame(&tcp::TcpFrameParams {
+            source_address: REMOTE_IP,
+            dest_address: MOCK_IP,
+            source_port: 1234,
+            dest_port: 80,
+            seq_num: 0,
+            ack_num: 0,
+            flags: tcp::TcpFlags(0),
+            window_size: 0,
+            urgent_ptr: 0,
+            options: tcp::options::TcpOptions::default(),
+            payload: Arc::new([1, 2, 3]),
+        });
+        let mut packet = gen_remote_ipv4_packet(&tcp_frame, Ipv4Protocol::Tcp);
+        packet[14 + 20 + 4] ^= 0xff;
+        net_stack.handle_packet(&packet).await;
+
+        let drops = &net_stack.checksum_drops;
+        test_eq!(drops.ipv4.load(Ordering::Relaxed), 1);
+        test_eq!(drops.udp.load(Ordering::Relaxed), 1);
+        test_eq!(drops.tcp.load(Ordering::Relaxed), 1);
+
+        // Corrupted packets shouldn't be answered, not even with port unreachable
+        test_eq!(net_stack.interface().sent.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_net_stack_malformed_tcp, {
+        let net_stack = gen_net_stack();
+
+        let with_checksum = |mut segment: Vec<u8>, checksum_idx: usize| {
+            let checksum =
+                calculate_pseudo_header_checksum(&REMOTE_IP, &MOCK_IP, Ipv4Protocol::Tcp, &segment);
+            segment[checksum_idx..checksum_idx + 2].copy_from_slice(&checksum.to_be_bytes());
+            segment
+        };
+
+        // Too short for a header, the last two bytes make the checksum work out
+        let short = with_checksum(vec![0x12, 0x34, 0x00, 0x50, 0, 0, 0, 0, 0, 0, 0, 0], 10);
+
+        let segment = tcp::generate_tcp_frame(&tcp::TcpFrameParams {
+            source_address: REMOTE_IP,
+            dest_address: MOCK_IP,
+            source_port: 1234,
+            dest_port: 80,
+            seq_num: 0,
+            ack_num: 0,
+            flags: tcp::TcpFlags(0),
+            window_size: 0,
+            urgent_ptr: 0,
+            options: tcp::options::TcpOptions::default(),
+            payload: Arc::new([1, 2, 3]),
+        });
+        let mut zero_offset = segment.clone();
+        zero_offset[12] = 0;
+        zero_offset[16..18].fill(0);
+        let zero_offset = with_checksum(zero_offset, 16);
+
+        let mut long_offset = segment;
+        long_offset[12] = 0xf0;
+        long_offset[16..18].fill(0);
+        let long_offset = with_checksum(long_offset, 16);
+
+        for segment in [short, zero_offset, long_offset] {
+            let packet = gen_remote_ipv4_packet(&segment, Ipv4Protocol::Tcp);
+            net_stack.handle_packet(&packet).await;
+        }
+
+        test_eq!(net_stack.malformed_tcp_drops.load(Ordering::Relaxed), 3);
+        test_eq!(net_stack.checksum_drops.tcp.load(Ordering::Relaxed), 0);
+        // Not even a reset goes back
+        test_eq!(net_stack.interface().sent.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_net_stack_fragmentation, {
+        let net_stack = gen_net_stack();
+        net_stack.arp_table.write_mac(&REMOTE_IP, &REMOTE_MAC).await;
+
+        let socket = udp::UdpSocket::bind(&net_stack, 4321).map_err(|e| format!("{:?}", e))?;
+        let payload: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+
+        for _ in 0..2 {
+            socket
+                .send_to(&payload, &REMOTE_IP, 1234)
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+        }
+
+        {
+            let sent = net_stack.interface().sent.lock().await;
+            // 4008 bytes of udp in 1480 byte chunks, twice
+            test_eq!(sent.len(), 6);
+
+            let mut identifications = Vec::new();
+            for frame in sent.iter() {
+                test_true!(frame.len() <= 14 + ETHERNET_MTU);
+                let packet = parse_packet(frame).map_err(|_| "Invalid packet".to_string())?;
+                let ipv4_frame = match packet.inner {
+                    ParsedPacket::Ipv4(v) => v,
+                    _ => return Err("Sent packet was not ipv4".into()),
+                };
+                test_true!(ipv4_frame.is_fragment());
+                identifications.push(ipv4_frame.identification());
+            }
+
+            test_eq!(identifications[0], identifications[2]);
+            test_ne!(identifications[0], identifications[3]);
+        }
+
+        let udp_frame = generate_udp_frame(&UdpFrameParams {
+            source_ip: REMOTE_IP,
+            dest_ip: MOCK_IP,
+            source_port: 1234,
+            dest_port: 4321,
+            payload: &payload,
+        });
+        let fragments = generate_ipv4_fragments(
+            &udp_frame,
+            Ipv4Protocol::Udp,
+            &REMOTE_IP,
+            &MOCK_IP,
+            1,
+            ETHERNET_MTU,
+        )
+        .map_err(|e| format!("{:?}", e))?;
+
+        for fragment in fragments.iter().rev() {
+            let mut packet = generate_ethernet_frame(&EthernetFrameParams {
+                dest_mac: MOCK_MAC,
+                source_mac: REMOTE_MAC,
+                ether_type: EtherType::Ipv4,
+                payload: fragment,
+            });
+            packet.extend_from_slice(&[0; 4]);
+            net_stack.handle_packet(&packet).await;
+        }
+
+        let datagram = socket.recv_from().await;
+        test_eq!(datagram.source_port, 1234);
+        test_eq!(datagram.data, payload);
+
+        Ok(())
+    });
+
+    create_test!(test_net_stack_ping, {
+        let net_stack = gen_net_stack();
+        net_stack.arp_table.write_mac(&REMOTE_IP, &REMOTE_MAC).await;
+
+        let ping = core::pin::pin!(net_stack.ping(&REMOTE_IP, 10.0));
+        let respond = core::pin::pin!(async {
+            let request = net_stack.interface().sent.lock().await.pop();
+            let request = match request {
+                Some(v) => v,
+                None => return Err::<(), String>("No echo request sent".into()),
+            };
+
+            let packet = parse_packet(&request).map_err(|_| "Invalid packet".to_string())?;
+            let ipv4_frame = match packet.inner {
+                ParsedPacket::Ipv4(v) => v,
+                _ => return Err("Sent packet was not ipv4".into()),
+            };
+            let request = match parse_ipv4(&ipv4_frame) {
+                Ok(ParsedIpv4Frame::Icmp(v)) => v,
+                _ => return Err("Sent packet was not icmp".into()),
+            };
+
+            let reply = icmp::generate_echo(
+                IcmpType::EchoReply,
+                request.identifier(),
+                request.sequence_number(),
+                request.payload(),
+            );
+            net_stack.time.set_tick(net_stack.time.get() + 5);
+            net_stack
+                .handle_packet(&gen_remote_ipv4_packet(&reply, Ipv4Protocol::Icmp))
+                .await;
+
+            core::future::pending().await
+        });
+
+        let rtt = match crate::future::select(ping, respond).await {
+            Either::Left((v, _)) => v.map_err(|e| format!("{:?}", e))?,
+            Either::Right((e, _)) => return e,
+        };
+
+        test_eq!(rtt, 0.5);
+
+        Ok(())
+    });
 }
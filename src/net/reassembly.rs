@@ -0,0 +1,293 @@
+use crate::{
+    net::{self, Ipv4Frame},
+    time::MonotonicTime,
+    util::async_mutex::Mutex,
+    IpAddr,
+};
+
+use alloc::{sync::Arc, vec::Vec};
+
+use hashbrown::HashMap;
+
+// Same as the linux default (ipfrag_time)
+const REASSEMBLY_TIMEOUT_S: f32 = 30.0;
+// Memory we're willing to hold in incomplete datagrams, the oldest ones get dropped past this
+const MAX_REASSEMBLY_BYTES: usize = 256 * 1024;
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct ReassemblyKey {
+    source_ip: IpAddr,
+    dest_ip: IpAddr,
+    identification: u16,
+    protocol: u8,
+}
+
+#[derive(Debug)]
+enum InvalidFragment {
+    TooLarge,
+    PastEnd,
+    LengthMismatch,
+}
+
+struct PartialDatagram {
+    // Header of the fragment at offset 0, once we have it
+    header: Option<Vec<u8>>,
+    payload: Vec<u8>,
+    // Sorted, non overlapping [start, end) ranges of the payload that we've received
+    received: Vec<(usize, usize)>,
+    // Known once the last fragment arrives
+    payload_length: Option<usize>,
+    expiry: usize,
+}
+
+impl PartialDatagram {
+    fn new(expiry: usize) -> PartialDatagram {
+        PartialDatagram {
+            header: None,
+            payload: Vec::new(),
+            received: Vec::new(),
+            payload_length: None,
+            expiry,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.header.as_ref().map_or(0, |h| h.len()) + self.payload.len()
+    }
+
+    fn insert(&mut self, frame: &Ipv4Frame<'_>) -> Result<(), InvalidFragment> {
+        let data = frame.payload();
+        let start = frame.fragment_offset();
+        let end = start + data.len();
+
+        if frame.header_length() + end > MAX_DATAGRAM_SIZE {
+            return Err(InvalidFragment::TooLarge);
+        }
+
+        if !frame.more_fragments() {
+            match self.payload_length {
+                Some(length) if length != end => return Err(InvalidFragment::LengthMismatch),
+                _ => self.payload_length = Some(end),
+            }
+        }
+
+        if let Some(length) = self.payload_length {
+            if end > length || self.payload.len() > length {
+                return Err(InvalidFragment::PastEnd);
+            }
+        }
+
+        if start == 0 {
+            self.header = Some(frame.packet[..frame.header_length()].to_vec());
+        }
+
+        if self.payload.len() < end {
+            self.payload.resize(end, 0);
+        }
+        self.payload[start..end].copy_from_slice(data);
+
+        self.received.push((start, end));
+        self.received.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.received.len());
+        for &(start, end) in &self.received {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.received = merged;
+
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        let length = match self.payload_length {
+            Some(v) => v,
+            None => return false,
+        };
+
+        self.header.is_some() && self.received == [(0, length)]
+    }
+
+    /// Rebuilds an unfragmented ipv4 packet out of the fragments
+    fn into_packet(self) -> Vec<u8> {
+        let mut packet = self.header.expect("Complete datagram should have a header");
+        let total_length = (packet.len() + self.payload.len()) as u16;
+        packet[2..4].copy_from_slice(&total_length.to_be_bytes());
+        // Clear flags + fragment offset
+        packet[6..8].copy_from_slice(&0u16.to_be_bytes());
+        packet[10..12].copy_from_slice(&0u16.to_be_bytes());
+        let checksum = net::calculate_ipv4_checksum(&packet);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        packet.extend_from_slice(&self.payload);
+        packet
+    }
+}
+
+pub struct Reassembler {
+    datagrams: Mutex<HashMap<ReassemblyKey, PartialDatagram>>,
+    time: Arc<MonotonicTime>,
+}
+
+impl Reassembler {
+    pub fn new(time: Arc<MonotonicTime>) -> Reassembler {
+        Reassembler {
+            datagrams: Mutex::new(HashMap::new()),
+            time,
+        }
+    }
+
+    /// Stores a fragment, returning the full ipv4 packet once all of its fragments have arrived
+    pub async fn add_fragment(&self, frame: &Ipv4Frame<'_>) -> Option<Vec<u8>> {
+        let now = self.time.get();
+        let mut datagrams = self.datagrams.lock().await;
+
+        datagrams.retain(|key, datagram| {
+            if datagram.expiry < now {
+                debug!("Reassembly of {:?} timed out", key);
+                return false;
+            }
+            true
+        });
+
+        let key = ReassemblyKey {
+            source_ip: frame.source_ip(),
+            dest_ip: frame.dest_ip(),
+            identification: frame.identification(),
+            protocol: frame.protocol().into(),
+        };
+
+        let expiry = now + (REASSEMBLY_TIMEOUT_S * self.time.tick_freq()) as usize;
+        let datagram = datagrams
+            .entry(key.clone())
+            .or_insert_with(|| PartialDatagram::new(expiry));
+
+        if let Err(e) = datagram.insert(frame) {
+            debug!("Dropping datagram {:?}, invalid fragment: {:?}", key, e);
+            datagrams.remove(&key);
+            return None;
+        }
+
+        if datagram.is_complete() {
+            return datagrams.remove(&key).map(PartialDatagram::into_packet);
+        }
+
+        while datagrams.values().map(PartialDatagram::size).sum::<usize>() > MAX_REASSEMBLY_BYTES {
+            let oldest = datagrams
+                .iter()
+                .min_by_key(|(_, datagram)| datagram.expiry)
+                .map(|(key, _)| key.clone())
+                .expect("Datagrams cannot be empty if they are using memory");
+            debug!("Out of reassembly memory, dropping {:?}", oldest);
+            datagrams.remove(&oldest);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{net::Ipv4Protocol, testing::*};
+    use alloc::string::ToString;
+
+    const SOURCE_IP: IpAddr = [192, 168, 2, 1];
+    const DEST_IP: IpAddr = [192, 168, 2, 2];
+
+    fn gen_fragments(payload: &[u8], identification: u16) -> Vec<Vec<u8>> {
+        net::generate_ipv4_fragments(
+            payload,
+            Ipv4Protocol::Udp,
+            &SOURCE_IP,
+            &DEST_IP,
+            identification,
+            // Small mtu to get a few fragments out of a small payload
+            68,
+        )
+        .expect("Payload should fit")
+    }
+
+    create_test!(test_reassembly_out_of_order, {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let reassembler = Reassembler::new(time);
+
+        let payload: Vec<u8> = (0..200).collect();
+        let fragments = gen_fragments(&payload, 1);
+        test_eq!(fragments.len(), 5);
+
+        // Deliver backwards, with a duplicate in the middle
+        let mut order: Vec<usize> = (0..fragments.len()).rev().collect();
+        order.insert(2, 3);
+
+        let mut reassembled = None;
+        for (i, idx) in order.iter().enumerate() {
+            let frame =
+                Ipv4Frame::new(&fragments[*idx]).map_err(|_| "Invalid fragment".to_string())?;
+            test_true!(frame.is_fragment());
+            let res = reassembler.add_fragment(&frame).await;
+            if i != order.len() - 1 {
+                test_true!(res.is_none());
+            }
+            reassembled = res;
+        }
+
+        let reassembled = reassembled.ok_or_else(|| "Datagram not reassembled".to_string())?;
+        let frame =
+            Ipv4Frame::new(&reassembled).map_err(|_| "Invalid reassembled frame".to_string())?;
+        test_false!(frame.is_fragment());
+        test_eq!(frame.source_ip(), SOURCE_IP);
+        test_eq!(frame.identification(), 1);
+        test_eq!(frame.payload(), &payload);
+
+        Ok(())
+    });
+
+    create_test!(test_reassembly_timeout, {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let reassembler = Reassembler::new(Arc::clone(&time));
+
+        let payload: Vec<u8> = (0..100).collect();
+        let fragments = gen_fragments(&payload, 2);
+        test_eq!(fragments.len(), 3);
+
+        for fragment in &fragments[..2] {
+            let frame = Ipv4Frame::new(fragment).map_err(|_| "Invalid fragment".to_string())?;
+            test_true!(reassembler.add_fragment(&frame).await.is_none());
+        }
+
+        time.set_tick((REASSEMBLY_TIMEOUT_S * time.tick_freq()) as usize + 1);
+
+        // The first two fragments have expired, so the last one alone can't complete anything
+        let frame = Ipv4Frame::new(&fragments[2]).map_err(|_| "Invalid fragment".to_string())?;
+        test_true!(reassembler.add_fragment(&frame).await.is_none());
+        test_eq!(reassembler.datagrams.lock().await.len(), 1);
+
+        Ok(())
+    });
+
+    create_test!(test_reassembly_memory_limit, {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let reassembler = Reassembler::new(Arc::clone(&time));
+
+        let payload = [0; 1000];
+        let datagrams_to_fill = MAX_REASSEMBLY_BYTES / payload.len() + 1;
+        for identification in 0..datagrams_to_fill {
+            // Keep the first datagram the oldest
+            time.set_tick(identification);
+            let fragments = gen_fragments(&payload, identification as u16);
+            let frame = Ipv4Frame::new(&fragments[fragments.len() - 1])
+                .map_err(|_| "Invalid fragment".to_string())?;
+            test_true!(reassembler.add_fragment(&frame).await.is_none());
+        }
+
+        let datagrams = reassembler.datagrams.lock().await;
+        let used: usize = datagrams.values().map(PartialDatagram::size).sum();
+        test_true!(used <= MAX_REASSEMBLY_BYTES);
+        test_false!(datagrams.keys().any(|k| k.identification == 0));
+
+        Ok(())
+    });
+}
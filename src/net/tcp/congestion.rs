@@ -0,0 +1,201 @@
+use super::seq_lt;
+
+use alloc::boxed::Box;
+
+// Duplicate acks in a row that we take as a lost segment (RFC 5681)
+pub const DUP_ACK_THRESHOLD: usize = 3;
+
+pub struct AckInfo {
+    pub ack_num: u32,
+    // Bytes newly acknowledged by this ack, 0 for duplicates
+    pub acked: usize,
+    // Bytes still unacknowledged after this ack
+    pub in_flight: usize,
+    // Sequence number of the next byte we'll send
+    pub next_seq: u32,
+}
+
+/// Decides how much data a connection can have in flight on top of the peer's receive window.
+/// The state machine reports acks and losses, and retransmits the oldest unacked segment
+/// whenever a callback asks for it
+pub trait CongestionControl: Send + Sync {
+    /// Bytes allowed in flight
+    fn window(&self) -> usize;
+
+    /// An ack moved the left edge of the window. Returns true if the oldest unacked segment
+    /// should be retransmitted right away
+    fn on_ack(&mut self, ack: &AckInfo) -> bool;
+
+    /// An ack that acknowledged nothing new while data was outstanding. duplicates counts the
+    /// duplicates in a row. Returns true if the oldest unacked segment should be retransmitted
+    /// right away
+    fn on_duplicate_ack(&mut self, ack: &AckInfo, duplicates: usize) -> bool;
+
+    /// The retransmission timer went off
+    fn on_retransmit_timeout(&mut self, in_flight: usize);
+}
+
+/// Controller new connections use
+pub fn default_controller(mss: usize) -> Box<dyn CongestionControl> {
+    Box::new(NewReno::new(mss))
+}
+
+/// Slow start, congestion avoidance, fast retransmit and fast recovery from RFC 5681, with the
+/// partial ack handling of RFC 6582
+pub struct NewReno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    // Highest sequence number sent when we entered fast recovery, recovery ends once it's acked
+    recover: Option<u32>,
+}
+
+impl NewReno {
+    pub fn new(mss: usize) -> NewReno {
+        // Initial window from RFC 5681 3.1
+        let cwnd = if mss > 2190 {
+            2 * mss
+        } else if mss > 1095 {
+            3 * mss
+        } else {
+            4 * mss
+        };
+
+        NewReno {
+            mss,
+            cwnd,
+            ssthresh: usize::MAX,
+            recover: None,
+        }
+    }
+
+    fn reduced_ssthresh(&self, in_flight: usize) -> usize {
+        (in_flight / 2).max(2 * self.mss)
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn window(&self) -> usize {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, ack: &AckInfo) -> bool {
+        match self.recover {
+            Some(recover) if seq_lt(ack.ack_num, recover) => {
+                // Partial ack, the next hole is lost too. Deflate by what was acked and stay
+                // in recovery
+                self.cwnd = self.cwnd.saturating_sub(ack.acked);
+                if ack.acked >= self.mss {
+                    self.cwnd += self.mss;
+                }
+                self.cwnd = self.cwnd.max(self.mss);
+                true
+            }
+            Some(_) => {
+                self.cwnd = self.ssthresh.min(ack.in_flight.max(self.mss) + self.mss);
+                self.recover = None;
+                false
+            }
+            None => {
+                if self.cwnd < self.ssthresh {
+                    self.cwnd += ack.acked.min(self.mss);
+                } else {
+                    self.cwnd += (self.mss * self.mss / self.cwnd).max(1);
+                }
+                false
+            }
+        }
+    }
+
+    fn on_duplicate_ack(&mut self, ack: &AckInfo, duplicates: usize) -> bool {
+        if self.recover.is_some() {
+            // Every duplicate means another segment left the network
+            self.cwnd += self.mss;
+            return false;
+        }
+
+        if duplicates != DUP_ACK_THRESHOLD {
+            return false;
+        }
+
+        self.ssthresh = self.reduced_ssthresh(ack.in_flight);
+        self.cwnd = self.ssthresh + DUP_ACK_THRESHOLD * self.mss;
+        self.recover = Some(ack.next_seq);
+        true
+    }
+
+    fn on_retransmit_timeout(&mut self, in_flight: usize) {
+        self.ssthresh = self.reduced_ssthresh(in_flight);
+        self.cwnd = self.mss;
+        self.recover = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    const MSS: usize = 1000;
+
+    fn ack(ack_num: u32, acked: usize, in_flight: usize) -> AckInfo {
+        AckInfo {
+            ack_num,
+            acked,
+            in_flight,
+            next_seq: ack_num.wrapping_add(in_flight as u32),
+        }
+    }
+
+    create_test!(test_new_reno_slow_start_and_avoidance, {
+        let mut reno = NewReno::new(MSS);
+        test_eq!(reno.window(), 4 * MSS);
+
+        // Slow start grows by a segment per ack
+        test_false!(reno.on_ack(&ack(1000, MSS, 3 * MSS)));
+        test_eq!(reno.window(), 5 * MSS);
+
+        reno.on_retransmit_timeout(10 * MSS);
+        test_eq!(reno.window(), MSS);
+        test_eq!(reno.ssthresh, 5 * MSS);
+
+        for i in 0..4 {
+            reno.on_ack(&ack(2000 + i * 1000, MSS, 0));
+        }
+        test_eq!(reno.window(), 5 * MSS);
+
+        // Past ssthresh it's about a segment per window
+        reno.on_ack(&ack(6000, MSS, 0));
+        test_eq!(reno.window(), 5 * MSS + MSS / 5);
+
+        Ok(())
+    });
+
+    create_test!(test_new_reno_fast_recovery, {
+        let mut reno = NewReno::new(MSS);
+        let in_flight = 8 * MSS;
+        let dup = ack(0, 0, in_flight);
+
+        test_false!(reno.on_duplicate_ack(&dup, 1));
+        test_false!(reno.on_duplicate_ack(&dup, 2));
+        test_true!(reno.on_duplicate_ack(&dup, 3));
+        test_eq!(reno.ssthresh, 4 * MSS);
+        test_eq!(reno.window(), 7 * MSS);
+
+        // Inflates while recovering, and doesn't retransmit again
+        test_false!(reno.on_duplicate_ack(&dup, 4));
+        test_eq!(reno.window(), 8 * MSS);
+
+        // Partial ack retransmits the next hole
+        test_true!(reno.on_ack(&ack(2000, 2 * MSS, 6 * MSS)));
+        test_eq!(reno.window(), 7 * MSS);
+
+        // Full ack ends recovery. Nothing is left in flight, so the window only gets to a
+        // couple of segments instead of ssthresh
+        test_false!(reno.on_ack(&ack(8000, 6 * MSS, 0)));
+        test_eq!(reno.window(), 2 * MSS);
+        test_true!(reno.recover.is_none());
+
+        Ok(())
+    });
+}
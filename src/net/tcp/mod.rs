@@ -0,0 +1,4303 @@
+pub mod congestion;
+pub mod options;
+mod out_of_order;
+mod syn_cookies;
+
+use congestion::{AckInfo, CongestionControl};
+use options::{NegotiatedOptions, SackBlock, TcpOptions, Timestamps};
+use out_of_order::OutOfOrderQueue;
+use syn_cookies::SynCookies;
+
+use crate::{
+    future::Either,
+    net::{self, Ipv4Protocol},
+    rng::Rng,
+    sleep::{self, WakeupRequester},
+    time::MonotonicTime,
+    util::{
+        async_channel::{self, Receiver, Sender},
+        async_io::{AsyncRead, UnexpectedEof},
+        async_mutex::Mutex,
+        atomic_cell::AtomicCell,
+        bit_manipulation::{GetBits, SetBits},
+        oneshot,
+        spinlock::SpinLock,
+    },
+    IpAddr,
+};
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use hashbrown::HashMap;
+
+// Ports handed out to outgoing connections
+const EPHEMERAL_PORT_START: u16 = 49152;
+const EPHEMERAL_PORT_END: u16 = 65535;
+
+// Syn is resent after SYN_TIMEOUT_S, doubling every retry, before connect gives up
+const SYN_TIMEOUT_S: f32 = 1.0;
+const SYN_RETRIES: usize = 3;
+// Syn ack is resent every SYN_ACK_TIMEOUT_S, after SYN_RETRIES the half open connection is
+// dropped
+const SYN_ACK_TIMEOUT_S: f32 = 1.0;
+
+// Half open connections a listener keeps state for, and connections waiting to be accepted.
+// Past the first limit syns are answered with syn cookies, past the second the handshake isn't
+// finished until the application catches up
+const LISTEN_BACKLOG: usize = 64;
+
+// 2 * MSL, same as linux. Long enough for stray segments of the old connection to die out
+const TIME_WAIT_S: f32 = 60.0;
+// How long a connection the application is done with waits in FIN-WAIT-2 for the peer's fin,
+// same as linux's tcp_fin_timeout
+const FIN_WAIT2_TIMEOUT_S: f32 = 60.0;
+
+// Retransmission timeout bounds (RFC 6298). The minimum is linux's rather than the RFC's 1s,
+// a second is a long time to stall for on a lan
+const INITIAL_RTO_S: f32 = 1.0;
+const MIN_RTO_S: f32 = 0.2;
+const MAX_RTO_S: f32 = 60.0;
+// Timeouts in a row without the peer acking anything before the connection is aborted
+const MAX_RETRANSMITS: usize = 8;
+
+// Received data the application hasn't read yet, advertised to the peer as our window
+const RECEIVE_BUFFER_SIZE: usize = 32 * 1024;
+// Written data the peer hasn't acked yet. Writers wait once there's this much of it
+const SEND_BUFFER_SIZE: usize = 64 * 1024;
+// Smallest shift that gets RECEIVE_BUFFER_SIZE into the 16 bit window field (RFC 7323)
+const RECEIVE_WINDOW_SCALE: u8 = {
+    let mut shift = 0;
+    while RECEIVE_BUFFER_SIZE >> shift > u16::MAX as usize {
+        shift += 1;
+    }
+    shift
+};
+// Segment size to assume when the peer doesn't tell us (RFC 879)
+const DEFAULT_MSS: usize = 536;
+const TCP_HEADER_LEN: usize = 20;
+// Biggest segment that fits in an ethernet frame without fragmenting, offered in our syns
+const LOCAL_MSS: usize = net::ETHERNET_MTU - net::IPV4_HEADER_SIZE - TCP_HEADER_LEN;
+
+pub struct TcpFlags(pub u8);
+
+#[allow(unused)]
+impl TcpFlags {
+    pub fn cwr(&self) -> bool {
+        self.0.get_bit(7)
+    }
+
+    pub fn ece(&self) -> bool {
+        self.0.get_bit(6)
+    }
+
+    pub fn urg(&self) -> bool {
+        self.0.get_bit(5)
+    }
+
+    pub fn ack(&self) -> bool {
+        self.0.get_bit(4)
+    }
+
+    pub fn psh(&self) -> bool {
+        self.0.get_bit(3)
+    }
+
+    pub fn rst(&self) -> bool {
+        self.0.get_bit(2)
+    }
+
+    pub fn syn(&self) -> bool {
+        self.0.get_bit(1)
+    }
+
+    pub fn fin(&self) -> bool {
+        self.0.get_bit(0)
+    }
+}
+
+impl core::fmt::Debug for TcpFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "cwr: {}", self.cwr())?;
+        write!(f, "ece: {}", self.ece())?;
+        write!(f, "urg: {}", self.urg())?;
+        write!(f, "ack: {}", self.ack())?;
+        write!(f, "psh: {}", self.psh())?;
+        write!(f, "rst: {}", self.rst())?;
+        write!(f, "syn: {}", self.syn())?;
+        write!(f, "fin: {}", self.fin())?;
+        Ok(())
+    }
+}
+
+pub struct TcpFlagsParams {
+    pub cwr: bool,
+    pub ece: bool,
+    pub urg: bool,
+    pub ack: bool,
+    pub psh: bool,
+    pub rst: bool,
+    pub syn: bool,
+    pub fin: bool,
+}
+
+pub fn generate_tcp_flags(params: &TcpFlagsParams) -> TcpFlags {
+    let mut ret = 0u8;
+
+    ret.set_bit(7, params.cwr);
+    ret.set_bit(6, params.ece);
+    ret.set_bit(5, params.urg);
+    ret.set_bit(4, params.ack);
+    ret.set_bit(3, params.psh);
+    ret.set_bit(2, params.rst);
+    ret.set_bit(1, params.syn);
+    ret.set_bit(0, params.fin);
+
+    TcpFlags(ret)
+}
+
+#[derive(Debug)]
+pub struct InvalidTcpFrame(usize, usize);
+
+pub struct TcpFrame<'a> {
+    data: &'a [u8],
+}
+
+impl TcpFrame<'_> {
+    pub(super) fn new(data: &[u8]) -> Result<TcpFrame<'_>, InvalidTcpFrame> {
+        if data.len() < TCP_HEADER_LEN {
+            return Err(InvalidTcpFrame(data.len(), 0));
+        }
+
+        let frame = TcpFrame { data };
+        let data_offset = frame.data_offset_bytes();
+        if data_offset < TCP_HEADER_LEN || data_offset > data.len() {
+            return Err(InvalidTcpFrame(data.len(), data_offset));
+        }
+
+        Ok(frame)
+    }
+
+    pub fn source_port(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[0..2]
+                .try_into()
+                .expect("tcp source port length wrong"),
+        )
+    }
+
+    pub fn dest_port(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[2..4]
+                .try_into()
+                .expect("tcp dest port length wrong"),
+        )
+    }
+
+    pub fn seq_num(&self) -> u32 {
+        u32::from_be_bytes(
+            self.data[4..8]
+                .try_into()
+                .expect("tcp seq num length wrong"),
+        )
+    }
+
+    pub fn ack_num(&self) -> u32 {
+        u32::from_be_bytes(
+            self.data[8..12]
+                .try_into()
+                .expect("tcp ack num length wrong"),
+        )
+    }
+
+    pub fn data_offset_bytes(&self) -> usize {
+        let data_offset_words = self.data[12].get_bits(4, 4);
+        (data_offset_words as usize) * 4
+    }
+
+    pub fn flags(&self) -> TcpFlags {
+        TcpFlags(self.data[13])
+    }
+
+    pub fn window_size(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[14..16]
+                .try_into()
+                .expect("tcp window size length wrong"),
+        )
+    }
+
+    pub fn checksum(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[16..18]
+                .try_into()
+                .expect("tcp checksum length wrong"),
+        )
+    }
+
+    pub fn urgent_ptr(&self) -> u16 {
+        u16::from_be_bytes(
+            self.data[18..20]
+                .try_into()
+                .expect("tcp checksum length wrong"),
+        )
+    }
+
+    pub fn options(&self) -> TcpOptions {
+        let options = self
+            .data
+            .get(TCP_HEADER_LEN..self.data_offset_bytes())
+            .unwrap_or(&[]);
+        options::parse_tcp_options(options)
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.data[self.data_offset_bytes()..]
+    }
+
+    /// Sequence space taken up by the segment. Syn and fin each count as one byte
+    pub fn segment_length(&self) -> u32 {
+        let flags = self.flags();
+        self.payload().len() as u32 + flags.syn() as u32 + flags.fin() as u32
+    }
+}
+
+impl core::fmt::Debug for TcpFrame<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "source_port: {}", self.source_port())?;
+        writeln!(f, "dest_port: {}", self.dest_port())?;
+        writeln!(f, "seq_num: {}", self.seq_num())?;
+        writeln!(f, "ack_num: {}", self.ack_num())?;
+        writeln!(f, "data_offset_bytes: {}", self.data_offset_bytes())?;
+        writeln!(f, "flags: {:?}", self.flags())?;
+        writeln!(f, "window_size: {}", self.window_size())?;
+        writeln!(f, "checksum: {:x}", self.checksum())?;
+        writeln!(f, "urgent_ptr: {}", self.urgent_ptr())?;
+        writeln!(f, "options: {:?}", self.options())?;
+        Ok(())
+    }
+}
+
+pub struct TcpFrameParams {
+    pub source_address: IpAddr,
+    pub dest_address: IpAddr,
+    pub source_port: u16,
+    pub dest_port: u16,
+    pub seq_num: u32,
+    pub ack_num: u32,
+    pub flags: TcpFlags,
+    pub window_size: u16,
+    pub urgent_ptr: u16,
+    pub options: TcpOptions,
+    pub payload: Arc<[u8]>,
+}
+
+pub fn generate_tcp_frame(params: &TcpFrameParams) -> Vec<u8> {
+    let options = options::generate_tcp_options(&params.options);
+    let header_len = TCP_HEADER_LEN + options.len();
+
+    let mut ret = Vec::with_capacity(header_len + params.payload.len());
+
+    ret.extend_from_slice(&params.source_port.to_be_bytes());
+    ret.extend_from_slice(&params.dest_port.to_be_bytes());
+    ret.extend_from_slice(&params.seq_num.to_be_bytes());
+    ret.extend_from_slice(&params.ack_num.to_be_bytes());
+    ret.push(((header_len / 4) << 4) as u8);
+    ret.push(params.flags.0);
+    ret.extend_from_slice(&params.window_size.to_be_bytes());
+    const CHECKSUM: u16 = 0;
+    let checksum_idx = ret.len();
+    ret.extend_from_slice(&CHECKSUM.to_be_bytes());
+    ret.extend_from_slice(&params.urgent_ptr.to_be_bytes());
+    ret.extend_from_slice(&options);
+    ret.extend_from_slice(&params.payload);
+
+    let checksum = net::calculate_pseudo_header_checksum(
+        &params.source_address,
+        &params.dest_address,
+        Ipv4Protocol::Tcp,
+        &ret,
+    );
+    ret[checksum_idx..checksum_idx + 2].copy_from_slice(&checksum.to_be_bytes());
+
+    ret
+}
+
+fn generate_tcp_push(
+    tcp_key: &TcpKey,
+    state: &mut ConnectedState,
+    data: Arc<[u8]>,
+    now: usize,
+) -> TcpFrameParams {
+    let payload_length = data.len();
+    // Push once the segment empties the send buffer (RFC 1122 4.2.2.2)
+    let psh = state.send_buffer.is_empty();
+    let ret = TcpFrameParams {
+        source_address: tcp_key.local_ip,
+        dest_address: tcp_key.remote_ip,
+        source_port: tcp_key.local_port,
+        dest_port: tcp_key.remote_port,
+        seq_num: state.seq_num,
+        ack_num: state.outgoing_ack_num,
+        flags: generate_tcp_flags(&TcpFlagsParams {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh,
+            rst: false,
+            syn: false,
+            fin: false,
+        }),
+        window_size: state.advertise_window(),
+        urgent_ptr: 0,
+        options: state.segment_options(now),
+        payload: data,
+    };
+
+    state.seq_num = state.seq_num.wrapping_add(payload_length as u32);
+
+    ret
+}
+
+fn generate_tcp_fin(tcp_key: &TcpKey, state: &mut ConnectedState, now: usize) -> TcpFrameParams {
+    let ret = TcpFrameParams {
+        source_address: tcp_key.local_ip,
+        dest_address: tcp_key.remote_ip,
+        source_port: tcp_key.local_port,
+        dest_port: tcp_key.remote_port,
+        seq_num: state.seq_num,
+        ack_num: state.outgoing_ack_num,
+        flags: generate_tcp_flags(&TcpFlagsParams {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh: false,
+            rst: false,
+            syn: false,
+            fin: true,
+        }),
+        window_size: state.advertise_window(),
+        urgent_ptr: 0,
+        options: state.segment_options(now),
+        payload: Arc::new([]),
+    };
+
+    state.fin_seq = Some(state.seq_num);
+    state.seq_num = state.seq_num.wrapping_add(1);
+
+    ret
+}
+
+fn generate_tcp_ack(tcp_key: &TcpKey, state: &mut ConnectedState, now: usize) -> Arc<[u8]> {
+    generate_tcp_ack_with_seq(tcp_key, state, state.seq_num, now)
+}
+
+/// Zero window probe. An already acked sequence number makes the peer answer with an ack
+/// carrying its current window, without us having to push data past that window
+fn generate_window_probe(tcp_key: &TcpKey, state: &mut ConnectedState, now: usize) -> Arc<[u8]> {
+    generate_tcp_ack_with_seq(tcp_key, state, state.incoming_ack_num.wrapping_sub(1), now)
+}
+
+/// Keepalive probe (RFC 1122 4.2.3.6). Same trick as the window probe, the old sequence number
+/// gets us an ack if the peer is still there
+fn generate_keepalive_probe(tcp_key: &TcpKey, state: &mut ConnectedState, now: usize) -> Arc<[u8]> {
+    generate_tcp_ack_with_seq(tcp_key, state, state.incoming_ack_num.wrapping_sub(1), now)
+}
+
+/// Reset that aborts an established connection
+fn generate_connection_reset(tcp_key: &TcpKey, state: &ConnectedState) -> Arc<[u8]> {
+    generate_tcp_frame(&TcpFrameParams {
+        source_address: tcp_key.local_ip,
+        dest_address: tcp_key.remote_ip,
+        source_port: tcp_key.local_port,
+        dest_port: tcp_key.remote_port,
+        seq_num: state.seq_num,
+        ack_num: state.outgoing_ack_num,
+        flags: generate_tcp_flags(&TcpFlagsParams {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh: false,
+            rst: true,
+            syn: false,
+            fin: false,
+        }),
+        window_size: 0,
+        urgent_ptr: 0,
+        options: TcpOptions::default(),
+        payload: Arc::new([]),
+    })
+    .into()
+}
+
+fn generate_tcp_ack_with_seq(
+    tcp_key: &TcpKey,
+    state: &mut ConnectedState,
+    seq_num: u32,
+    now: usize,
+) -> Arc<[u8]> {
+    let mut options = state.segment_options(now);
+    if state.options.sack_permitted && !state.out_of_order.is_empty() {
+        options.sack_blocks = state.out_of_order.sack_blocks();
+    }
+
+    generate_tcp_frame(&TcpFrameParams {
+        source_address: tcp_key.local_ip,
+        dest_address: tcp_key.remote_ip,
+        source_port: tcp_key.local_port,
+        dest_port: tcp_key.remote_port,
+        seq_num,
+        ack_num: state.outgoing_ack_num,
+        flags: generate_tcp_flags(&TcpFlagsParams {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh: false,
+            rst: false,
+            syn: false,
+            fin: false,
+        }),
+        window_size: state.advertise_window(),
+        urgent_ptr: 0,
+        options,
+        payload: Arc::new([]),
+    })
+    .into()
+}
+
+/// Reset for a segment that doesn't belong to any connection, see "Reset Generation" in RFC 793
+fn generate_tcp_reset(
+    frame: &TcpFrame<'_>,
+    source_ip: &IpAddr,
+    dest_ip: &IpAddr,
+) -> Option<Arc<[u8]>> {
+    let flags = frame.flags();
+    // Never answer a reset with a reset
+    if flags.rst() {
+        return None;
+    }
+
+    let (seq_num, ack_num) = if flags.ack() {
+        (frame.ack_num(), 0)
+    } else {
+        (0, frame.seq_num().wrapping_add(frame.segment_length()))
+    };
+
+    let ret = generate_tcp_frame(&TcpFrameParams {
+        source_address: *dest_ip,
+        dest_address: *source_ip,
+        source_port: frame.dest_port(),
+        dest_port: frame.source_port(),
+        seq_num,
+        ack_num,
+        flags: generate_tcp_flags(&TcpFlagsParams {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack: !flags.ack(),
+            psh: false,
+            rst: true,
+            syn: false,
+            fin: false,
+        }),
+        window_size: 0,
+        urgent_ptr: 0,
+        options: TcpOptions::default(),
+        payload: Arc::new([]),
+    });
+
+    Some(ret.into())
+}
+
+/// Syn ack answering the syn in frame
+fn generate_syn_ack(
+    frame: &TcpFrame<'_>,
+    source_ip: &IpAddr,
+    dest_ip: &IpAddr,
+    seq_num: u32,
+    options: TcpOptions,
+) -> Arc<[u8]> {
+    generate_tcp_frame(&TcpFrameParams {
+        source_address: *dest_ip,
+        dest_address: *source_ip,
+        source_port: frame.dest_port(),
+        dest_port: frame.source_port(),
+        seq_num,
+        ack_num: frame.seq_num().wrapping_add(1),
+        flags: generate_tcp_flags(&TcpFlagsParams {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh: false,
+            rst: false,
+            syn: true,
+            fin: false,
+        }),
+        window_size: initial_window(),
+        urgent_ptr: 0,
+        options,
+        payload: Arc::new([]),
+    })
+    .into()
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct TcpListenerKey {
+    ip: IpAddr,
+    port: u16,
+}
+
+#[derive(Clone)]
+struct ListenerEntry {
+    tx: Sender<TcpConnection>,
+    // Connections sent to the TcpListener that haven't been accepted yet
+    queued: Arc<AtomicUsize>,
+}
+
+impl ListenerEntry {
+    fn queue_full(&self) -> bool {
+        self.queued.load(Ordering::Acquire) >= LISTEN_BACKLOG
+    }
+
+    async fn push(&self, connection: TcpConnection) {
+        self.queued.fetch_add(1, Ordering::AcqRel);
+        self.tx.send(connection).await;
+    }
+}
+
+type Listeners = HashMap<TcpListenerKey, ListenerEntry>;
+
+fn find_listener<'a>(
+    listeners: &'a Listeners,
+    ip: &IpAddr,
+    port: u16,
+) -> Option<(&'a TcpListenerKey, &'a ListenerEntry)> {
+    let wildcard_key = TcpListenerKey { ip: [0; 4], port };
+    listeners
+        .get_key_value(&TcpListenerKey { ip: *ip, port })
+        .or_else(|| listeners.get_key_value(&wildcard_key))
+}
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct TcpKey {
+    remote_ip: IpAddr,
+    local_ip: IpAddr,
+    remote_port: u16,
+    local_port: u16,
+}
+
+fn initial_window() -> u16 {
+    RECEIVE_BUFFER_SIZE.min(u16::MAX as usize) as u16
+}
+
+/// Options for our syn, or for our syn ack when answering peer_syn. A syn ack only carries the
+/// extensions the peer's syn asked for
+fn syn_options(peer_syn: Option<&TcpOptions>, now: usize) -> TcpOptions {
+    let mut ret = TcpOptions {
+        mss: Some(LOCAL_MSS as u16),
+        window_scale: Some(RECEIVE_WINDOW_SCALE),
+        sack_permitted: true,
+        sack_blocks: Vec::new(),
+        timestamps: Some(Timestamps {
+            value: now as u32,
+            echo_reply: 0,
+        }),
+    };
+
+    if let Some(peer_syn) = peer_syn {
+        if peer_syn.window_scale.is_none() {
+            ret.window_scale = None;
+        }
+        ret.sack_permitted &= peer_syn.sack_permitted;
+        ret.timestamps = peer_syn.timestamps.map(|peer| Timestamps {
+            value: now as u32,
+            echo_reply: peer.value,
+        });
+    }
+
+    ret
+}
+
+/// a < b in sequence space, which wraps around
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    !seq_lt(b, a)
+}
+
+struct UnackedPacket {
+    timestamp: usize,
+    // Acks for retransmitted segments are ambiguous, so they aren't used for rtt samples (Karn's
+    // algorithm)
+    retransmitted: bool,
+    // The peer has it past a hole, so it doesn't need resending
+    sacked: bool,
+    params: TcpFrameParams,
+}
+
+impl UnackedPacket {
+    /// Sequence number right after the segment, an ack of this covers all of it
+    fn end_seq(&self) -> u32 {
+        let length = self.params.payload.len() as u32 + self.params.flags.fin() as u32;
+        self.params.seq_num.wrapping_add(length)
+    }
+}
+
+/// Retransmission timeout estimation from RFC 6298
+struct RttEstimator {
+    srtt: f32,
+    rttvar: f32,
+    rto: f32,
+    has_sample: bool,
+}
+
+impl RttEstimator {
+    fn new() -> RttEstimator {
+        RttEstimator {
+            srtt: 0.0,
+            rttvar: 0.0,
+            rto: INITIAL_RTO_S,
+            has_sample: false,
+        }
+    }
+
+    fn sample(&mut self, rtt_s: f32, clock_granularity_s: f32) {
+        const ALPHA: f32 = 1.0 / 8.0;
+        const BETA: f32 = 1.0 / 4.0;
+
+        if self.has_sample {
+            let diff = self.srtt - rtt_s;
+            let diff = if diff < 0.0 { -diff } else { diff };
+            self.rttvar = (1.0 - BETA) * self.rttvar + BETA * diff;
+            self.srtt = (1.0 - ALPHA) * self.srtt + ALPHA * rtt_s;
+        } else {
+            self.srtt = rtt_s;
+            self.rttvar = rtt_s / 2.0;
+            self.has_sample = true;
+        }
+
+        self.rto =
+            (self.srtt + clock_granularity_s.max(4.0 * self.rttvar)).clamp(MIN_RTO_S, MAX_RTO_S);
+    }
+
+    /// Called on every timeout, the next sample undoes it
+    fn backoff(&mut self) {
+        self.rto = (self.rto * 2.0).min(MAX_RTO_S);
+    }
+
+    fn rto_s(&self) -> f32 {
+        self.rto
+    }
+}
+
+/// Where a synchronized connection is in the RFC 793 teardown
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum ConnectionPhase {
+    Established,
+    // We've closed our side, waiting for our fin to be acked
+    FinWait1,
+    // Our fin is acked, waiting for the peer to close its side
+    FinWait2,
+    // Both sides sent fins at the same time, waiting for ours to be acked
+    Closing,
+    // The peer closed its side, waiting for the application to close ours
+    CloseWait,
+    // Both sides closed, waiting for our fin to be acked
+    LastAck,
+}
+
+impl ConnectionPhase {
+    fn name(&self) -> &'static str {
+        match self {
+            ConnectionPhase::Established => "ESTABLISHED",
+            ConnectionPhase::FinWait1 => "FIN-WAIT-1",
+            ConnectionPhase::FinWait2 => "FIN-WAIT-2",
+            ConnectionPhase::Closing => "CLOSING",
+            ConnectionPhase::CloseWait => "CLOSE-WAIT",
+            ConnectionPhase::LastAck => "LAST-ACK",
+        }
+    }
+}
+
+struct ConnectedState {
+    seq_num: u32,          // Incoming seq num
+    outgoing_ack_num: u32, // Outgoing ack num
+    incoming_ack_num: u32,
+    options: NegotiatedOptions,
+    // Latest timestamp from the peer, echoed back when timestamps are on
+    ts_recent: u32,
+    // The peer's receive window in bytes, scaled
+    window_size: usize,
+    // Receive window in the last segment we sent, in bytes
+    advertised_window: usize,
+    // Bytes handed to the TcpConnection that it hasn't read yet
+    unread_bytes: Arc<AtomicUsize>,
+    out_of_order: OutOfOrderQueue,
+    // Armed while the peer's window is closed and we have data for it
+    persist_timeout: Option<usize>,
+    zero_window_probes: u32,
+    dup_ack_counter: usize,
+    congestion: Box<dyn CongestionControl>,
+    // Set when the congestion controller wants the oldest unacked segment resent
+    fast_retransmit: bool,
+    unacknowledged: VecDeque<UnackedPacket>,
+    // Written data that hasn't been sent yet, cut into segments as the window allows
+    send_buffer: VecDeque<u8>,
+    // Set by the TcpConnection to send small segments without waiting for acks
+    nodelay: Arc<AtomicBool>,
+    send_space: Arc<SpinLock<SendSpace>>,
+    rtt: RttEstimator,
+    // Tick the oldest unacked segment gets resent at, armed while anything is unacked
+    retransmit_timeout: Option<usize>,
+    retransmits: usize,
+    phase: ConnectionPhase,
+    // Sequence number of our fin once it has been sent
+    fin_seq: Option<u32>,
+    // Set by the TcpConnection when the application is done writing
+    write_closed: Arc<AtomicBool>,
+    // Set when the TcpConnection is dropped, nothing is going to read from the connection again
+    orphaned: Arc<AtomicBool>,
+    // Tick an orphaned connection gives up waiting for the peer's fin at
+    fin_wait2_timeout: Option<usize>,
+    timeouts: Arc<SpinLock<ConnectionTimeouts>>,
+    // Tick we last heard from the peer at, keepalive probes start some time after it
+    last_received: usize,
+    // Tick data last went either way at, for the idle timeout
+    last_data: usize,
+    // Probes sent since we last heard from the peer
+    keepalive_probes: usize,
+    // Earliest wakeup the keepalive and idle timers have asked for
+    next_timer: Option<usize>,
+    // Set when the connection fails, so writers see it too
+    error: Arc<SpinLock<Option<TcpError>>>,
+    tx: Sender<Result<Vec<u8>, TcpError>>,
+    rx: Receiver<Arc<[u8]>>,
+}
+
+impl ConnectedState {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        seq_num: u32,
+        outgoing_ack_num: u32,
+        incoming_ack_num: u32,
+        window_size: usize,
+        options: NegotiatedOptions,
+        ts_recent: u32,
+        now: usize,
+        service_waker: &Arc<AtomicCell<Waker>>,
+    ) -> (ConnectedState, TcpConnection) {
+        let (tx_in, rx_in) = async_channel::channel();
+        let (tx_out, rx_out) = async_channel::channel();
+        let write_closed = Arc::new(AtomicBool::new(false));
+        let orphaned = Arc::new(AtomicBool::new(false));
+        let nodelay = Arc::new(AtomicBool::new(false));
+        let unread_bytes = Arc::new(AtomicUsize::new(0));
+        let send_space = Arc::new(SpinLock::new(SendSpace::default()));
+        let timeouts = Arc::new(SpinLock::new(ConnectionTimeouts::default()));
+        let error = Arc::new(SpinLock::new(None));
+
+        let connection = TcpConnection {
+            rx: rx_in,
+            tx: tx_out,
+            unread_bytes: Arc::clone(&unread_bytes),
+            read_buffer: SpinLock::new(ReadBuffer {
+                data: VecDeque::new(),
+                end: None,
+            }),
+            write_closed: Arc::clone(&write_closed),
+            orphaned: Arc::clone(&orphaned),
+            nodelay: Arc::clone(&nodelay),
+            send_space: Arc::clone(&send_space),
+            timeouts: Arc::clone(&timeouts),
+            error: Arc::clone(&error),
+            service_waker: Arc::clone(service_waker),
+        };
+
+        let state = ConnectedState {
+            seq_num,
+            outgoing_ack_num,
+            incoming_ack_num,
+            options,
+            ts_recent,
+            window_size,
+            advertised_window: initial_window() as usize,
+            unread_bytes,
+            out_of_order: OutOfOrderQueue::default(),
+            persist_timeout: None,
+            zero_window_probes: 0,
+            dup_ack_counter: 0,
+            congestion: congestion::default_controller(options.max_payload()),
+            fast_retransmit: false,
+            unacknowledged: VecDeque::new(),
+            send_buffer: VecDeque::new(),
+            nodelay,
+            send_space,
+            rtt: RttEstimator::new(),
+            retransmit_timeout: None,
+            retransmits: 0,
+            phase: ConnectionPhase::Established,
+            fin_seq: None,
+            write_closed,
+            orphaned,
+            fin_wait2_timeout: None,
+            timeouts,
+            last_received: now,
+            last_data: now,
+            keepalive_probes: 0,
+            next_timer: None,
+            error,
+            tx: tx_in,
+            rx: rx_out,
+        };
+
+        (state, connection)
+    }
+
+    /// Free space in our receive buffer, rounded down to what the window field can express
+    fn receive_window(&self) -> usize {
+        let scale = self.options.receive_window_scale;
+        let unread = self.unread_bytes.load(Ordering::Acquire);
+        let window = RECEIVE_BUFFER_SIZE
+            .saturating_sub(unread)
+            .min((u16::MAX as usize) << scale);
+        window >> scale << scale
+    }
+
+    /// Receive window to put in an outgoing segment
+    fn advertise_window(&mut self) -> u16 {
+        self.advertised_window = self.receive_window();
+        (self.advertised_window >> self.options.receive_window_scale) as u16
+    }
+
+    /// Whether the application has read enough since our last advertisement that the peer
+    /// should hear about it. Small updates aren't worth a segment (receiver side silly window
+    /// avoidance from RFC 1122)
+    fn needs_window_update(&self) -> bool {
+        let threshold = (RECEIVE_BUFFER_SIZE / 2).min(self.options.mss);
+        self.receive_window() >= self.advertised_window + threshold
+    }
+
+    /// Window the peer advertised in frame, in bytes
+    fn peer_window(&self, frame: &TcpFrame<'_>) -> usize {
+        (frame.window_size() as usize) << self.options.send_window_scale
+    }
+
+    /// Options every segment on the connection carries
+    fn segment_options(&self, now: usize) -> TcpOptions {
+        TcpOptions {
+            timestamps: self.options.timestamps.then_some(Timestamps {
+                value: now as u32,
+                echo_reply: self.ts_recent,
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Bytes we can send before filling either the peer's window or the congestion window
+    fn send_window_available(&self) -> usize {
+        let window = self.window_size.min(self.congestion.window());
+        window.saturating_sub(self.in_flight())
+    }
+
+    /// Probes back off like retransmissions, but are kept up for as long as the peer answers
+    fn restart_persist_timer(&mut self, time: &MonotonicTime, wakeups: &SpinLock<Vec<usize>>) {
+        let backoff = (1u32 << self.zero_window_probes.min(16)) as f32;
+        let interval_s = (self.rtt.rto_s() * backoff).min(MAX_RTO_S);
+        let timeout = time.get() + (interval_s * time.tick_freq()) as usize;
+        self.persist_timeout = Some(timeout);
+        wakeups.lock().push(timeout);
+    }
+
+    /// Has the service woken up at tick, unless an earlier wakeup is already on its way. The
+    /// earlier one asks for this one again when it goes off
+    fn schedule_timer(&mut self, tick: usize, now: usize, wakeups: &SpinLock<Vec<usize>>) {
+        if matches!(self.next_timer, Some(next) if next > now && next <= tick) {
+            return;
+        }
+
+        self.next_timer = Some(tick);
+        wakeups.lock().push(tick);
+    }
+
+    fn restart_retransmit_timer(&mut self, time: &MonotonicTime, wakeups: &SpinLock<Vec<usize>>) {
+        let timeout = time.get() + (self.rtt.rto_s() * time.tick_freq()) as usize;
+        self.retransmit_timeout = Some(timeout);
+        wakeups.lock().push(timeout);
+    }
+
+    /// Moves the left edge of our send window. Drops every segment the ack covers, sampling the
+    /// rtt from them, and lets the congestion controller know about new and duplicate acks
+    fn handle_ack(
+        &mut self,
+        frame: &TcpFrame<'_>,
+        options: &TcpOptions,
+        time: &MonotonicTime,
+        wakeups: &SpinLock<Vec<usize>>,
+    ) {
+        let ack_num = frame.ack_num();
+
+        if self.options.sack_permitted {
+            self.mark_sacked(&options.sack_blocks);
+        }
+
+        if ack_num == self.incoming_ack_num {
+            // RFC 5681 only counts pure acks that don't change the window as duplicates
+            let duplicate = frame.segment_length() == 0
+                && self.peer_window(frame) == self.window_size
+                && !self.unacknowledged.is_empty();
+            if !duplicate {
+                self.dup_ack_counter = 0;
+                return;
+            }
+
+            self.dup_ack_counter += 1;
+            let info = self.ack_info(0);
+            if self
+                .congestion
+                .on_duplicate_ack(&info, self.dup_ack_counter)
+            {
+                self.fast_retransmit = true;
+            }
+            return;
+        }
+
+        if !seq_lt(self.incoming_ack_num, ack_num) || !seq_le(ack_num, self.seq_num) {
+            // Old, or acks something we haven't sent
+            return;
+        }
+
+        let acked = ack_num.wrapping_sub(self.incoming_ack_num) as usize;
+        self.incoming_ack_num = ack_num;
+        self.dup_ack_counter = 0;
+
+        let now = time.get();
+        // Echoed timestamps give an unambiguous sample even for retransmitted segments
+        // (RFC 7323 4.3)
+        let echoed_rtt = match options.timestamps {
+            Some(timestamps) if self.options.timestamps => {
+                Some((now as u32).wrapping_sub(timestamps.echo_reply))
+            }
+            _ => None,
+        };
+
+        let mut acked_payload = 0;
+        while let Some(packet) = self.unacknowledged.front() {
+            if !seq_le(packet.end_seq(), ack_num) {
+                break;
+            }
+
+            if echoed_rtt.is_none() && !packet.retransmitted {
+                let rtt_s = now.saturating_sub(packet.timestamp) as f32 / time.tick_freq();
+                self.rtt.sample(rtt_s, 1.0 / time.tick_freq());
+            }
+
+            acked_payload += packet.params.payload.len();
+            self.unacknowledged.pop_front();
+        }
+        self.send_space.lock().release(acked_payload);
+
+        if let Some(rtt) = echoed_rtt {
+            self.rtt
+                .sample(rtt as f32 / time.tick_freq(), 1.0 / time.tick_freq());
+        }
+
+        self.retransmits = 0;
+        if self.unacknowledged.is_empty() {
+            self.retransmit_timeout = None;
+        } else {
+            self.restart_retransmit_timer(time, wakeups);
+        }
+
+        let info = self.ack_info(acked);
+        if self.congestion.on_ack(&info) {
+            self.fast_retransmit = true;
+        }
+    }
+
+    /// Flags the unacked segments that the peer's sack blocks cover
+    fn mark_sacked(&mut self, blocks: &[SackBlock]) {
+        for packet in &mut self.unacknowledged {
+            let start = packet.params.seq_num;
+            let end = packet.end_seq();
+            packet.sacked |= blocks
+                .iter()
+                .any(|block| seq_le(block.start, start) && seq_le(end, block.end));
+        }
+    }
+
+    /// Oldest segment that the peer's sacks show as lost: nothing covers it but data after it
+    /// made it through. The first unacked segment is left to the duplicate ack and timeout
+    /// logic, and each hole is only resent once
+    fn next_sack_hole(&self) -> Option<usize> {
+        let last_sacked = self
+            .unacknowledged
+            .iter()
+            .rposition(|packet| packet.sacked)?;
+        (1..last_sacked).find(|&i| {
+            let packet = &self.unacknowledged[i];
+            !packet.sacked && !packet.retransmitted
+        })
+    }
+
+    fn in_flight(&self) -> usize {
+        self.seq_num.wrapping_sub(self.incoming_ack_num) as usize
+    }
+
+    fn ack_info(&self, acked: usize) -> AckInfo {
+        AckInfo {
+            ack_num: self.incoming_ack_num,
+            acked,
+            in_flight: self.in_flight(),
+            next_seq: self.seq_num,
+        }
+    }
+
+    fn stats(&self) -> TcpConnectionStats {
+        TcpConnectionStats {
+            in_flight: self.in_flight(),
+            send_buffered: self.send_buffer.len(),
+            unread: self.unread_bytes.load(Ordering::Acquire),
+            congestion_window: self.congestion.window(),
+            peer_window: self.window_size,
+            srtt_s: self.rtt.has_sample.then_some(self.rtt.srtt),
+            retransmits: self.retransmits,
+        }
+    }
+}
+
+enum TcpState {
+    Uninit,
+    SynSent {
+        seq_num: u32,
+        sent_frame: OutgoingTcpPacket,
+        // Set by connect whenever the syn should be (re)sent
+        send_pending: bool,
+        connection: Option<oneshot::Sender<Result<TcpConnection, ConnectError>>>,
+    },
+    SynAckSent {
+        seq_num: u32,
+        ack_num: u32,
+        timeout: usize,
+        retries: usize,
+        sent_frame: OutgoingTcpPacket,
+        options: NegotiatedOptions,
+        // Listener the connection goes to once established, its backlog counts this one
+        listener: TcpListenerKey,
+    },
+    Connected(Box<ConnectedState>),
+    TimeWait {
+        expiry: usize,
+        // Resent if the peer retransmits its fin
+        ack: Arc<[u8]>,
+    },
+}
+
+impl Drop for ConnectedState {
+    fn drop(&mut self) {
+        // Nothing is going to be acked anymore, writers have to find out about it
+        let mut send_space = self.send_space.lock();
+        send_space.closed = true;
+        send_space.wake_writers();
+    }
+}
+
+/// Written data the peer hasn't acked yet, shared so writers can wait for room
+#[derive(Default)]
+struct SendSpace {
+    buffered: usize,
+    writers: Vec<Waker>,
+    // The connection is gone, so the buffer will never drain
+    closed: bool,
+}
+
+impl SendSpace {
+    fn release(&mut self, acked: usize) {
+        if acked == 0 {
+            return;
+        }
+
+        self.buffered = self.buffered.saturating_sub(acked);
+        if self.buffered < SEND_BUFFER_SIZE {
+            self.wake_writers();
+        }
+    }
+
+    fn wake_writers(&mut self) {
+        for waker in self.writers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+struct ReadBuffer {
+    // Received data left over from earlier reads
+    data: VecDeque<u8>,
+    // How the stream ended, once the peer has closed or reset the connection
+    end: Option<Result<(), TcpError>>,
+}
+
+/// Keepalive probing (RFC 1122 4.2.3.6). Once the peer has been quiet for idle_s a probe goes
+/// out every interval_s, and the connection is given up on after probes of them go unanswered
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    pub idle_s: f32,
+    pub interval_s: f32,
+    pub probes: usize,
+}
+
+impl Default for Keepalive {
+    // Same as linux
+    fn default() -> Keepalive {
+        Keepalive {
+            idle_s: 7200.0,
+            interval_s: 75.0,
+            probes: 9,
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct ConnectionTimeouts {
+    keepalive: Option<Keepalive>,
+    // Connection is torn down after this long without data going either way
+    idle_timeout_s: Option<f32>,
+}
+
+pub struct TcpConnection {
+    // Chunks of in order data from the peer, an empty one means the peer closed its side
+    rx: Receiver<Result<Vec<u8>, TcpError>>,
+    tx: Sender<Arc<[u8]>>,
+    unread_bytes: Arc<AtomicUsize>,
+    read_buffer: SpinLock<ReadBuffer>,
+    write_closed: Arc<AtomicBool>,
+    orphaned: Arc<AtomicBool>,
+    nodelay: Arc<AtomicBool>,
+    send_space: Arc<SpinLock<SendSpace>>,
+    timeouts: Arc<SpinLock<ConnectionTimeouts>>,
+    // Why the connection failed, once it has
+    error: Arc<SpinLock<Option<TcpError>>>,
+    service_waker: Arc<AtomicCell<Waker>>,
+}
+
+impl TcpConnection {
+    /// Queues data to be sent. The connection is a byte stream, so writes can be merged or
+    /// split up on their way to the peer. Waits while SEND_BUFFER_SIZE bytes or more are still
+    /// waiting to be acked, so a peer that stops reading holds the writer up instead of using
+    /// up memory. Fails once the connection has been reset or timed out, or after shutdown
+    pub async fn write<T>(&self, data: T) -> Result<(), TcpError>
+    where
+        T: Into<Arc<[u8]>>,
+    {
+        if let Some(error) = *self.error.lock() {
+            return Err(error);
+        }
+
+        if self.write_closed.load(Ordering::Acquire) {
+            return Err(TcpError::Shutdown);
+        }
+
+        let data = data.into();
+        crate::future::poll_fn(|cx| {
+            let mut send_space = self.send_space.lock();
+            if send_space.buffered < SEND_BUFFER_SIZE || send_space.closed {
+                send_space.buffered += data.len();
+                return Poll::Ready(());
+            }
+
+            if !send_space.writers.iter().any(|w| w.will_wake(cx.waker())) {
+                send_space.writers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await;
+
+        // The connection might have failed while we were waiting
+        if let Some(error) = *self.error.lock() {
+            return Err(error);
+        }
+
+        self.tx.send(data).await;
+        Ok(())
+    }
+
+    /// Sends keepalive probes while the peer is quiet, None turns them off (the default)
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) {
+        self.timeouts.lock().keepalive = keepalive;
+        self.wake_service();
+    }
+
+    /// Tears the connection down once no data has gone either way for timeout_s, None turns
+    /// the timeout off (the default)
+    pub fn set_idle_timeout(&self, timeout_s: Option<f32>) {
+        self.timeouts.lock().idle_timeout_s = timeout_s;
+        self.wake_service();
+    }
+
+    /// Turns Nagle's algorithm off, so small writes go out right away instead of being held
+    /// back until the data in flight is acked
+    pub fn set_nodelay(&self, nodelay: bool) {
+        self.nodelay.store(nodelay, Ordering::Release);
+        self.wake_service();
+    }
+
+    /// Closes our sending side once everything written so far has been sent. The peer can keep
+    /// sending to us until it closes its side too
+    pub fn shutdown(&self) {
+        self.write_closed.store(true, Ordering::Release);
+        self.wake_service();
+    }
+
+    fn wake_service(&self) {
+        if let Some(service_waker) = self.service_waker.get() {
+            service_waker.wake_by_ref();
+        }
+    }
+
+    /// Shuts down the connection and gives up on reading anything else from it. Dropping the
+    /// connection does the same. If data is left unread, or more arrives, the peer gets a reset
+    /// instead of waiting on a reader that isn't there
+    pub fn close(self) {}
+}
+
+impl Drop for TcpConnection {
+    fn drop(&mut self) {
+        self.orphaned.store(true, Ordering::Release);
+        self.shutdown();
+    }
+}
+
+impl AsyncRead for TcpConnection {
+    type Error = TcpError;
+
+    /// Reads whatever the peer has sent so far, up to buf.len() bytes. Data that arrived before
+    /// a fin or reset is read first, after that every read returns 0 or the error
+    fn read<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> Pin<Box<dyn Future<Output = Result<usize, TcpError>> + Send + 'a>> {
+        Box::pin(async move {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+
+            loop {
+                {
+                    let mut read_buffer = self.read_buffer.lock();
+                    if !read_buffer.data.is_empty() {
+                        let n = buf.len().min(read_buffer.data.len());
+                        for (dest, byte) in buf.iter_mut().zip(read_buffer.data.drain(..n)) {
+                            *dest = byte;
+                        }
+                        drop(read_buffer);
+
+                        // Our receive window just opened up, the service might want to tell
+                        // the peer
+                        self.unread_bytes.fetch_sub(n, Ordering::AcqRel);
+                        self.wake_service();
+                        return Ok(n);
+                    }
+
+                    if let Some(end) = read_buffer.end {
+                        return end.map(|()| 0);
+                    }
+                }
+
+                let received = self.rx.recv().await;
+                let mut read_buffer = self.read_buffer.lock();
+                match received {
+                    Ok(data) if data.is_empty() => read_buffer.end = Some(Ok(())),
+                    Ok(data) => read_buffer.data.extend(data),
+                    Err(e) => read_buffer.end = Some(Err(e)),
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TcpError {
+    // The peer reset the connection
+    Reset,
+    // The peer stopped acknowledging what we sent, or stopped answering keepalives
+    TimedOut,
+    // Nothing was sent or received for longer than the idle timeout
+    IdleTimeout,
+    // The peer closed the connection in the middle of a read_exact
+    UnexpectedEof,
+    // Write after our sending side was shut down
+    Shutdown,
+}
+
+impl From<UnexpectedEof> for TcpError {
+    fn from(_: UnexpectedEof) -> Self {
+        TcpError::UnexpectedEof
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConnectError {
+    NoAddress,
+    NoFreePorts,
+    Refused,
+    Timeout,
+}
+
+/// Snapshot of a connection, for debugging
+#[derive(Debug)]
+pub struct TcpConnectionInfo {
+    pub local_ip: IpAddr,
+    pub local_port: u16,
+    pub remote_ip: IpAddr,
+    pub remote_port: u16,
+    // Name of the RFC 9293 state
+    pub state: &'static str,
+    // Only once the handshake is done
+    pub stats: Option<TcpConnectionStats>,
+}
+
+#[derive(Debug)]
+pub struct TcpConnectionStats {
+    // Sent but not acked yet
+    pub in_flight: usize,
+    // Written but not sent yet
+    pub send_buffered: usize,
+    // Received but not read yet
+    pub unread: usize,
+    pub congestion_window: usize,
+    pub peer_window: usize,
+    // None until the first rtt sample
+    pub srtt_s: Option<f32>,
+    pub retransmits: usize,
+}
+
+#[derive(Debug)]
+pub struct TcpListenerInfo {
+    pub ip: IpAddr,
+    pub port: u16,
+    // Connections waiting to be accepted
+    pub queued: usize,
+}
+
+pub struct TcpListener {
+    rx: Receiver<TcpConnection>,
+    queued: Arc<AtomicUsize>,
+    key: TcpListenerKey,
+    listeners: Arc<SpinLock<Listeners>>,
+}
+
+impl TcpListener {
+    pub async fn connection(&self) -> TcpConnection {
+        let connection = self.rx.recv().await;
+        self.queued.fetch_sub(1, Ordering::AcqRel);
+        connection
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut listeners = self.listeners.lock();
+        // A later listen on the same address replaces us, that one stays
+        let ours = matches!(
+            listeners.get(&self.key),
+            Some(entry) if Arc::ptr_eq(&entry.queued, &self.queued)
+        );
+        if ours {
+            listeners.remove(&self.key);
+        }
+    }
+}
+
+pub struct Tcp {
+    listeners: Arc<SpinLock<Listeners>>,
+    tcp_states: Mutex<HashMap<TcpKey, TcpState>>,
+    local_ip: SpinLock<IpAddr>,
+    next_ephemeral_port: SpinLock<u16>,
+    rng: Mutex<Rng>,
+    syn_cookies: SynCookies,
+    time: Arc<MonotonicTime>,
+    service_waker: Arc<AtomicCell<Waker>>,
+    wakeup_list: WakeupRequester,
+    // Timer ticks the service has to be woken up at, registered next time it runs
+    pending_wakeups: SpinLock<Vec<usize>>,
+}
+
+impl Tcp {
+    pub fn new(time: Arc<MonotonicTime>, wakeup_list: WakeupRequester, mut rng: Rng) -> Tcp {
+        let num_ephemeral_ports = (EPHEMERAL_PORT_END - EPHEMERAL_PORT_START) as u64 + 1;
+        let first_ephemeral_port = EPHEMERAL_PORT_START + (rng.u64() % num_ephemeral_ports) as u16;
+
+        Tcp {
+            listeners: Arc::new(SpinLock::new(Default::default())),
+            tcp_states: Mutex::new(Default::default()),
+            local_ip: SpinLock::new([0; 4]),
+            next_ephemeral_port: SpinLock::new(first_ephemeral_port),
+            syn_cookies: SynCookies::new(&mut rng),
+            rng: Mutex::new(rng),
+            service_waker: Arc::new(AtomicCell::new()),
+            time,
+            wakeup_list,
+            pending_wakeups: SpinLock::new(Vec::new()),
+        }
+    }
+
+    pub async fn connections(&self) -> Vec<TcpConnectionInfo> {
+        let tcp_states = self.tcp_states.lock().await;
+        tcp_states
+            .iter()
+            .filter_map(|(key, state)| {
+                let (state, stats) = match state {
+                    TcpState::Uninit => return None,
+                    TcpState::SynSent { .. } => ("SYN-SENT", None),
+                    TcpState::SynAckSent { .. } => ("SYN-RECEIVED", None),
+                    TcpState::Connected(state) => (state.phase.name(), Some(state.stats())),
+                    TcpState::TimeWait { .. } => ("TIME-WAIT", None),
+                };
+                Some(TcpConnectionInfo {
+                    local_ip: key.local_ip,
+                    local_port: key.local_port,
+                    remote_ip: key.remote_ip,
+                    remote_port: key.remote_port,
+                    state,
+                    stats,
+                })
+            })
+            .collect()
+    }
+
+    pub fn listeners(&self) -> Vec<TcpListenerInfo> {
+        self.listeners
+            .lock()
+            .iter()
+            .map(|(key, entry)| TcpListenerInfo {
+                ip: key.ip,
+                port: key.port,
+                queued: entry.queued.load(Ordering::Acquire),
+            })
+            .collect()
+    }
+
+    /// Listen for connections to ip:port. An ip of 0.0.0.0 accepts connections to any of our
+    /// addresses. Dropping the listener stops accepting them
+    pub async fn listen(&self, ip: IpAddr, port: u16) -> TcpListener {
+        let (tx, rx) = async_channel::channel();
+        let key = TcpListenerKey { ip, port };
+        let queued = Arc::new(AtomicUsize::new(0));
+        self.listeners.lock().insert(
+            key.clone(),
+            ListenerEntry {
+                tx,
+                queued: Arc::clone(&queued),
+            },
+        );
+
+        TcpListener {
+            rx,
+            queued,
+            key,
+            listeners: Arc::clone(&self.listeners),
+        }
+    }
+
+    /// Address that outgoing connections are made from
+    pub fn set_local_ip(&self, ip: IpAddr) {
+        *self.local_ip.lock() = ip;
+    }
+
+    /// Opens a connection to remote_ip:remote_port from an ephemeral port
+    pub async fn connect(
+        &self,
+        remote_ip: IpAddr,
+        remote_port: u16,
+    ) -> Result<TcpConnection, ConnectError> {
+        let local_ip = *self.local_ip.lock();
+        if local_ip == [0; 4] {
+            return Err(ConnectError::NoAddress);
+        }
+
+        let seq_num = self.rng.lock().await.u64() as u32;
+        let (tx, rx) = oneshot::channel();
+
+        let tcp_key = {
+            let mut tcp_states = self.tcp_states.lock().await;
+            let local_port = self
+                .find_ephemeral_port(&tcp_states)
+                .ok_or(ConnectError::NoFreePorts)?;
+
+            let tcp_key = TcpKey {
+                remote_ip,
+                local_ip,
+                remote_port,
+                local_port,
+            };
+
+            let syn = generate_tcp_frame(&TcpFrameParams {
+                source_address: local_ip,
+                dest_address: remote_ip,
+                source_port: local_port,
+                dest_port: remote_port,
+                seq_num,
+                ack_num: 0,
+                flags: generate_tcp_flags(&TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: false,
+                    psh: false,
+                    rst: false,
+                    syn: true,
+                    fin: false,
+                }),
+                window_size: initial_window(),
+                urgent_ptr: 0,
+                options: syn_options(None, self.time.get()),
+                payload: Arc::new([]),
+            });
+
+            tcp_states.insert(
+                tcp_key.clone(),
+                TcpState::SynSent {
+                    seq_num,
+                    sent_frame: OutgoingTcpPacket {
+                        local_ip,
+                        remote_ip,
+                        payload: syn.into(),
+                    },
+                    send_pending: false,
+                    connection: Some(tx),
+                },
+            );
+
+            tcp_key
+        };
+
+        let mut timeout_s = SYN_TIMEOUT_S;
+        for _ in 0..=SYN_RETRIES {
+            if let Some(TcpState::SynSent { send_pending, .. }) =
+                self.tcp_states.lock().await.get_mut(&tcp_key)
+            {
+                *send_pending = true;
+            }
+            self.wake_service();
+
+            let sleep_fut = sleep::sleep(timeout_s, &self.time, &self.wakeup_list);
+            let sleep_fut = core::pin::pin!(sleep_fut);
+            let connection = core::pin::pin!(rx.recv());
+
+            match crate::future::select(connection, sleep_fut).await {
+                Either::Left((Ok(res), _)) => return res,
+                Either::Left((Err(_), _)) => break,
+                Either::Right(_) => {
+                    debug!("No syn ack from {:?} after {}s", tcp_key, timeout_s);
+                    timeout_s *= 2.0;
+                }
+            }
+        }
+
+        {
+            let mut tcp_states = self.tcp_states.lock().await;
+            if let Some(TcpState::SynSent { .. }) = tcp_states.get(&tcp_key) {
+                tcp_states.remove(&tcp_key);
+                return Err(ConnectError::Timeout);
+            }
+        }
+
+        // The syn ack came in as we were giving up, the entry belongs to the connection now and
+        // the connection is already waiting for us
+        match crate::future::poll_immediate(rx.recv()).await {
+            Some(Ok(res)) => res,
+            _ => Err(ConnectError::Timeout),
+        }
+    }
+
+    fn find_ephemeral_port(&self, tcp_states: &HashMap<TcpKey, TcpState>) -> Option<u16> {
+        let listeners = self.listeners.lock();
+        let mut next_port = self.next_ephemeral_port.lock();
+
+        for _ in EPHEMERAL_PORT_START..=EPHEMERAL_PORT_END {
+            let port = *next_port;
+            *next_port = if port == EPHEMERAL_PORT_END {
+                EPHEMERAL_PORT_START
+            } else {
+                port + 1
+            };
+
+            let in_use = listeners.keys().any(|key| key.port == port)
+                || tcp_states.keys().any(|key| key.local_port == port);
+            if !in_use {
+                return Some(port);
+            }
+        }
+
+        None
+    }
+
+    fn time_wait_expiry(&self) -> usize {
+        (self.time.get() as f32 + TIME_WAIT_S * self.time.tick_freq()) as usize
+    }
+
+    fn wake_service(&self) {
+        if let Some(service_waker) = self.service_waker.get() {
+            service_waker.wake_by_ref();
+        }
+    }
+
+    /// Finishes a handshake we answered with a syn cookie, if frame acks a valid one
+    fn syn_cookie_connection(
+        &self,
+        tcp_key: &TcpKey,
+        frame: &TcpFrame<'_>,
+    ) -> Option<(ConnectedState, TcpConnection)> {
+        let peer_isn = frame.seq_num().wrapping_sub(1);
+        let cookie = frame.ack_num().wrapping_sub(1);
+        let mss = self
+            .syn_cookies
+            .validate(tcp_key, peer_isn, cookie, &self.time)?;
+
+        // The syn ack only offered an mss, so nothing else was negotiated
+        let options = NegotiatedOptions {
+            mss: mss as usize,
+            send_window_scale: 0,
+            receive_window_scale: 0,
+            sack_permitted: false,
+            timestamps: false,
+        };
+
+        Some(ConnectedState::new(
+            frame.ack_num(),
+            frame.seq_num(),
+            frame.ack_num(),
+            frame.window_size() as usize,
+            options,
+            0,
+            self.time.get(),
+            &self.service_waker,
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn handle_frame<'a>(
+        &'a self,
+        frame: &'a TcpFrame<'_>,
+        source_ip: &'a IpAddr,
+        dest_ip: &'a IpAddr,
+    ) -> Pin<Box<dyn Future<Output = Option<Arc<[u8]>>> + 'a + Send>> {
+        Box::pin(async move {
+            let tcp_key = TcpKey {
+                remote_ip: *source_ip,
+                local_ip: *dest_ip,
+                remote_port: frame.source_port(),
+                local_port: frame.dest_port(),
+            };
+
+            let mut tcp_states = self.tcp_states.lock().await;
+            let state = tcp_states
+                .entry(tcp_key.clone())
+                .or_insert(TcpState::Uninit);
+            let flags = frame.flags();
+
+            let ret = match state {
+                TcpState::Uninit => {
+                    let listener = {
+                        let listeners = self.listeners.lock();
+                        find_listener(&listeners, dest_ip, frame.dest_port())
+                            .map(|(key, entry)| (key.clone(), entry.clone()))
+                    };
+
+                    let (listener_key, listener) = match listener {
+                        Some(v) => v,
+                        None => {
+                            debug!("Segment for unknown connection {:?}, resetting", tcp_key);
+                            tcp_states.remove(&tcp_key);
+                            return generate_tcp_reset(frame, source_ip, dest_ip);
+                        }
+                    };
+
+                    if flags.ack() && !flags.syn() && !flags.rst() {
+                        // Could be the end of a handshake we answered with a syn cookie
+                        if let Some((connected_state, connection)) =
+                            self.syn_cookie_connection(&tcp_key, frame)
+                        {
+                            if listener.queue_full() {
+                                debug!("Accept queue full, dropping ack for {:?}", tcp_key);
+                                tcp_states.remove(&tcp_key);
+                                return None;
+                            }
+
+                            debug!("Connection {:?} established with a syn cookie", tcp_key);
+                            tcp_states
+                                .insert(tcp_key, TcpState::Connected(Box::new(connected_state)));
+                            listener.push(connection).await;
+                            return None;
+                        }
+                    }
+
+                    if !flags.syn() || flags.ack() {
+                        debug!("Segment for unknown connection {:?}, resetting", tcp_key);
+                        tcp_states.remove(&tcp_key);
+                        return generate_tcp_reset(frame, source_ip, dest_ip);
+                    }
+
+                    let peer_options = frame.options();
+
+                    let half_open = tcp_states
+                        .values()
+                        .filter(|state| {
+                            matches!(
+                                state,
+                                TcpState::SynAckSent { listener, .. } if *listener == listener_key
+                            )
+                        })
+                        .count();
+                    if half_open >= LISTEN_BACKLOG {
+                        debug!("Backlog full, answering {:?} with a syn cookie", tcp_key);
+                        tcp_states.remove(&tcp_key);
+                        let peer_mss = peer_options.mss.map_or(DEFAULT_MSS, usize::from);
+                        let (seq_num, mss) = self.syn_cookies.generate(
+                            &tcp_key,
+                            frame.seq_num(),
+                            peer_mss,
+                            &self.time,
+                        );
+                        // Nothing else we agree on could be remembered
+                        let response_options = TcpOptions {
+                            mss: Some(mss),
+                            ..Default::default()
+                        };
+                        return Some(generate_syn_ack(
+                            frame,
+                            source_ip,
+                            dest_ip,
+                            seq_num,
+                            response_options,
+                        ));
+                    }
+
+                    let seq_num = self.rng.lock().await.u64() as u32;
+                    let ack_num = frame.seq_num().wrapping_add(1);
+                    let response_options = syn_options(Some(&peer_options), self.time.get());
+                    let options =
+                        NegotiatedOptions::negotiate(&response_options, &peer_options, DEFAULT_MSS);
+                    let response_frame =
+                        generate_syn_ack(frame, source_ip, dest_ip, seq_num, response_options);
+
+                    let sent_frame = OutgoingTcpPacket {
+                        local_ip: *dest_ip,
+                        remote_ip: *source_ip,
+                        payload: Arc::clone(&response_frame),
+                    };
+
+                    let timeout = (self.time.get() as f32
+                        + SYN_ACK_TIMEOUT_S * self.time.tick_freq())
+                        as usize;
+                    self.wakeup_list.register_wakeup_time(timeout).await;
+                    tcp_states.insert(
+                        tcp_key,
+                        TcpState::SynAckSent {
+                            seq_num,
+                            ack_num,
+                            sent_frame,
+                            timeout,
+                            retries: 0,
+                            options,
+                            listener: listener_key,
+                        },
+                    );
+
+                    Some(response_frame)
+                }
+                TcpState::SynSent {
+                    seq_num,
+                    connection,
+                    ..
+                } => {
+                    let expected_ack = seq_num.wrapping_add(1);
+
+                    if flags.rst() {
+                        if flags.ack() && frame.ack_num() == expected_ack {
+                            debug!("Connection to {:?} refused", tcp_key);
+                            let connection = connection.take();
+                            tcp_states.remove(&tcp_key);
+                            if let Some(connection) = connection {
+                                connection.send(Err(ConnectError::Refused)).await;
+                            }
+                        }
+                        return None;
+                    }
+
+                    if !flags.syn() || !flags.ack() {
+                        debug!("Expected syn ack, ignoring");
+                        return None;
+                    }
+
+                    if frame.ack_num() != expected_ack {
+                        debug!(
+                            "Unexpected ack num in syn ack: expected {}, got {}",
+                            expected_ack,
+                            frame.ack_num()
+                        );
+                        return None;
+                    }
+
+                    let peer_options = frame.options();
+                    let options = NegotiatedOptions::negotiate(
+                        &syn_options(None, 0),
+                        &peer_options,
+                        DEFAULT_MSS,
+                    );
+                    let ts_recent = peer_options.timestamps.map_or(0, |t| t.value);
+
+                    let outgoing_ack_num = frame.seq_num().wrapping_add(1);
+                    let (mut connected_state, new_connection) = ConnectedState::new(
+                        expected_ack,
+                        outgoing_ack_num,
+                        frame.ack_num(),
+                        // Windows in syns are never scaled
+                        frame.window_size() as usize,
+                        options,
+                        ts_recent,
+                        self.time.get(),
+                        &self.service_waker,
+                    );
+                    let response_frame =
+                        generate_tcp_ack(&tcp_key, &mut connected_state, self.time.get());
+                    let connection = connection.take();
+                    *state = TcpState::Connected(Box::new(connected_state));
+
+                    if let Some(connection) = connection {
+                        connection.send(Ok(new_connection)).await;
+                    }
+
+                    Some(response_frame)
+                }
+                TcpState::SynAckSent {
+                    ack_num,
+                    seq_num,
+                    options,
+                    listener,
+                    ..
+                } => {
+                    if flags.rst() {
+                        if frame.seq_num() == *ack_num {
+                            debug!("Connection {:?} reset before it was established", tcp_key);
+                            tcp_states.remove(&tcp_key);
+                        }
+                        return None;
+                    }
+
+                    if flags.syn() {
+                        debug!("Resetting connection, unexpected syn");
+                        *state = TcpState::Uninit;
+                        drop(tcp_states);
+                        return self.handle_frame(frame, source_ip, dest_ip).await;
+                    }
+
+                    if !flags.ack() {
+                        debug!("Ack unset, ignoring");
+                        return None;
+                    }
+
+                    if frame.seq_num() != *ack_num {
+                        error!(
+                            "Sequence number not expected in syn ack: ack_num: {}, seq_num: {}",
+                            ack_num,
+                            frame.seq_num()
+                        );
+                        return None;
+                    }
+
+                    // Acks something other than our syn ack, nothing to build a connection on
+                    // (RFC 9293 3.10.7.4)
+                    if frame.ack_num() != seq_num.wrapping_add(1) {
+                        debug!(
+                            "Unacceptable ack in handshake for {:?}: expected {}, got {}",
+                            tcp_key,
+                            seq_num.wrapping_add(1),
+                            frame.ack_num()
+                        );
+                        return generate_tcp_reset(frame, source_ip, dest_ip);
+                    }
+
+                    let listener = self.listeners.lock().get(listener).cloned();
+                    let listener = match listener {
+                        Some(x) => x,
+                        None => {
+                            debug!("Listener for {:?} closed, resetting", tcp_key);
+                            tcp_states.remove(&tcp_key);
+                            return generate_tcp_reset(frame, source_ip, dest_ip);
+                        }
+                    };
+
+                    if listener.queue_full() {
+                        // The syn ack gets resent, so the peer will try again
+                        debug!("Accept queue full, ignoring ack for {:?}", tcp_key);
+                        return None;
+                    }
+
+                    let ts_recent = frame.options().timestamps.map_or(0, |t| t.value);
+                    let (connected_state, connection) = ConnectedState::new(
+                        seq_num.wrapping_add(1),
+                        *ack_num,
+                        frame.ack_num(),
+                        (frame.window_size() as usize) << options.send_window_scale,
+                        *options,
+                        ts_recent,
+                        self.time.get(),
+                        &self.service_waker,
+                    );
+                    *state = TcpState::Connected(Box::new(connected_state));
+
+                    listener.push(connection).await;
+
+                    // Data or a fin riding on the ack goes through the same in order delivery
+                    // as everything after it
+                    if frame.segment_length() > 0 {
+                        drop(tcp_states);
+                        return self.handle_frame(frame, source_ip, dest_ip).await;
+                    }
+
+                    None
+                }
+                TcpState::Connected(ref mut connected) => {
+                    if flags.rst() {
+                        if frame.seq_num() == connected.outgoing_ack_num {
+                            debug!("Connection {:?} reset by peer", tcp_key);
+                            *connected.error.lock() = Some(TcpError::Reset);
+                            connected.tx.send(Err(TcpError::Reset)).await;
+                            tcp_states.remove(&tcp_key);
+                        }
+                        return None;
+                    }
+
+                    let now = self.time.get();
+
+                    // A syn has no place in a synchronized connection, whatever its sequence
+                    // number. Answer with a challenge ack and drop it (RFC 9293 3.10.7.4)
+                    if flags.syn() {
+                        debug!("Unexpected syn on {:?}, sending challenge ack", tcp_key);
+                        return Some(generate_tcp_ack(&tcp_key, connected, now));
+                    }
+
+                    // Anything at all shows the peer is still there
+                    connected.last_received = now;
+                    connected.keepalive_probes = 0;
+                    let options = frame.options();
+                    let timestamps = options.timestamps.filter(|_| connected.options.timestamps);
+
+                    // Anything older than the last timestamp we've seen is a stray segment from
+                    // a previous wrap of the sequence space (PAWS, RFC 7323 5)
+                    if matches!(timestamps, Some(t) if seq_lt(t.value, connected.ts_recent)) {
+                        debug!("Dropping segment with an old timestamp on {:?}", tcp_key);
+                        return Some(generate_tcp_ack(&tcp_key, connected, now));
+                    }
+
+                    let next_seq = connected.outgoing_ack_num;
+                    let seq = frame.seq_num();
+                    let segment_length = frame.segment_length();
+
+                    // Nothing we haven't seen before. Retransmissions after a lost ack,
+                    // keepalives and window probes all end up here, and all of them want to
+                    // hear where we're at
+                    let old = if segment_length == 0 {
+                        seq_lt(seq, next_seq)
+                    } else {
+                        seq_le(seq.wrapping_add(segment_length), next_seq)
+                    };
+                    if old {
+                        return Some(generate_tcp_ack(&tcp_key, connected, now));
+                    }
+
+                    // Nobody is left to read it, so the peer has to find out it's going nowhere
+                    // (RFC 9293 3.10.7.4)
+                    if !frame.payload().is_empty() && connected.orphaned.load(Ordering::Acquire) {
+                        debug!("Data for closed connection {:?}, resetting", tcp_key);
+                        let reset = generate_connection_reset(&tcp_key, connected);
+                        tcp_states.remove(&tcp_key);
+                        return Some(reset);
+                    }
+
+                    if let Some(timestamps) = timestamps {
+                        if seq_le(seq, next_seq) {
+                            connected.ts_recent = timestamps.value;
+                        }
+                    }
+
+                    connected.handle_ack(frame, &options, &self.time, &self.pending_wakeups);
+
+                    connected.window_size = connected.peer_window(frame);
+                    if connected.window_size > 0 {
+                        connected.persist_timeout = None;
+                        connected.zero_window_probes = 0;
+                    }
+
+                    let fin_acked = connected.fin_seq.map(|fin_seq| fin_seq.wrapping_add(1))
+                        == Some(frame.ack_num());
+
+                    let (data_seq, data) = if seq_lt(seq, next_seq) {
+                        let already_have = next_seq.wrapping_sub(seq) as usize;
+                        (next_seq, frame.payload().get(already_have..).unwrap_or(&[]))
+                    } else {
+                        (seq, frame.payload())
+                    };
+
+                    // Out of order data takes up receive buffer too. Anything past the buffer
+                    // gets dropped, the peer resends it once the application has made room
+                    let offset = data_seq.wrapping_sub(next_seq) as usize;
+                    let buffer_space = RECEIVE_BUFFER_SIZE
+                        .saturating_sub(connected.unread_bytes.load(Ordering::Acquire));
+                    let accepted = buffer_space.saturating_sub(offset).min(data.len());
+                    let truncated = accepted < data.len();
+                    if truncated {
+                        debug!(
+                            "Receive buffer full, dropping {} bytes",
+                            data.len() - accepted
+                        );
+                    }
+                    let data = &data[..accepted];
+
+                    if flags.fin() && !truncated {
+                        connected
+                            .out_of_order
+                            .set_fin(data_seq.wrapping_add(data.len() as u32));
+                    }
+
+                    let mut delivered = Vec::new();
+                    if data_seq == next_seq {
+                        delivered.extend_from_slice(data);
+                        connected.outgoing_ack_num = next_seq.wrapping_add(data.len() as u32);
+
+                        // The segment might have filled a hole, whatever was queued up behind it
+                        // can go too
+                        while let Some(queued) =
+                            connected.out_of_order.pop(connected.outgoing_ack_num)
+                        {
+                            connected.outgoing_ack_num =
+                                connected.outgoing_ack_num.wrapping_add(queued.len() as u32);
+                            delivered.extend_from_slice(&queued);
+                        }
+                    } else {
+                        debug!(
+                            "Queueing out of order segment: expected {}, got {}",
+                            next_seq, data_seq
+                        );
+                        connected.out_of_order.insert(next_seq, data_seq, data);
+                    }
+
+                    let fin = connected
+                        .out_of_order
+                        .fin_reached(connected.outgoing_ack_num);
+                    if fin {
+                        connected.outgoing_ack_num = connected.outgoing_ack_num.wrapping_add(1);
+                    }
+
+                    if !delivered.is_empty() {
+                        connected.last_data = now;
+                        connected
+                            .unread_bytes
+                            .fetch_add(delivered.len(), Ordering::AcqRel);
+                        connected.tx.send(Ok(delivered)).await;
+                    }
+
+                    if fin {
+                        // Empty read tells the application that the peer is done sending
+                        connected.tx.send(Ok(Vec::new())).await;
+                    }
+
+                    let response_frame = generate_tcp_ack(&tcp_key, connected, now);
+
+                    connected.phase = match (connected.phase, fin_acked, fin) {
+                        (ConnectionPhase::Established, _, true) => ConnectionPhase::CloseWait,
+                        (ConnectionPhase::FinWait1, true, false) => ConnectionPhase::FinWait2,
+                        (ConnectionPhase::FinWait1, false, true) => ConnectionPhase::Closing,
+                        (ConnectionPhase::FinWait1, true, true)
+                        | (ConnectionPhase::FinWait2, _, true)
+                        | (ConnectionPhase::Closing, true, _) => {
+                            debug!("Connection {:?} entering time wait", tcp_key);
+                            let expiry = self.time_wait_expiry();
+                            self.wakeup_list.register_wakeup_time(expiry).await;
+                            *state = TcpState::TimeWait {
+                                expiry,
+                                ack: Arc::clone(&response_frame),
+                            };
+                            return fin.then_some(response_frame);
+                        }
+                        (ConnectionPhase::LastAck, true, _) => {
+                            debug!("Connection {:?} closed", tcp_key);
+                            tcp_states.remove(&tcp_key);
+                            return None;
+                        }
+                        (phase, _, _) => phase,
+                    };
+
+                    // Every segment carrying data gets acked right away, out of order ones with a
+                    // duplicate ack so the peer's fast retransmit can kick in (RFC 5681 4.2)
+                    if segment_length > 0 || truncated {
+                        return Some(response_frame);
+                    }
+
+                    None
+                }
+                TcpState::TimeWait { expiry, ack } => {
+                    // Resets are ignored here to protect against time wait assassination (RFC 1337)
+                    if flags.fin() && !flags.rst() {
+                        // Our last ack got lost, send it again and give the peer the full time
+                        // wait to see it
+                        *expiry = self.time_wait_expiry();
+                        self.wakeup_list.register_wakeup_time(*expiry).await;
+                        return Some(Arc::clone(ack));
+                    }
+
+                    None
+                }
+            };
+
+            self.wake_service();
+
+            ret
+        })
+    }
+
+    pub async fn service(&self) -> OutgoingTcpPacket {
+        loop {
+            let wakeups = core::mem::take(&mut *self.pending_wakeups.lock());
+            for tick in wakeups {
+                self.wakeup_list.register_wakeup_time(tick).await;
+            }
+
+            let event = OutgoingPoller {
+                tcp_states: &self.tcp_states,
+                time: &self.time,
+                waker: &self.service_waker,
+                pending_wakeups: &self.pending_wakeups,
+            }
+            .await;
+
+            match event {
+                PollerEvent::Send(packet) => return packet,
+                PollerEvent::Abort { tx, error, reset } => {
+                    tx.send(Err(error)).await;
+                    if let Some(reset) = reset {
+                        return reset;
+                    }
+                }
+            }
+        }
+    }
+}
+
+enum PollerEvent {
+    Send(OutgoingTcpPacket),
+    Abort {
+        tx: Sender<Result<Vec<u8>, TcpError>>,
+        error: TcpError,
+        reset: Option<OutgoingTcpPacket>,
+    },
+}
+
+struct OutgoingPoller<'a> {
+    tcp_states: &'a Mutex<HashMap<TcpKey, TcpState>>,
+    time: &'a MonotonicTime,
+    waker: &'a AtomicCell<Waker>,
+    pending_wakeups: &'a SpinLock<Vec<usize>>,
+}
+
+impl Future for OutgoingPoller<'_> {
+    type Output = PollerEvent;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.waker.store(cx.waker().clone());
+
+        let guard = core::pin::pin!(self.tcp_states.lock()).poll(cx);
+
+        let mut guard = match guard {
+            Poll::Ready(v) => v,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let now = self.time.get();
+        guard.retain(|tcp_key, tcp_state| match tcp_state {
+            TcpState::TimeWait { expiry, .. } if *expiry <= now => {
+                debug!("Connection {:?} closed after time wait", tcp_key);
+                false
+            }
+            TcpState::SynAckSent {
+                timeout, retries, ..
+            } if *retries >= SYN_RETRIES && *timeout < now => {
+                debug!("No ack for syn ack to {:?}, dropping", tcp_key);
+                false
+            }
+            _ => true,
+        });
+
+        let mut aborted = None;
+        // Orphaned connections to tear down with a reset
+        let mut orphan_reset = None;
+
+        for (tcp_key, tcp_state) in &mut *guard {
+            match tcp_state {
+                TcpState::Connected(connection) => {
+                    let timeouts = *connection.timeouts.lock();
+                    let tick_freq = self.time.tick_freq();
+
+                    if connection.orphaned.load(Ordering::Acquire) {
+                        // Whatever is waiting will never be read, the peer has to know it was
+                        // lost (RFC 2525 2.17)
+                        if connection.unread_bytes.load(Ordering::Acquire) > 0 {
+                            debug!("Connection {:?} closed with unread data", tcp_key);
+                            orphan_reset = Some(tcp_key.clone());
+                            break;
+                        }
+
+                        // A peer that never sends its fin would keep us here forever
+                        if connection.phase == ConnectionPhase::FinWait2 {
+                            let timeout = *connection
+                                .fin_wait2_timeout
+                                .get_or_insert(now + (FIN_WAIT2_TIMEOUT_S * tick_freq) as usize);
+                            if now >= timeout {
+                                debug!("No fin from {:?} after closing", tcp_key);
+                                orphan_reset = Some(tcp_key.clone());
+                                break;
+                            }
+                            connection.schedule_timer(timeout, now, self.pending_wakeups);
+                        }
+                    }
+
+                    if let Some(idle_timeout_s) = timeouts.idle_timeout_s {
+                        let deadline = connection.last_data + (idle_timeout_s * tick_freq) as usize;
+                        if now >= deadline {
+                            aborted = Some((tcp_key.clone(), TcpError::IdleTimeout));
+                            break;
+                        }
+                        connection.schedule_timer(deadline, now, self.pending_wakeups);
+                    }
+
+                    // Lost data is the retransmission timer's business, probes are only for
+                    // quiet connections
+                    if let (Some(keepalive), true) =
+                        (timeouts.keepalive, connection.unacknowledged.is_empty())
+                    {
+                        let quiet_s = keepalive.idle_s
+                            + keepalive.interval_s * connection.keepalive_probes as f32;
+                        let next_probe = connection.last_received + (quiet_s * tick_freq) as usize;
+                        if now >= next_probe {
+                            if connection.keepalive_probes >= keepalive.probes {
+                                aborted = Some((tcp_key.clone(), TcpError::TimedOut));
+                                break;
+                            }
+
+                            debug!("Sending keepalive probe to {:?}", tcp_key);
+                            connection.keepalive_probes += 1;
+                            let next_probe = now + (keepalive.interval_s * tick_freq) as usize;
+                            connection.schedule_timer(next_probe, now, self.pending_wakeups);
+                            return Poll::Ready(PollerEvent::Send(OutgoingTcpPacket {
+                                local_ip: tcp_key.local_ip,
+                                remote_ip: tcp_key.remote_ip,
+                                payload: generate_keepalive_probe(tcp_key, connection, now),
+                            }));
+                        }
+                        connection.schedule_timer(next_probe, now, self.pending_wakeups);
+                    }
+
+                    if matches!(connection.retransmit_timeout, Some(timeout) if now >= timeout) {
+                        if connection.retransmits >= MAX_RETRANSMITS {
+                            aborted = Some((tcp_key.clone(), TcpError::TimedOut));
+                            break;
+                        }
+
+                        connection.retransmits += 1;
+                        connection.rtt.backoff();
+                        let in_flight = connection.in_flight();
+                        connection.congestion.on_retransmit_timeout(in_flight);
+                        connection.restart_retransmit_timer(self.time, self.pending_wakeups);
+
+                        // The peer is allowed to drop data it sacked, so after a timeout we
+                        // can't count on it anymore (RFC 2018 8)
+                        for packet in &mut connection.unacknowledged {
+                            packet.sacked = false;
+                        }
+
+                        if !connection.unacknowledged.is_empty() {
+                            debug!(
+                                "Retransmission timeout for {:?}, rto now {}s",
+                                tcp_key,
+                                connection.rtt.rto_s()
+                            );
+                            return Poll::Ready(PollerEvent::Send(generate_retransmission(
+                                tcp_key, connection, 0, now,
+                            )));
+                        }
+                    }
+
+                    if connection.fast_retransmit {
+                        connection.fast_retransmit = false;
+                        if !connection.unacknowledged.is_empty() {
+                            debug!("Fast retransmit for {:?}", tcp_key);
+                            return Poll::Ready(PollerEvent::Send(generate_retransmission(
+                                tcp_key, connection, 0, now,
+                            )));
+                        }
+                    }
+
+                    if connection.send_window_available() > 0 {
+                        if let Some(index) = connection.next_sack_hole() {
+                            debug!(
+                                "Resending segment {} sacks show lost for {:?}",
+                                index, tcp_key
+                            );
+                            return Poll::Ready(PollerEvent::Send(generate_retransmission(
+                                tcp_key, connection, index, now,
+                            )));
+                        }
+                    }
+
+                    while let Poll::Ready(data) = core::pin::pin!(connection.rx.recv()).poll(cx) {
+                        connection.send_buffer.extend(data.iter());
+                    }
+
+                    if let Some(params) = next_segment(tcp_key, connection, now) {
+                        connection.last_data = now;
+                        return Poll::Ready(PollerEvent::Send(write_request_to_outgoing_packet(
+                            tcp_key,
+                            connection,
+                            self.time,
+                            self.pending_wakeups,
+                            params,
+                        )));
+                    }
+
+                    // With nothing in flight no ack is coming to reopen the window, so we have
+                    // to keep asking (RFC 1122 4.2.2.17)
+                    let window_closed = !connection.send_buffer.is_empty()
+                        && connection.unacknowledged.is_empty()
+                        && connection.send_window_available() == 0;
+
+                    if !window_closed {
+                        connection.persist_timeout = None;
+                    } else if connection.persist_timeout.is_none() {
+                        connection.restart_persist_timer(self.time, self.pending_wakeups);
+                    } else if matches!(connection.persist_timeout, Some(timeout) if now >= timeout)
+                    {
+                        debug!("Probing zero window of {:?}", tcp_key);
+                        connection.zero_window_probes += 1;
+                        connection.restart_persist_timer(self.time, self.pending_wakeups);
+                        return Poll::Ready(PollerEvent::Send(OutgoingTcpPacket {
+                            local_ip: tcp_key.local_ip,
+                            remote_ip: tcp_key.remote_ip,
+                            payload: generate_window_probe(tcp_key, connection, now),
+                        }));
+                    }
+
+                    if connection.needs_window_update() {
+                        return Poll::Ready(PollerEvent::Send(OutgoingTcpPacket {
+                            local_ip: tcp_key.local_ip,
+                            remote_ip: tcp_key.remote_ip,
+                            payload: generate_tcp_ack(tcp_key, connection, now),
+                        }));
+                    }
+                }
+                TcpState::SynAckSent {
+                    ref mut timeout,
+                    retries,
+                    sent_frame,
+                    ..
+                } => {
+                    if now > *timeout {
+                        *retries += 1;
+                        *timeout += (self.time.tick_freq() * SYN_ACK_TIMEOUT_S) as usize;
+                        self.pending_wakeups.lock().push(*timeout);
+                        return Poll::Ready(PollerEvent::Send(sent_frame.clone()));
+                    }
+                }
+                TcpState::SynSent {
+                    send_pending,
+                    sent_frame,
+                    ..
+                } => {
+                    if *send_pending {
+                        *send_pending = false;
+                        return Poll::Ready(PollerEvent::Send(sent_frame.clone()));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(tcp_key) = orphan_reset {
+            if let Some(TcpState::Connected(connection)) = guard.remove(&tcp_key) {
+                return Poll::Ready(PollerEvent::Send(OutgoingTcpPacket {
+                    local_ip: tcp_key.local_ip,
+                    remote_ip: tcp_key.remote_ip,
+                    payload: generate_connection_reset(&tcp_key, &connection),
+                }));
+            }
+        }
+
+        if let Some((tcp_key, error)) = aborted {
+            warn!("Aborting connection {:?}: {:?}", tcp_key, error);
+            if let Some(TcpState::Connected(connection)) = guard.remove(&tcp_key) {
+                *connection.error.lock() = Some(error);
+                // A peer that stopped answering won't hear it, an idle one should
+                let reset = (error == TcpError::IdleTimeout).then(|| OutgoingTcpPacket {
+                    local_ip: tcp_key.local_ip,
+                    remote_ip: tcp_key.remote_ip,
+                    payload: generate_connection_reset(&tcp_key, &connection),
+                });
+                return Poll::Ready(PollerEvent::Abort {
+                    tx: connection.tx.clone(),
+                    error,
+                    reset,
+                });
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Cuts the next segment that fits in the peer's window and the mss off the send buffer, or
+/// sends our fin once everything has been sent
+fn next_segment(
+    tcp_key: &TcpKey,
+    connection: &mut ConnectedState,
+    now: usize,
+) -> Option<TcpFrameParams> {
+    if !connection.send_buffer.is_empty() {
+        let mss = connection.options.max_payload();
+        let length = connection
+            .send_buffer
+            .len()
+            .min(mss)
+            .min(connection.send_window_available());
+        if length == 0 {
+            return None;
+        }
+
+        // Nagle's algorithm (RFC 896). While anything is unacked, small segments wait for more
+        // data or for the ack. Shutting down flushes whatever is left
+        let delay = length < mss
+            && !connection.unacknowledged.is_empty()
+            && !connection.nodelay.load(Ordering::Acquire)
+            && !connection.write_closed.load(Ordering::Acquire);
+        if delay {
+            return None;
+        }
+
+        let data = connection.send_buffer.drain(..length).collect();
+
+        // FIXME: Hidden mutation of connected state
+        return Some(generate_tcp_push(tcp_key, connection, data, now));
+    }
+
+    if connection.fin_seq.is_none() && connection.write_closed.load(Ordering::Acquire) {
+        connection.phase = match connection.phase {
+            ConnectionPhase::Established => ConnectionPhase::FinWait1,
+            ConnectionPhase::CloseWait => ConnectionPhase::LastAck,
+            phase => phase,
+        };
+        return Some(generate_tcp_fin(tcp_key, connection, now));
+    }
+
+    None
+}
+
+fn write_request_to_outgoing_packet(
+    tcp_key: &TcpKey,
+    connected_state: &mut ConnectedState,
+    time: &MonotonicTime,
+    wakeups: &SpinLock<Vec<usize>>,
+    params: TcpFrameParams,
+) -> OutgoingTcpPacket {
+    let payload = generate_tcp_frame(&params).into();
+    connected_state.unacknowledged.push_back(UnackedPacket {
+        timestamp: time.get(),
+        retransmitted: false,
+        sacked: false,
+        params,
+    });
+
+    if connected_state.retransmit_timeout.is_none() {
+        connected_state.restart_retransmit_timer(time, wakeups);
+    }
+
+    OutgoingTcpPacket {
+        local_ip: tcp_key.local_ip,
+        remote_ip: tcp_key.remote_ip,
+        payload,
+    }
+}
+
+/// Resends an unacked segment. The data stays the same, but the ack, window and timestamp are
+/// brought up to date
+fn generate_retransmission(
+    tcp_key: &TcpKey,
+    state: &mut ConnectedState,
+    index: usize,
+    now: usize,
+) -> OutgoingTcpPacket {
+    let ack_num = state.outgoing_ack_num;
+    let window_size = state.advertise_window();
+    let options = state.segment_options(now);
+
+    let packet = &mut state.unacknowledged[index];
+    packet.retransmitted = true;
+    packet.params.ack_num = ack_num;
+    packet.params.window_size = window_size;
+    packet.params.options = options;
+
+    OutgoingTcpPacket {
+        local_ip: tcp_key.local_ip,
+        remote_ip: tcp_key.remote_ip,
+        payload: generate_tcp_frame(&packet.params).into(),
+    }
+}
+
+#[derive(Clone)]
+pub struct OutgoingTcpPacket {
+    pub local_ip: IpAddr,
+    pub remote_ip: IpAddr,
+    pub payload: Arc<[u8]>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use crate::MonotonicTime;
+    use alloc::{
+        format,
+        string::{String, ToString},
+        vec,
+    };
+
+    struct TcpFixture {
+        time: Arc<MonotonicTime>,
+        tcp: Tcp,
+    }
+
+    fn tcp_frame(data: &[u8]) -> TcpFrame<'_> {
+        TcpFrame::new(data).expect("invalid tcp frame")
+    }
+
+    fn gen_fixture() -> TcpFixture {
+        let time = Arc::new(MonotonicTime::new(10.0));
+        let (wakeup_list, _, _) = crate::sleep::construct_wakeup_handlers();
+        let tcp = Tcp::new(Arc::clone(&time), wakeup_list, Rng::new(0));
+
+        TcpFixture { time, tcp }
+    }
+
+    async fn read_some(connection: &TcpConnection) -> Result<Vec<u8>, TcpError> {
+        let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+        let n = connection.read(&mut buf).await?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    struct MockClient {
+        client_ip: IpAddr,
+        server_ip: IpAddr,
+        client_port: u16,
+        server_port: u16,
+        window_size: u16,
+        seq: u32,
+        ack: u32,
+        // Sent with every segment
+        options: TcpOptions,
+    }
+
+    impl MockClient {
+        fn syn(&mut self) -> Arc<[u8]> {
+            let ret = generate_tcp_frame(&TcpFrameParams {
+                source_address: self.client_ip,
+                dest_address: self.server_ip,
+                source_port: self.client_port,
+                dest_port: self.server_port,
+                seq_num: self.seq,
+                // Syn should always have ack 0 as we have nothing to ack
+                ack_num: 0,
+                flags: generate_tcp_flags(&TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: false,
+                    psh: false,
+                    rst: false,
+                    syn: true,
+                    fin: false,
+                }),
+                window_size: self.window_size,
+                urgent_ptr: 0,
+                options: self.options.clone(),
+                payload: Arc::new([]),
+            })
+            .into();
+            self.seq = self.seq.wrapping_add(1);
+            ret
+        }
+
+        fn ack(&self) -> Arc<[u8]> {
+            generate_tcp_frame(&TcpFrameParams {
+                source_address: self.client_ip,
+                dest_address: self.server_ip,
+                source_port: self.client_port,
+                dest_port: self.server_port,
+                seq_num: self.seq,
+                ack_num: self.ack,
+                flags: generate_tcp_flags(&TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: true,
+                    psh: false,
+                    rst: false,
+                    syn: false,
+                    fin: false,
+                }),
+                window_size: self.window_size,
+                urgent_ptr: 0,
+                options: self.options.clone(),
+                payload: Arc::new([]),
+            })
+            .into()
+        }
+
+        async fn handshake(&mut self, fixture: &TcpFixture) -> Result<(), String> {
+            let syn = self.syn();
+
+            let syn_ack = match fixture
+                .tcp
+                .handle_frame(&tcp_frame(&syn), &self.client_ip, &self.server_ip)
+                .await
+            {
+                Some(v) => v,
+                None => {
+                    return Err("No syn ack for syn".into());
+                }
+            };
+
+            self.handle_frame(&syn_ack);
+
+            let ack = self.ack();
+
+            let response = fixture
+                .tcp
+                .handle_frame(&tcp_frame(&ack), &self.client_ip, &self.server_ip)
+                .await;
+
+            test_true!(response.is_none());
+
+            Ok(())
+        }
+
+        fn frame(&self, flags: TcpFlagsParams, payload: &[u8]) -> Arc<[u8]> {
+            generate_tcp_frame(&TcpFrameParams {
+                source_address: self.client_ip,
+                dest_address: self.server_ip,
+                source_port: self.client_port,
+                dest_port: self.server_port,
+                seq_num: self.seq,
+                ack_num: self.ack,
+                flags: generate_tcp_flags(&flags),
+                window_size: self.window_size,
+                urgent_ptr: 0,
+                options: self.options.clone(),
+                payload: payload.into(),
+            })
+            .into()
+        }
+
+        fn push(&mut self, data: &[u8]) -> Arc<[u8]> {
+            let ret = self.frame(
+                TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: true,
+                    psh: true,
+                    rst: false,
+                    syn: false,
+                    fin: false,
+                },
+                data,
+            );
+            self.seq = self.seq.wrapping_add(data.len() as u32);
+            ret
+        }
+
+        fn fin(&mut self) -> Arc<[u8]> {
+            let ret = self.frame(
+                TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: true,
+                    psh: false,
+                    rst: false,
+                    syn: false,
+                    fin: true,
+                },
+                &[],
+            );
+            self.seq = self.seq.wrapping_add(1);
+            ret
+        }
+
+        fn rst(&self) -> Arc<[u8]> {
+            self.frame(
+                TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: false,
+                    psh: false,
+                    rst: true,
+                    syn: false,
+                    fin: false,
+                },
+                &[],
+            )
+        }
+
+        fn handle_frame(&mut self, buf: &[u8]) {
+            let frame = tcp_frame(buf);
+            let seq = frame.seq_num();
+
+            if frame.flags().syn() || self.ack == seq {
+                self.ack = seq.wrapping_add(frame.segment_length());
+            }
+        }
+    }
+
+    create_test!(test_tcp_frame_parsing, {
+        const TCP_SYN: &[u8] = &[
+            0x80, 0xd8, 0x17, 0x70, 0x5a, 0x5b, 0x14, 0x47, 0x00, 0x00, 0x00, 0x00, 0xa0, 0x02,
+            0xfa, 0xf0, 0x7e, 0xa4, 0x00, 0x00, 0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a,
+            0x41, 0xcf, 0x00, 0x5d, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+        ];
+
+        let frame = tcp_frame(TCP_SYN);
+        test_eq!(frame.source_port(), 32984);
+        test_eq!(frame.dest_port(), 6000);
+        test_eq!(frame.seq_num(), 1515918407);
+        test_eq!(frame.ack_num(), 0);
+        test_eq!(frame.data_offset_bytes(), 40);
+        test_eq!(frame.flags().0, 2);
+        test_eq!(frame.window_size(), 64240);
+        test_eq!(frame.checksum(), 0x7ea4);
+        test_eq!(frame.urgent_ptr(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_flags_back_and_forth, {
+        let flags = generate_tcp_flags(&TcpFlagsParams {
+            cwr: true,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh: false,
+            rst: true,
+            syn: true,
+            fin: true,
+        });
+
+        test_eq!(flags.cwr(), true);
+        test_eq!(flags.ece(), false);
+        test_eq!(flags.urg(), false);
+        test_eq!(flags.ack(), true);
+        test_eq!(flags.psh(), false);
+        test_eq!(flags.rst(), true);
+        test_eq!(flags.syn(), true);
+        test_eq!(flags.fin(), true);
+
+        Ok(())
+    });
+
+    create_test!(test_dropped_syn_ack_ack, {
+        const TCP_SYN: &[u8] = b"\x89\x06\x27\x0f\xcc\x6b\x38\x32\x00\x00\x00\x00\xa0\x02\xfa\xf0\x22\xb5\x00\x00\x02\x04\x05\xb4\x04\x02\x08\x0a\xc3\x8b\x2c\xc7\x00\x00\x00\x00\x01\x03\x03\x07";
+        const TCP_ACK: &[u8] =
+            b"\x89\x06\x27\x0f\xcc\x6b\x38\x33\x00\x39\x84\x21\x50\x10\xfa\xf0\xf6\x80\x00\x00";
+        const SOURCE_IP: IpAddr = [192, 168, 2, 1];
+        const DEST_IP: IpAddr = [192, 168, 2, 2];
+
+        let fixture = gen_fixture();
+
+        let listener = fixture.tcp.listen(DEST_IP, 9999).await;
+
+        let frame = tcp_frame(TCP_SYN);
+        fixture.tcp.handle_frame(&frame, &SOURCE_IP, &DEST_IP).await;
+
+        // We should get a syn-ack response from the initial syn
+        if crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_some()
+        {
+            return Err("Unexpected serivce response".into());
+        }
+
+        // After 2 seconds we should have waited enough to trigger a syn-ack resend
+        fixture
+            .time
+            .set_tick((fixture.time.tick_freq() * 2.0) as usize);
+
+        let syn_ack = match crate::future::poll_immediate(fixture.tcp.service()).await {
+            Some(v) => v,
+            None => return Err("Syn ack retransmit missing".into()),
+        };
+
+        let syn_ack = tcp_frame(&syn_ack.payload);
+
+        test_true!(syn_ack.flags().syn());
+        test_true!(syn_ack.flags().ack());
+
+        let frame = tcp_frame(TCP_ACK);
+        fixture.tcp.handle_frame(&frame, &SOURCE_IP, &DEST_IP).await;
+
+        if crate::future::poll_immediate(listener.connection())
+            .await
+            .is_none()
+        {
+            return Err("Connection not ready".into());
+        }
+
+        Ok(())
+    });
+
+    create_test!(test_dup_ack_retransmission, {
+        const CLIENT_IP: IpAddr = [192, 168, 2, 1];
+        const SERVER_IP: IpAddr = [192, 168, 2, 2];
+        const CLIENT_PORT: u16 = 1234;
+        const SERVER_PORT: u16 = 5678;
+
+        let fixture = gen_fixture();
+
+        let listener = fixture.tcp.listen(SERVER_IP, SERVER_PORT).await;
+
+        let mut mock_client = MockClient {
+            client_ip: CLIENT_IP,
+            server_ip: SERVER_IP,
+            client_port: CLIENT_PORT,
+            server_port: SERVER_PORT,
+            window_size: 5000,
+            seq: 150,
+            ack: 0,
+            options: TcpOptions::default(),
+        };
+
+        mock_client.handshake(&fixture).await?;
+
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        test_ok!(connection.write(Arc::<str>::from("hello world")).await);
+
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("tcp service did not return a value".to_string())?;
+
+        mock_client.handle_frame(&frame.payload);
+
+        let frame = tcp_frame(&frame.payload);
+        test_eq!(frame.payload(), b"hello world");
+
+        let data1_ack = mock_client.ack();
+
+        let response = fixture
+            .tcp
+            .handle_frame(&tcp_frame(&data1_ack), &CLIENT_IP, &SERVER_IP)
+            .await;
+
+        test_true!(response.is_none());
+
+        // Written after the ack, otherwise it would have been coalesced with the first write
+        test_ok!(connection.write(Arc::<str>::from("hello world 2")).await);
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("tcp service did not return a value".to_string())?;
+
+        let frame = tcp_frame(&frame.payload);
+        test_eq!(frame.payload(), b"hello world 2");
+
+        // Intentionally do not inform the mock of the second frame
+
+        // After we've sent first two packets, nothing to do
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        for i in 0..3 {
+            // Two duplicates aren't enough to call the segment lost
+            if i == 2 {
+                test_true!(crate::future::poll_immediate(fixture.tcp.service())
+                    .await
+                    .is_none());
+            }
+
+            // ACK first segment 3 more times
+            let response = fixture
+                .tcp
+                .handle_frame(&tcp_frame(&data1_ack), &CLIENT_IP, &SERVER_IP)
+                .await;
+            test_true!(response.is_none());
+        }
+
+        // 3 duplicate acks, retransmission please
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or("tcp service did not return a value".to_string())?;
+        let frame = tcp_frame(&frame.payload);
+        test_eq!(frame.payload(), b"hello world 2");
+
+        Ok(())
+    });
+
+    fn gen_syn_ack_response(syn: &[u8], flags: TcpFlagsParams) -> Arc<[u8]> {
+        let syn = tcp_frame(syn);
+        generate_tcp_frame(&TcpFrameParams {
+            source_address: [192, 168, 2, 1],
+            dest_address: [192, 168, 2, 2],
+            source_port: syn.dest_port(),
+            dest_port: syn.source_port(),
+            seq_num: 1000,
+            ack_num: syn.seq_num().wrapping_add(1),
+            flags: generate_tcp_flags(&flags),
+            window_size: 5000,
+            urgent_ptr: 0,
+            options: TcpOptions::default(),
+            payload: Arc::new([]),
+        })
+        .into()
+    }
+
+    create_test!(test_tcp_connect, {
+        const LOCAL_IP: IpAddr = [192, 168, 2, 2];
+        const REMOTE_IP: IpAddr = [192, 168, 2, 1];
+
+        let fixture = gen_fixture();
+        test_true!(matches!(
+            fixture.tcp.connect(REMOTE_IP, 80).await,
+            Err(ConnectError::NoAddress)
+        ));
+
+        fixture.tcp.set_local_ip(LOCAL_IP);
+
+        let connect = core::pin::pin!(fixture.tcp.connect(REMOTE_IP, 80));
+        let server = core::pin::pin!(async {
+            let syn = fixture.tcp.service().await;
+            test_eq!(syn.local_ip, LOCAL_IP);
+            test_eq!(syn.remote_ip, REMOTE_IP);
+
+            let syn_frame = tcp_frame(&syn.payload);
+            test_true!(syn_frame.flags().syn());
+            test_false!(syn_frame.flags().ack());
+            test_eq!(syn_frame.dest_port(), 80);
+            test_ge!(syn_frame.source_port(), EPHEMERAL_PORT_START);
+
+            let syn_ack = gen_syn_ack_response(
+                &syn.payload,
+                TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: true,
+                    psh: false,
+                    rst: false,
+                    syn: true,
+                    fin: false,
+                },
+            );
+
+            let ack = fixture
+                .tcp
+                .handle_frame(&tcp_frame(&syn_ack), &REMOTE_IP, &LOCAL_IP)
+                .await
+                .ok_or_else(|| "Syn ack was not acked".to_string())?;
+            let ack = tcp_frame(&ack);
+            test_true!(ack.flags().ack());
+            test_false!(ack.flags().syn());
+            test_eq!(ack.ack_num(), 1001);
+            test_eq!(ack.seq_num(), syn_frame.seq_num().wrapping_add(1));
+
+            core::future::pending::<Result<(), String>>().await
+        });
+
+        let connection = match crate::future::select(connect, server).await {
+            Either::Left((connection, _)) => connection.map_err(|e| format!("{:?}", e))?,
+            Either::Right((e, _)) => return e,
+        };
+
+        test_ok!(connection.write(Arc::<str>::from("hello")).await);
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Write was not sent".to_string())?;
+        test_eq!(tcp_frame(&frame.payload).payload(), b"hello");
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_connect_refused, {
+        const LOCAL_IP: IpAddr = [192, 168, 2, 2];
+        const REMOTE_IP: IpAddr = [192, 168, 2, 1];
+
+        let fixture = gen_fixture();
+        fixture.tcp.set_local_ip(LOCAL_IP);
+
+        let connect = core::pin::pin!(fixture.tcp.connect(REMOTE_IP, 80));
+        let server = core::pin::pin!(async {
+            let syn = fixture.tcp.service().await;
+            let rst = gen_syn_ack_response(
+                &syn.payload,
+                TcpFlagsParams {
+                    cwr: false,
+                    ece: false,
+                    urg: false,
+                    ack: true,
+                    psh: false,
+                    rst: true,
+                    syn: false,
+                    fin: false,
+                },
+            );
+
+            let response = fixture
+                .tcp
+                .handle_frame(&tcp_frame(&rst), &REMOTE_IP, &LOCAL_IP)
+                .await;
+            test_true!(response.is_none());
+
+            core::future::pending::<Result<(), String>>().await
+        });
+
+        match crate::future::select(connect, server).await {
+            Either::Left((res, _)) => {
+                test_true!(matches!(res, Err(ConnectError::Refused)));
+            }
+            Either::Right((e, _)) => return e,
+        }
+
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    fn gen_mock_client(server_port: u16) -> MockClient {
+        MockClient {
+            client_ip: [192, 168, 2, 1],
+            server_ip: [192, 168, 2, 2],
+            client_port: 1234,
+            server_port,
+            window_size: 5000,
+            seq: 150,
+            ack: 0,
+            options: TcpOptions::default(),
+        }
+    }
+
+    async fn handle_mock_frame(
+        fixture: &TcpFixture,
+        mock_client: &MockClient,
+        frame: &[u8],
+    ) -> Option<Arc<[u8]>> {
+        fixture
+            .tcp
+            .handle_frame(
+                &tcp_frame(frame),
+                &mock_client.client_ip,
+                &mock_client.server_ip,
+            )
+            .await
+    }
+
+    create_test!(test_tcp_passive_close, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        let fin = mock_client.fin();
+        let ack = handle_mock_frame(&fixture, &mock_client, &fin)
+            .await
+            .ok_or_else(|| "Fin was not acked".to_string())?;
+        test_eq!(tcp_frame(&ack).ack_num(), mock_client.seq);
+
+        // Eof, and it stays that way
+        test_eq!(read_some(&connection).await.ok(), Some(b"".to_vec()));
+        test_eq!(read_some(&connection).await.ok(), Some(b"".to_vec()));
+
+        // Our side is still open
+        test_ok!(connection.write(Arc::<str>::from("bye")).await);
+        let data = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Write after peer fin was not sent".to_string())?;
+        test_eq!(tcp_frame(&data.payload).payload(), b"bye");
+        mock_client.handle_frame(&data.payload);
+
+        connection.close();
+        let fin = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Fin not sent on close".to_string())?;
+        test_true!(tcp_frame(&fin.payload).flags().fin());
+        mock_client.handle_frame(&fin.payload);
+
+        let response = handle_mock_frame(&fixture, &mock_client, &mock_client.ack()).await;
+        test_true!(response.is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_sequence_wrap, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        // Data and fin both cross the end of the sequence space
+        mock_client.seq = u32::MAX - 3;
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        let push = mock_client.push(b"hello");
+        test_true!(handle_mock_frame(&fixture, &mock_client, &push)
+            .await
+            .is_some());
+        test_eq!(read_some(&connection).await.ok(), Some(b"hello".to_vec()));
+
+        let fin = mock_client.fin();
+        let ack = handle_mock_frame(&fixture, &mock_client, &fin)
+            .await
+            .ok_or_else(|| "Fin was not acked".to_string())?;
+        test_eq!(tcp_frame(&ack).ack_num(), mock_client.seq);
+        test_eq!(read_some(&connection).await.ok(), Some(b"".to_vec()));
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_connection_info, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+        mock_client.handshake(&fixture).await?;
+
+        let listeners = fixture.tcp.listeners();
+        test_eq!(listeners.len(), 1);
+        test_eq!(listeners[0].port, 80);
+        test_eq!(listeners[0].queued, 1);
+
+        let connections = fixture.tcp.connections().await;
+        test_eq!(connections.len(), 1);
+        test_eq!(connections[0].state, "ESTABLISHED");
+        test_eq!(connections[0].remote_ip, mock_client.client_ip);
+        test_eq!(connections[0].remote_port, mock_client.client_port);
+        test_eq!(connections[0].local_port, 80);
+        test_true!(connections[0].stats.is_some());
+
+        drop(listener);
+        test_true!(fixture.tcp.listeners().is_empty());
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_stream_reads, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        for segment in [&b"GET / HTTP/1.1\r"[..], b"\nbody", b"123"] {
+            let push = mock_client.push(segment);
+            test_true!(handle_mock_frame(&fixture, &mock_client, &push)
+                .await
+                .is_some());
+        }
+
+        // Lines span segments, and whatever is past the delimiter is kept for the next read
+        let mut line = Vec::new();
+        test_eq!(connection.read_until(b'\n', &mut line).await.ok(), Some(16));
+        test_eq!(line, b"GET / HTTP/1.1\r\n");
+
+        let mut buf = [0; 2];
+        test_eq!(connection.read(&mut buf).await.ok(), Some(2));
+        test_eq!(&buf, b"bo");
+
+        let mut buf = [0; 5];
+        test_ok!(connection.read_exact(&mut buf).await);
+        test_eq!(&buf, b"dy123");
+
+        // Data that came before the reset can still be read
+        let push = mock_client.push(b"end");
+        test_true!(handle_mock_frame(&fixture, &mock_client, &push)
+            .await
+            .is_some());
+        test_true!(
+            handle_mock_frame(&fixture, &mock_client, &mock_client.rst())
+                .await
+                .is_none()
+        );
+
+        let mut buf = [0; 4];
+        test_eq!(
+            connection.read_exact(&mut buf).await.err(),
+            Some(TcpError::Reset)
+        );
+        test_eq!(read_some(&connection).await.err(), Some(TcpError::Reset));
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_active_close, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        connection.shutdown();
+        let written = connection.write(b"too late".to_vec()).await;
+        test_eq!(written.err(), Some(TcpError::Shutdown));
+        let fin = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Fin not sent on shutdown".to_string())?;
+        test_true!(tcp_frame(&fin.payload).flags().fin());
+        mock_client.handle_frame(&fin.payload);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        let response = handle_mock_frame(&fixture, &mock_client, &mock_client.ack()).await;
+        test_true!(response.is_none());
+
+        // Half closed, the peer can keep sending
+        let push = mock_client.push(b"still here");
+        test_true!(handle_mock_frame(&fixture, &mock_client, &push)
+            .await
+            .is_some());
+        test_eq!(
+            read_some(&connection).await.ok(),
+            Some(b"still here".to_vec())
+        );
+
+        let fin = mock_client.fin();
+        let ack = handle_mock_frame(&fixture, &mock_client, &fin)
+            .await
+            .ok_or_else(|| "Fin was not acked".to_string())?;
+        test_eq!(tcp_frame(&ack).ack_num(), mock_client.seq);
+        test_eq!(read_some(&connection).await.ok(), Some(b"".to_vec()));
+
+        // A retransmitted fin is acked again from time wait
+        test_true!(handle_mock_frame(&fixture, &mock_client, &fin)
+            .await
+            .is_some());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 1);
+
+        fixture
+            .time
+            .set_tick((TIME_WAIT_S * fixture.time.tick_freq()) as usize + 1);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_close_unread, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        let push = mock_client.push(b"never read");
+        test_true!(handle_mock_frame(&fixture, &mock_client, &push)
+            .await
+            .is_some());
+
+        // Reset instead of a fin, the peer shouldn't think the data got anywhere
+        connection.close();
+        let rst = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "No reset on close with unread data".to_string())?;
+        let rst = tcp_frame(&rst.payload);
+        test_true!(rst.flags().rst());
+        test_false!(rst.flags().fin());
+        test_eq!(rst.seq_num(), mock_client.ack);
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        // Same once data shows up after a clean close
+        let mut mock_client = gen_mock_client(80);
+        mock_client.client_port += 1;
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Second connection not ready".to_string())?;
+
+        connection.close();
+        let fin = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Fin not sent on close".to_string())?;
+        test_true!(tcp_frame(&fin.payload).flags().fin());
+        mock_client.handle_frame(&fin.payload);
+        test_true!(
+            handle_mock_frame(&fixture, &mock_client, &mock_client.ack())
+                .await
+                .is_none()
+        );
+
+        let push = mock_client.push(b"anyone there?");
+        let rst = handle_mock_frame(&fixture, &mock_client, &push)
+            .await
+            .ok_or_else(|| "No reset for data after close".to_string())?;
+        test_true!(tcp_frame(&rst).flags().rst());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_orphan_fin_wait2, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        connection.close();
+        let fin = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Fin not sent on close".to_string())?;
+        mock_client.handle_frame(&fin.payload);
+        test_true!(
+            handle_mock_frame(&fixture, &mock_client, &mock_client.ack())
+                .await
+                .is_none()
+        );
+
+        // The peer acked our fin but never sends its own
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 1);
+
+        fixture
+            .time
+            .set_tick((FIN_WAIT2_TIMEOUT_S * fixture.time.tick_freq()) as usize + 1);
+        let rst = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Orphan not dropped after fin wait 2 timeout".to_string())?;
+        test_true!(tcp_frame(&rst.payload).flags().rst());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_reset, {
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen([192, 168, 2, 2], 80).await;
+
+        // Nobody listening on the port
+        let mut mock_client = gen_mock_client(81);
+        let syn = mock_client.syn();
+        let rst = handle_mock_frame(&fixture, &mock_client, &syn)
+            .await
+            .ok_or_else(|| "Syn to closed port not reset".to_string())?;
+        let rst = tcp_frame(&rst);
+        test_true!(rst.flags().rst());
+        test_true!(rst.flags().ack());
+        test_eq!(rst.ack_num(), mock_client.seq);
+
+        // Ack for a connection that doesn't exist
+        mock_client.ack = 1234;
+        let rst = handle_mock_frame(&fixture, &mock_client, &mock_client.ack())
+            .await
+            .ok_or_else(|| "Stray ack not reset".to_string())?;
+        let rst = tcp_frame(&rst);
+        test_true!(rst.flags().rst());
+        test_false!(rst.flags().ack());
+        test_eq!(rst.seq_num(), 1234);
+
+        // Never reset a reset
+        test_true!(
+            handle_mock_frame(&fixture, &mock_client, &mock_client.rst())
+                .await
+                .is_none()
+        );
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        let mut mock_client = gen_mock_client(80);
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        let response = handle_mock_frame(&fixture, &mock_client, &mock_client.rst()).await;
+        test_true!(response.is_none());
+        test_eq!(read_some(&connection).await.err(), Some(TcpError::Reset));
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_rtt_estimator, {
+        let mut rtt = RttEstimator::new();
+        test_eq!(rtt.rto_s(), INITIAL_RTO_S);
+
+        rtt.sample(1.0, 0.1);
+        test_eq!(rtt.srtt, 1.0);
+        test_eq!(rtt.rttvar, 0.5);
+        test_eq!(rtt.rto_s(), 3.0);
+
+        rtt.sample(1.0, 0.1);
+        test_eq!(rtt.srtt, 1.0);
+        test_eq!(rtt.rttvar, 0.375);
+        test_eq!(rtt.rto_s(), 2.5);
+
+        rtt.backoff();
+        test_eq!(rtt.rto_s(), 5.0);
+        for _ in 0..10 {
+            rtt.backoff();
+        }
+        test_eq!(rtt.rto_s(), MAX_RTO_S);
+
+        // Tiny rtts are held up by the minimum
+        rtt.sample(0.001, 0.001);
+        rtt.sample(0.001, 0.001);
+        test_eq!(rtt.rto_s(), MIN_RTO_S);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_retransmission_timeout, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        test_ok!(connection.write(Arc::<str>::from("hello")).await);
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Write was not sent".to_string())?;
+        mock_client.handle_frame(&frame.payload);
+
+        // Ack after 0.2s gives us our first rtt sample
+        fixture.time.set_tick(2);
+        handle_mock_frame(&fixture, &mock_client, &mock_client.ack()).await;
+        match fixture.tcp.tcp_states.lock().await.values().next() {
+            Some(TcpState::Connected(state)) => {
+                test_eq!(state.rtt.srtt, 0.2);
+                test_true!(state.retransmit_timeout.is_none());
+            }
+            _ => return Err("Connection missing".into()),
+        }
+
+        // Never acked, so it gets resent with a backed off timer until we give up
+        test_ok!(connection.write(Arc::<str>::from("hello again")).await);
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Write was not sent".to_string())?;
+        let seq_num = tcp_frame(&frame.payload).seq_num();
+
+        let mut tick = fixture.time.get();
+        let mut last_rto = 0.0;
+        for _ in 0..MAX_RETRANSMITS {
+            test_true!(crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .is_none());
+
+            tick += (MAX_RTO_S * fixture.time.tick_freq()) as usize + 1;
+            fixture.time.set_tick(tick);
+
+            let frame = crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or_else(|| "Segment was not retransmitted".to_string())?;
+            let frame = tcp_frame(&frame.payload);
+            test_eq!(frame.seq_num(), seq_num);
+            test_eq!(frame.payload(), b"hello again");
+
+            let rto = match fixture.tcp.tcp_states.lock().await.values().next() {
+                Some(TcpState::Connected(state)) => state.rtt.rto_s(),
+                _ => return Err("Connection missing".into()),
+            };
+            test_true!(rto > last_rto || rto == MAX_RTO_S);
+            last_rto = rto;
+        }
+
+        tick += (MAX_RTO_S * fixture.time.tick_freq()) as usize + 1;
+        fixture.time.set_tick(tick);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+        test_eq!(read_some(&connection).await.err(), Some(TcpError::TimedOut));
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_send_window, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        mock_client.window_size = 8;
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        test_ok!(connection.write(Arc::<str>::from("hello world")).await);
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Write was not sent".to_string())?;
+        test_eq!(tcp_frame(&frame.payload).payload(), b"hello wo");
+        mock_client.handle_frame(&frame.payload);
+
+        // Window is full
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        // Peer takes the data but has no room for more
+        mock_client.window_size = 0;
+        handle_mock_frame(&fixture, &mock_client, &mock_client.ack()).await;
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        fixture
+            .time
+            .set_tick((INITIAL_RTO_S * fixture.time.tick_freq()) as usize + 1);
+        let probe = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Zero window was not probed".to_string())?;
+        let probe = tcp_frame(&probe.payload);
+        test_eq!(probe.payload().len(), 0);
+        test_eq!(probe.seq_num(), mock_client.ack.wrapping_sub(1));
+
+        // The probe's answer opens the window back up
+        mock_client.window_size = 100;
+        handle_mock_frame(&fixture, &mock_client, &mock_client.ack()).await;
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Rest of the write was not sent".to_string())?;
+        let frame = tcp_frame(&frame.payload);
+        test_eq!(frame.payload(), b"rld");
+        test_eq!(frame.seq_num(), mock_client.ack);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_send_buffer, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        test_ok!(connection.write(vec![0u8; SEND_BUFFER_SIZE]).await);
+
+        // Buffer is full until the peer acks something
+        let more = || connection.write(Arc::<str>::from("more"));
+        test_true!(crate::future::poll_immediate(more()).await.is_none());
+
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Write was not sent".to_string())?;
+        mock_client.handle_frame(&frame.payload);
+        handle_mock_frame(&fixture, &mock_client, &mock_client.ack()).await;
+        test_true!(matches!(
+            crate::future::poll_immediate(more()).await,
+            Some(Ok(()))
+        ));
+
+        // Writers waiting on a full buffer hear about a reset
+        test_ok!(connection.write(vec![0u8; SEND_BUFFER_SIZE]).await);
+        let mut blocked = Box::pin(more());
+        test_true!(crate::future::poll_immediate(&mut blocked).await.is_none());
+        handle_mock_frame(&fixture, &mock_client, &mock_client.rst()).await;
+        let written = blocked.await;
+        test_eq!(written.err(), Some(TcpError::Reset));
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_receive_window, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        const CHUNK_SIZE: usize = 1024;
+        let chunk = [0xaa; CHUNK_SIZE];
+        let mut last_window = RECEIVE_BUFFER_SIZE;
+        for _ in 0..RECEIVE_BUFFER_SIZE / CHUNK_SIZE {
+            let push = mock_client.push(&chunk);
+            let ack = handle_mock_frame(&fixture, &mock_client, &push)
+                .await
+                .ok_or_else(|| "Push was not acked".to_string())?;
+            let ack = tcp_frame(&ack);
+            test_eq!(ack.ack_num(), mock_client.seq);
+            test_eq!(ack.window_size() as usize, last_window - CHUNK_SIZE);
+            last_window = ack.window_size() as usize;
+        }
+        test_eq!(last_window, 0);
+
+        // No room left, so the data isn't acked
+        let acked_seq = mock_client.seq;
+        let push = mock_client.push(&chunk);
+        let ack = handle_mock_frame(&fixture, &mock_client, &push)
+            .await
+            .ok_or_else(|| "Push past window not answered".to_string())?;
+        test_eq!(tcp_frame(&ack).ack_num(), acked_seq);
+
+        // Reading makes room, which the peer is told about
+        let mut buf = [0; CHUNK_SIZE];
+        test_eq!(connection.read(&mut buf).await.ok(), Some(CHUNK_SIZE));
+        let update = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "No window update after read".to_string())?;
+        test_eq!(
+            tcp_frame(&update.payload).window_size() as usize,
+            CHUNK_SIZE
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_option_negotiation, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        mock_client.window_size = 10;
+        mock_client.options = TcpOptions {
+            mss: Some(100),
+            window_scale: Some(2),
+            sack_permitted: true,
+            sack_blocks: Vec::new(),
+            timestamps: Some(Timestamps {
+                value: 1000,
+                echo_reply: 0,
+            }),
+        };
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        let syn = mock_client.syn();
+        let syn_ack = handle_mock_frame(&fixture, &mock_client, &syn)
+            .await
+            .ok_or_else(|| "No syn ack for syn".to_string())?;
+        mock_client.handle_frame(&syn_ack);
+
+        let syn_ack_options = tcp_frame(&syn_ack).options();
+        test_eq!(syn_ack_options.mss, Some(LOCAL_MSS as u16));
+        test_eq!(syn_ack_options.window_scale, Some(RECEIVE_WINDOW_SCALE));
+        test_true!(syn_ack_options.sack_permitted);
+        test_eq!(syn_ack_options.timestamps.map(|t| t.echo_reply), Some(1000));
+
+        mock_client.options.timestamps = Some(Timestamps {
+            value: 1001,
+            echo_reply: 0,
+        });
+        test_true!(
+            handle_mock_frame(&fixture, &mock_client, &mock_client.ack())
+                .await
+                .is_none()
+        );
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        // Windows after the handshake are scaled, 10 << 2
+        test_ok!(connection.write([0xaa; 200].as_slice()).await);
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Write was not sent".to_string())?;
+        mock_client.handle_frame(&frame.payload);
+        let frame = tcp_frame(&frame.payload);
+        test_eq!(frame.payload().len(), 40);
+        test_eq!(frame.options().timestamps.map(|t| t.echo_reply), Some(1001));
+
+        // With the window out of the way the peer's mss, less our timestamps, is the limit
+        mock_client.window_size = 100;
+        handle_mock_frame(&fixture, &mock_client, &mock_client.ack()).await;
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Rest of the write was not sent".to_string())?;
+        test_eq!(
+            tcp_frame(&frame.payload).payload().len(),
+            100 - options::TIMESTAMPS_LENGTH
+        );
+
+        // Timestamps older than the last one we saw are from a stray segment (PAWS)
+        mock_client.options.timestamps = Some(Timestamps {
+            value: 999,
+            echo_reply: 0,
+        });
+        let acked_seq = mock_client.seq;
+        let push = mock_client.push(b"stale");
+        let ack = handle_mock_frame(&fixture, &mock_client, &push)
+            .await
+            .ok_or_else(|| "Stale segment not answered".to_string())?;
+        test_eq!(tcp_frame(&ack).ack_num(), acked_seq);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_sack_retransmission, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        mock_client.options = TcpOptions {
+            mss: Some(100),
+            sack_permitted: true,
+            ..Default::default()
+        };
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        // Initial congestion window is 4 segments
+        test_ok!(connection.write([0xaa; 400].as_slice()).await);
+        for i in 0..4 {
+            let frame = crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or_else(|| format!("Segment {} was not sent", i))?;
+            test_eq!(tcp_frame(&frame.payload).payload().len(), 100);
+        }
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        // Segments 1 and 3 got lost
+        let start = mock_client.ack;
+        let block = |segment: u32| SackBlock {
+            start: start + segment * 100,
+            end: start + (segment + 1) * 100,
+        };
+        let sacks = [
+            alloc::vec![block(1)],
+            alloc::vec![block(1), block(3)],
+            alloc::vec![block(1), block(3)],
+        ];
+        for sack_blocks in sacks {
+            // The window is full, so the hole sacks reveal has to wait
+            test_true!(crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .is_none());
+            mock_client.options.sack_blocks = sack_blocks;
+            test_true!(
+                handle_mock_frame(&fixture, &mock_client, &mock_client.ack())
+                    .await
+                    .is_none()
+            );
+        }
+
+        // Fast retransmit of the first hole makes room for the second one, without waiting for
+        // a partial ack
+        for expected_seq in [start, start + 200] {
+            let frame = crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or_else(|| "Hole was not resent".to_string())?;
+            test_eq!(tcp_frame(&frame.payload).seq_num(), expected_seq);
+        }
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_segmentation_and_nagle, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        // The mock doesn't give an mss, so we're at the default
+        test_ok!(connection.write([0xaa; 1000].as_slice()).await);
+        test_ok!(connection.write([0xbb; 500].as_slice()).await);
+        let mut sent = Vec::new();
+        for _ in 0..2 {
+            let frame = crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .ok_or_else(|| "Full segment was not sent".to_string())?;
+            mock_client.handle_frame(&frame.payload);
+            let frame = tcp_frame(&frame.payload);
+            test_eq!(frame.payload().len(), DEFAULT_MSS);
+            sent.extend_from_slice(frame.payload());
+        }
+
+        // The small tail waits for the data in flight to be acked
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        handle_mock_frame(&fixture, &mock_client, &mock_client.ack()).await;
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Tail was not sent after the ack".to_string())?;
+        let frame = tcp_frame(&frame.payload);
+        test_eq!(frame.payload().len(), 1500 - 2 * DEFAULT_MSS);
+        test_true!(frame.flags().psh());
+        sent.extend_from_slice(frame.payload());
+
+        let mut expected = [0xaa; 1500];
+        expected[1000..].fill(0xbb);
+        test_eq!(sent.as_slice(), expected.as_slice());
+
+        // Unless Nagle is off
+        connection.set_nodelay(true);
+        test_ok!(connection.write(Arc::<str>::from("a")).await);
+        test_ok!(connection.write(Arc::<str>::from("b")).await);
+        let frame = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "Small write was held back".to_string())?;
+        test_eq!(tcp_frame(&frame.payload).payload(), b"ab");
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_out_of_order_receive, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        mock_client.options.sack_permitted = true;
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        // No push flag, the data still has to make it to the application
+        let ack_flags = || TcpFlagsParams {
+            cwr: false,
+            ece: false,
+            urg: false,
+            ack: true,
+            psh: false,
+            rst: false,
+            syn: false,
+            fin: false,
+        };
+        async fn send_at(
+            fixture: &TcpFixture,
+            mock_client: &mut MockClient,
+            seq: u32,
+            flags: TcpFlagsParams,
+            payload: &[u8],
+        ) -> Result<(u32, Vec<SackBlock>), String> {
+            mock_client.seq = seq;
+            let frame = mock_client.frame(flags, payload);
+            let ack = handle_mock_frame(fixture, mock_client, &frame)
+                .await
+                .ok_or_else(|| "Segment was not acked".to_string())?;
+            let ack = tcp_frame(&ack);
+            Ok((ack.ack_num(), ack.options().sack_blocks))
+        }
+
+        let start = mock_client.seq;
+
+        // Out of order segments get a duplicate ack, sacking what we have
+        let (ack_num, sack_blocks) =
+            send_at(&fixture, &mut mock_client, start + 6, ack_flags(), b"world").await?;
+        test_eq!(ack_num, start);
+        test_eq!(
+            sack_blocks,
+            [SackBlock {
+                start: start + 6,
+                end: start + 11,
+            }]
+        );
+
+        let fin_flags = TcpFlagsParams {
+            fin: true,
+            ..ack_flags()
+        };
+        let (ack_num, sack_blocks) =
+            send_at(&fixture, &mut mock_client, start + 14, fin_flags, b"").await?;
+        test_eq!(ack_num, start);
+        test_eq!(sack_blocks.len(), 1);
+
+        // Filling the hole delivers everything queued behind it
+        let (ack_num, sack_blocks) =
+            send_at(&fixture, &mut mock_client, start, ack_flags(), b"hello ").await?;
+        test_eq!(ack_num, start + 11);
+        test_true!(sack_blocks.is_empty());
+        test_eq!(
+            read_some(&connection).await.ok(),
+            Some(b"hello world".to_vec())
+        );
+
+        // A syn in an established connection only gets a challenge ack, its data and fin are
+        // dropped
+        let syn_flags = TcpFlagsParams {
+            syn: true,
+            fin: true,
+            ..ack_flags()
+        };
+        let (ack_num, _) = send_at(&fixture, &mut mock_client, start + 9, syn_flags, b"ld").await?;
+        test_eq!(ack_num, start + 11);
+
+        // Overlaps what we have, and reaches the fin that came early
+        let (ack_num, _) =
+            send_at(&fixture, &mut mock_client, start + 9, ack_flags(), b"ld!!!").await?;
+        test_eq!(ack_num, start + 15);
+        test_eq!(read_some(&connection).await.ok(), Some(b"!!!".to_vec()));
+        test_eq!(read_some(&connection).await.ok(), Some(b"".to_vec()));
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_data_on_handshake_ack, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        let syn = mock_client.syn();
+        let syn_ack = handle_mock_frame(&fixture, &mock_client, &syn)
+            .await
+            .ok_or_else(|| "No syn ack".to_string())?;
+        mock_client.handle_frame(&syn_ack);
+
+        // The ack finishing the handshake carries data, with the push flag set
+        let push = mock_client.push(b"hello");
+        let ack = handle_mock_frame(&fixture, &mock_client, &push)
+            .await
+            .ok_or_else(|| "Data on handshake ack was not acked".to_string())?;
+        test_eq!(tcp_frame(&ack).ack_num(), mock_client.seq);
+
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+        test_eq!(read_some(&connection).await.ok(), Some(b"hello".to_vec()));
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_bad_handshake_ack, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        let syn = mock_client.syn();
+        let syn_ack = handle_mock_frame(&fixture, &mock_client, &syn)
+            .await
+            .ok_or_else(|| "No syn ack".to_string())?;
+        mock_client.handle_frame(&syn_ack);
+
+        // Acks data we never sent
+        let expected_ack = mock_client.ack;
+        mock_client.ack = expected_ack.wrapping_add(1000);
+        let rst = handle_mock_frame(&fixture, &mock_client, &mock_client.ack())
+            .await
+            .ok_or_else(|| "Bad handshake ack not reset".to_string())?;
+        let rst = tcp_frame(&rst);
+        test_true!(rst.flags().rst());
+        test_eq!(rst.seq_num(), mock_client.ack);
+        test_true!(crate::future::poll_immediate(listener.connection())
+            .await
+            .is_none());
+        test_true!(matches!(
+            fixture.tcp.tcp_states.lock().await.values().next(),
+            Some(TcpState::SynAckSent { .. })
+        ));
+
+        // The handshake can still finish with the right ack
+        mock_client.ack = expected_ack;
+        let response = handle_mock_frame(&fixture, &mock_client, &mock_client.ack()).await;
+        test_true!(response.is_none());
+        test_true!(crate::future::poll_immediate(listener.connection())
+            .await
+            .is_some());
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_listener_drop, {
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen([0; 4], 80).await;
+        let replaced = fixture.tcp.listen([0; 4], 81).await;
+        let replacement = fixture.tcp.listen([0; 4], 81).await;
+
+        // A replaced listener going away leaves the new one alone
+        drop(replaced);
+        test_eq!(fixture.tcp.listeners.lock().len(), 2);
+        drop(replacement);
+        test_eq!(fixture.tcp.listeners.lock().len(), 1);
+
+        // Half open when the listener goes, so the handshake can't finish
+        let mut mock_client = gen_mock_client(80);
+        let syn = mock_client.syn();
+        let syn_ack = handle_mock_frame(&fixture, &mock_client, &syn)
+            .await
+            .ok_or_else(|| "No syn ack".to_string())?;
+        mock_client.handle_frame(&syn_ack);
+        drop(listener);
+        test_true!(fixture.tcp.listeners.lock().is_empty());
+
+        let rst = handle_mock_frame(&fixture, &mock_client, &mock_client.ack())
+            .await
+            .ok_or_else(|| "Handshake for closed listener not reset".to_string())?;
+        test_true!(tcp_frame(&rst).flags().rst());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_syn_max_isn, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        mock_client.seq = u32::MAX;
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        let syn = mock_client.syn();
+        let syn_ack = handle_mock_frame(&fixture, &mock_client, &syn)
+            .await
+            .ok_or_else(|| "No syn ack".to_string())?;
+        test_eq!(tcp_frame(&syn_ack).ack_num(), 0);
+        mock_client.handle_frame(&syn_ack);
+
+        test_true!(
+            handle_mock_frame(&fixture, &mock_client, &mock_client.ack())
+                .await
+                .is_none()
+        );
+        test_true!(crate::future::poll_immediate(listener.connection())
+            .await
+            .is_some());
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_syn_flood, {
+        let fixture = gen_fixture();
+        let listener = fixture.tcp.listen([0; 4], 80).await;
+
+        for port in 0..LISTEN_BACKLOG {
+            let mut mock_client = gen_mock_client(80);
+            mock_client.client_port = 1000 + port as u16;
+            let syn = mock_client.syn();
+            handle_mock_frame(&fixture, &mock_client, &syn)
+                .await
+                .ok_or_else(|| "No syn ack".to_string())?;
+        }
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), LISTEN_BACKLOG);
+
+        // Past the backlog syns are still answered, but nothing is stored for them
+        let mut mock_client = gen_mock_client(80);
+        mock_client.options.timestamps = Some(Timestamps {
+            value: 1,
+            echo_reply: 0,
+        });
+        let syn = mock_client.syn();
+        let syn_ack = handle_mock_frame(&fixture, &mock_client, &syn)
+            .await
+            .ok_or_else(|| "No syn ack past the backlog".to_string())?;
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), LISTEN_BACKLOG);
+
+        let frame = tcp_frame(&syn_ack);
+        test_true!(frame.flags().syn());
+        test_eq!(frame.ack_num(), mock_client.seq);
+        let options = frame.options();
+        test_eq!(options.mss, Some(DEFAULT_MSS as u16));
+        test_true!(options.timestamps.is_none());
+        mock_client.handle_frame(&syn_ack);
+        mock_client.options = TcpOptions::default();
+
+        // A forged cookie is reset, the real one gets us a connection
+        let mut forged = gen_mock_client(80);
+        forged.seq = mock_client.seq;
+        forged.ack = mock_client.ack.wrapping_add(1);
+        let rst = handle_mock_frame(&fixture, &forged, &forged.ack())
+            .await
+            .ok_or_else(|| "Forged cookie not reset".to_string())?;
+        test_true!(tcp_frame(&rst).flags().rst());
+
+        test_true!(
+            handle_mock_frame(&fixture, &mock_client, &mock_client.ack())
+                .await
+                .is_none()
+        );
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Syn cookie connection not accepted".to_string())?;
+
+        let push = mock_client.push(b"hello");
+        let ack = handle_mock_frame(&fixture, &mock_client, &push)
+            .await
+            .ok_or_else(|| "Push not acked".to_string())?;
+        test_eq!(tcp_frame(&ack).ack_num(), mock_client.seq);
+        test_eq!(read_some(&connection).await.ok(), Some(b"hello".to_vec()));
+
+        // Half open connections give up after a few syn acks
+        let give_up =
+            (SYN_ACK_TIMEOUT_S * (SYN_RETRIES + 2) as f32 * fixture.time.tick_freq()) as usize;
+        fixture.time.set_tick(give_up);
+        let mut resent = 0;
+        while crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_some()
+        {
+            resent += 1;
+        }
+        test_eq!(resent, LISTEN_BACKLOG * SYN_RETRIES);
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 1);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_keepalive, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        connection.set_keepalive(Some(Keepalive {
+            idle_s: 10.0,
+            interval_s: 1.0,
+            probes: 2,
+        }));
+        let tick_freq = fixture.time.tick_freq();
+        let set_time_s = |t: f32| fixture.time.set_tick((t * tick_freq) as usize);
+
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        set_time_s(10.0);
+        let probe = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "No keepalive probe".to_string())?;
+        let probe = tcp_frame(&probe.payload);
+        test_eq!(probe.seq_num(), mock_client.ack.wrapping_sub(1));
+        test_eq!(probe.payload().len(), 0);
+
+        // An answer means the peer is still there, so it's another idle period until the next
+        test_true!(
+            handle_mock_frame(&fixture, &mock_client, &mock_client.ack())
+                .await
+                .is_none()
+        );
+        set_time_s(19.0);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        for t in [20.0, 21.0] {
+            set_time_s(t);
+            test_true!(crate::future::poll_immediate(fixture.tcp.service())
+                .await
+                .is_some());
+        }
+
+        // Out of probes, the peer is gone
+        set_time_s(22.0);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+        test_eq!(read_some(&connection).await.err(), Some(TcpError::TimedOut));
+        test_eq!(
+            connection.write(Arc::<str>::from("hello")).await.err(),
+            Some(TcpError::TimedOut)
+        );
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_idle_timeout, {
+        let fixture = gen_fixture();
+        let mut mock_client = gen_mock_client(80);
+        let listener = fixture.tcp.listen(mock_client.server_ip, 80).await;
+
+        mock_client.handshake(&fixture).await?;
+        let connection = crate::future::poll_immediate(listener.connection())
+            .await
+            .ok_or("Connection not ready".to_string())?;
+
+        connection.set_idle_timeout(Some(5.0));
+        let tick_freq = fixture.time.tick_freq();
+        let set_time_s = |t: f32| fixture.time.set_tick((t * tick_freq) as usize);
+
+        // Data pushes the timeout back
+        set_time_s(4.0);
+        let push = mock_client.push(b"hi");
+        test_true!(handle_mock_frame(&fixture, &mock_client, &push)
+            .await
+            .is_some());
+
+        set_time_s(8.0);
+        test_true!(crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .is_none());
+
+        // The peer is told the connection is gone
+        set_time_s(9.0);
+        let rst = crate::future::poll_immediate(fixture.tcp.service())
+            .await
+            .ok_or_else(|| "No reset on idle timeout".to_string())?;
+        let rst = tcp_frame(&rst.payload);
+        test_true!(rst.flags().rst());
+        test_eq!(rst.seq_num(), mock_client.ack);
+        test_eq!(fixture.tcp.tcp_states.lock().await.len(), 0);
+
+        // What arrived before is still readable
+        test_eq!(read_some(&connection).await.ok(), Some(b"hi".to_vec()));
+        test_eq!(
+            read_some(&connection).await.err(),
+            Some(TcpError::IdleTimeout)
+        );
+        test_eq!(
+            connection.write(Arc::<str>::from("hello")).await.err(),
+            Some(TcpError::IdleTimeout)
+        );
+
+        Ok(())
+    });
+}
@@ -0,0 +1,289 @@
+use alloc::vec::Vec;
+
+// Option kinds, RFC 793, RFC 2018 and RFC 7323
+const END_OF_OPTIONS: u8 = 0;
+const NO_OPERATION: u8 = 1;
+const MAXIMUM_SEGMENT_SIZE: u8 = 2;
+const WINDOW_SCALE: u8 = 3;
+const SACK_PERMITTED: u8 = 4;
+const SACK: u8 = 5;
+const TIMESTAMPS: u8 = 8;
+
+// Largest shift RFC 7323 allows, anything bigger is treated as this
+pub const MAX_WINDOW_SCALE: u8 = 14;
+// Sack blocks that fit in the 40 bytes of options next to timestamps
+pub const MAX_SACK_BLOCKS: usize = 3;
+// Bytes timestamps take up in every segment once negotiated, padding included
+pub const TIMESTAMPS_LENGTH: usize = 12;
+// Floor for the peer's mss. A tiny one would have us send a flood of nearly empty segments
+// (CVE-2019-11479), linux has a similar limit
+const MIN_MSS: usize = 64;
+
+/// Received sequence space [start, end) the peer has, past a hole
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct SackBlock {
+    pub start: u32,
+    pub end: u32,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Timestamps {
+    pub value: u32,
+    pub echo_reply: u32,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct TcpOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    pub sack_blocks: Vec<SackBlock>,
+    pub timestamps: Option<Timestamps>,
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    u16::from_be_bytes(data.try_into().expect("tcp option length wrong"))
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data.try_into().expect("tcp option length wrong"))
+}
+
+/// Parses the options part of a tcp header. Options we don't know are skipped, and parsing
+/// stops at the first malformed one, keeping whatever came before it
+pub fn parse_tcp_options(mut data: &[u8]) -> TcpOptions {
+    let mut ret = TcpOptions::default();
+
+    while let Some(&kind) = data.first() {
+        match kind {
+            END_OF_OPTIONS => break,
+            NO_OPERATION => {
+                data = &data[1..];
+                continue;
+            }
+            _ => (),
+        }
+
+        let length = match data.get(1) {
+            Some(&length) if length >= 2 && length as usize <= data.len() => length as usize,
+            _ => break,
+        };
+        let value = &data[2..length];
+
+        match (kind, value.len()) {
+            (MAXIMUM_SEGMENT_SIZE, 2) => ret.mss = Some(read_u16(value)),
+            (WINDOW_SCALE, 1) => ret.window_scale = Some(value[0].min(MAX_WINDOW_SCALE)),
+            (SACK_PERMITTED, 0) => ret.sack_permitted = true,
+            (SACK, len) if len % 8 == 0 => {
+                ret.sack_blocks = value
+                    .chunks_exact(8)
+                    .map(|block| SackBlock {
+                        start: read_u32(&block[0..4]),
+                        end: read_u32(&block[4..8]),
+                    })
+                    .collect();
+            }
+            (TIMESTAMPS, 8) => {
+                ret.timestamps = Some(Timestamps {
+                    value: read_u32(&value[0..4]),
+                    echo_reply: read_u32(&value[4..8]),
+                })
+            }
+            (MAXIMUM_SEGMENT_SIZE | WINDOW_SCALE | SACK_PERMITTED | SACK | TIMESTAMPS, _) => {
+                debug!("Invalid length {} for tcp option {}", length, kind);
+            }
+            _ => (),
+        }
+
+        data = &data[length..];
+    }
+
+    ret
+}
+
+/// Serializes options for a tcp header. Every option is padded out with no-ops to a 4 byte
+/// boundary, so the result always fits the data offset field
+pub fn generate_tcp_options(options: &TcpOptions) -> Vec<u8> {
+    let mut ret = Vec::new();
+
+    if let Some(mss) = options.mss {
+        ret.extend_from_slice(&[MAXIMUM_SEGMENT_SIZE, 4]);
+        ret.extend_from_slice(&mss.to_be_bytes());
+    }
+
+    if let Some(window_scale) = options.window_scale {
+        ret.extend_from_slice(&[NO_OPERATION, WINDOW_SCALE, 3, window_scale]);
+    }
+
+    if options.sack_permitted {
+        ret.extend_from_slice(&[NO_OPERATION, NO_OPERATION, SACK_PERMITTED, 2]);
+    }
+
+    if let Some(timestamps) = options.timestamps {
+        ret.extend_from_slice(&[NO_OPERATION, NO_OPERATION, TIMESTAMPS, 10]);
+        ret.extend_from_slice(&timestamps.value.to_be_bytes());
+        ret.extend_from_slice(&timestamps.echo_reply.to_be_bytes());
+    }
+
+    if !options.sack_blocks.is_empty() {
+        let blocks = &options.sack_blocks[..options.sack_blocks.len().min(MAX_SACK_BLOCKS)];
+        ret.extend_from_slice(&[NO_OPERATION, NO_OPERATION, SACK, 2 + 8 * blocks.len() as u8]);
+        for block in blocks {
+            ret.extend_from_slice(&block.start.to_be_bytes());
+            ret.extend_from_slice(&block.end.to_be_bytes());
+        }
+    }
+
+    ret
+}
+
+/// What both sides agreed on during the handshake
+#[derive(Debug, Clone, Copy)]
+pub struct NegotiatedOptions {
+    // Largest segment the peer accepts, options included
+    pub mss: usize,
+    // Shift for the windows the peer advertises
+    pub send_window_scale: u8,
+    // Shift for the windows we advertise
+    pub receive_window_scale: u8,
+    pub sack_permitted: bool,
+    pub timestamps: bool,
+}
+
+impl NegotiatedOptions {
+    /// Combines the options from our syn with the ones from the peer's. Window scaling, sack
+    /// and timestamps are only used if both syns asked for them, the mss falls back to
+    /// default_mss if the peer didn't give one
+    pub fn negotiate(ours: &TcpOptions, theirs: &TcpOptions, default_mss: usize) -> Self {
+        let their_mss = theirs.mss.map(usize::from).unwrap_or(default_mss);
+        let our_mss = ours.mss.map(usize::from).unwrap_or(default_mss);
+
+        let (send_window_scale, receive_window_scale) =
+            match (theirs.window_scale, ours.window_scale) {
+                (Some(theirs), Some(ours)) => (theirs, ours),
+                _ => (0, 0),
+            };
+
+        NegotiatedOptions {
+            mss: their_mss.min(our_mss).max(MIN_MSS),
+            send_window_scale,
+            receive_window_scale,
+            sack_permitted: ours.sack_permitted && theirs.sack_permitted,
+            timestamps: ours.timestamps.is_some() && theirs.timestamps.is_some(),
+        }
+    }
+
+    /// Payload that fits in a single segment after the per segment options
+    pub fn max_payload(&self) -> usize {
+        if self.timestamps {
+            self.mss - TIMESTAMPS_LENGTH
+        } else {
+            self.mss
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::vec;
+
+    create_test!(test_tcp_options_back_and_forth, {
+        let options = TcpOptions {
+            mss: Some(1460),
+            window_scale: Some(7),
+            sack_permitted: true,
+            sack_blocks: vec![
+                SackBlock {
+                    start: 100,
+                    end: 200,
+                },
+                SackBlock {
+                    start: u32::MAX - 10,
+                    end: 5,
+                },
+            ],
+            timestamps: Some(Timestamps {
+                value: 1234,
+                echo_reply: 5678,
+            }),
+        };
+
+        let generated = generate_tcp_options(&options);
+        test_eq!(generated.len() % 4, 0);
+        test_eq!(parse_tcp_options(&generated), options);
+
+        test_eq!(parse_tcp_options(&[]), TcpOptions::default());
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_options_malformed, {
+        // Unknown option is skipped, then the mss is cut short by the end of the header
+        let data = [
+            &[NO_OPERATION, 30, 3, 0][..],
+            &[WINDOW_SCALE, 3, 20],
+            &[MAXIMUM_SEGMENT_SIZE, 4, 5],
+        ]
+        .concat();
+        let options = parse_tcp_options(&data);
+        test_eq!(options.window_scale, Some(MAX_WINDOW_SCALE));
+        test_true!(options.mss.is_none());
+
+        // Zero length options would never end
+        let options = parse_tcp_options(&[SACK_PERMITTED, 0, SACK_PERMITTED, 2]);
+        test_false!(options.sack_permitted);
+
+        // Nothing after the end of the list counts
+        let options = parse_tcp_options(&[END_OF_OPTIONS, SACK_PERMITTED, 2]);
+        test_false!(options.sack_permitted);
+
+        Ok(())
+    });
+
+    create_test!(test_tcp_options_negotiation, {
+        let ours = TcpOptions {
+            mss: Some(1460),
+            window_scale: Some(0),
+            sack_permitted: true,
+            sack_blocks: vec![],
+            timestamps: Some(Timestamps {
+                value: 0,
+                echo_reply: 0,
+            }),
+        };
+
+        let theirs = TcpOptions {
+            mss: Some(1000),
+            window_scale: Some(3),
+            timestamps: Some(Timestamps {
+                value: 1,
+                echo_reply: 0,
+            }),
+            ..Default::default()
+        };
+
+        let negotiated = NegotiatedOptions::negotiate(&ours, &theirs, 536);
+        test_eq!(negotiated.mss, 1000);
+        test_eq!(negotiated.send_window_scale, 3);
+        test_eq!(negotiated.receive_window_scale, 0);
+        test_false!(negotiated.sack_permitted);
+        test_true!(negotiated.timestamps);
+        test_eq!(negotiated.max_payload(), 1000 - TIMESTAMPS_LENGTH);
+
+        let negotiated = NegotiatedOptions::negotiate(&ours, &TcpOptions::default(), 536);
+        test_eq!(negotiated.mss, 536);
+        test_eq!(negotiated.send_window_scale, 0);
+        test_false!(negotiated.timestamps);
+
+        let tiny_mss = TcpOptions {
+            mss: Some(1),
+            ..Default::default()
+        };
+        let negotiated = NegotiatedOptions::negotiate(&ours, &tiny_mss, 536);
+        test_eq!(negotiated.mss, MIN_MSS);
+
+        Ok(())
+    });
+}
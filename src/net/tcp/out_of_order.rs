@@ -0,0 +1,158 @@
+use super::{
+    options::{SackBlock, MAX_SACK_BLOCKS},
+    seq_le, seq_lt,
+};
+
+use alloc::vec::Vec;
+
+struct Segment {
+    seq: u32,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn end(&self) -> u32 {
+        self.seq.wrapping_add(self.data.len() as u32)
+    }
+}
+
+/// Data that arrived past a hole in the sequence space, held until the hole is filled
+#[derive(Default)]
+pub struct OutOfOrderQueue {
+    // Sorted, with no segments overlapping or touching
+    segments: Vec<Segment>,
+    // Where the peer's fin sits, it can show up before the data in front of it
+    fin_seq: Option<u32>,
+    // Sequence number of the data we got last, its block goes first in our sacks (RFC 2018 4)
+    latest: Option<u32>,
+}
+
+impl OutOfOrderQueue {
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Stores data starting at seq, somewhere past next_seq, the next byte we expect
+    pub fn insert(&mut self, next_seq: u32, seq: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        self.latest = Some(seq);
+        self.segments.push(Segment {
+            seq,
+            data: data.to_vec(),
+        });
+        self.segments
+            .sort_unstable_by_key(|segment| segment.seq.wrapping_sub(next_seq));
+
+        let mut merged: Vec<Segment> = Vec::with_capacity(self.segments.len());
+        for segment in self.segments.drain(..) {
+            match merged.last_mut() {
+                Some(last) if seq_le(segment.seq, last.end()) => {
+                    let overlap = last.end().wrapping_sub(segment.seq) as usize;
+                    if overlap < segment.data.len() {
+                        last.data.extend_from_slice(&segment.data[overlap..]);
+                    }
+                }
+                _ => merged.push(segment),
+            }
+        }
+        self.segments = merged;
+    }
+
+    /// Takes the queued data that continues from next_seq, once the hole in front of it has
+    /// been filled
+    pub fn pop(&mut self, next_seq: u32) -> Option<Vec<u8>> {
+        while matches!(self.segments.first(), Some(segment) if seq_le(segment.seq, next_seq)) {
+            let mut segment = self.segments.remove(0);
+            if seq_lt(next_seq, segment.end()) {
+                let already_have = next_seq.wrapping_sub(segment.seq) as usize;
+                segment.data.drain(..already_have);
+                return Some(segment.data);
+            }
+        }
+
+        None
+    }
+
+    pub fn set_fin(&mut self, fin_seq: u32) {
+        self.fin_seq = Some(fin_seq);
+    }
+
+    /// Whether everything up to the peer's fin has arrived
+    pub fn fin_reached(&self, next_seq: u32) -> bool {
+        self.fin_seq == Some(next_seq)
+    }
+
+    /// Blocks to tell the peer about, the most recently received one first
+    pub fn sack_blocks(&self) -> Vec<SackBlock> {
+        let mut blocks: Vec<SackBlock> = self
+            .segments
+            .iter()
+            .map(|segment| SackBlock {
+                start: segment.seq,
+                end: segment.end(),
+            })
+            .collect();
+
+        let latest = self.latest.and_then(|latest| {
+            blocks
+                .iter()
+                .position(|block| seq_le(block.start, latest) && seq_lt(latest, block.end))
+        });
+        if let Some(latest) = latest {
+            let block = blocks.remove(latest);
+            blocks.insert(0, block);
+        }
+
+        blocks.truncate(MAX_SACK_BLOCKS);
+        blocks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    create_test!(test_out_of_order_queue, {
+        let mut queue = OutOfOrderQueue::default();
+        let next_seq = u32::MAX - 4;
+
+        // Overlapping and touching segments merge, across the sequence number wrap
+        queue.insert(next_seq, next_seq.wrapping_add(10), b"klmno");
+        queue.insert(next_seq, next_seq.wrapping_add(3), b"defgh");
+        queue.insert(next_seq, next_seq.wrapping_add(6), b"ghij");
+        test_eq!(
+            queue.sack_blocks(),
+            [SackBlock {
+                start: next_seq.wrapping_add(3),
+                end: next_seq.wrapping_add(15),
+            }]
+        );
+
+        queue.insert(next_seq, next_seq.wrapping_add(20), b"uv");
+        test_eq!(queue.sack_blocks()[0].start, next_seq.wrapping_add(20));
+        test_eq!(queue.sack_blocks().len(), 2);
+
+        // Nothing until the hole at the front is filled
+        test_true!(queue.pop(next_seq).is_none());
+        test_eq!(
+            queue.pop(next_seq.wrapping_add(5)),
+            Some(b"fghijklmno".to_vec())
+        );
+        test_true!(queue.pop(next_seq.wrapping_add(15)).is_none());
+        test_false!(queue.is_empty());
+
+        // Data we already have is dropped
+        test_true!(queue.pop(next_seq.wrapping_add(22)).is_none());
+        test_true!(queue.is_empty());
+
+        queue.set_fin(next_seq.wrapping_add(30));
+        test_false!(queue.fin_reached(next_seq.wrapping_add(22)));
+        test_true!(queue.fin_reached(next_seq.wrapping_add(30)));
+
+        Ok(())
+    });
+}
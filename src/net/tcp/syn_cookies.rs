@@ -0,0 +1,128 @@
+use super::TcpKey;
+
+use crate::{rng::Rng, time::MonotonicTime};
+
+use ahash::RandomState;
+use core::hash::{BuildHasher, Hash, Hasher};
+
+// Cookies are stamped with a counter that ticks every SYN_COOKIE_PERIOD_S, and acks for them
+// are accepted for one more period after that
+const SYN_COOKIE_PERIOD_S: f32 = 64.0;
+// Mss values a cookie can encode, the peer gets the largest one that fits its own
+const MSS_TABLE: [u16; 4] = [536, 1200, 1440, 1460];
+
+// Layout of the cookie, from the top: 5 bits of counter, 3 bits of mss index, 24 bits of hash
+const COUNTER_SHIFT: u32 = 27;
+const MSS_SHIFT: u32 = 24;
+const HASH_MASK: u32 = (1 << MSS_SHIFT) - 1;
+
+/// Initial sequence numbers that let us finish a handshake without having kept any state for
+/// it (RFC 4987 3.6). Used once a listener's backlog is full so a syn flood can't use up memory
+pub struct SynCookies {
+    secret: RandomState,
+}
+
+impl SynCookies {
+    pub fn new(rng: &mut Rng) -> SynCookies {
+        SynCookies {
+            secret: RandomState::with_seeds(rng.u64(), rng.u64(), rng.u64(), rng.u64()),
+        }
+    }
+
+    /// Sequence number for our syn ack to the syn from tcp_key with sequence number peer_isn.
+    /// Returns the mss the cookie encodes as well, which is what we tell the peer
+    pub fn generate(
+        &self,
+        tcp_key: &TcpKey,
+        peer_isn: u32,
+        peer_mss: usize,
+        time: &MonotonicTime,
+    ) -> (u32, u16) {
+        let mss_index = MSS_TABLE
+            .iter()
+            .rposition(|mss| *mss as usize <= peer_mss)
+            .unwrap_or(0);
+        let counter = counter(time);
+
+        let cookie = (counter << COUNTER_SHIFT)
+            | ((mss_index as u32) << MSS_SHIFT)
+            | self.hash(tcp_key, peer_isn, counter);
+        (cookie, MSS_TABLE[mss_index])
+    }
+
+    /// Checks the cookie the peer acked, returning the mss it encodes if it's one of ours and
+    /// hasn't expired
+    pub fn validate(
+        &self,
+        tcp_key: &TcpKey,
+        peer_isn: u32,
+        cookie: u32,
+        time: &MonotonicTime,
+    ) -> Option<u16> {
+        let now = counter(time);
+        let counter = cookie >> COUNTER_SHIFT;
+        let age = now.wrapping_sub(counter) & (u32::MAX >> COUNTER_SHIFT);
+        if age > 1 {
+            return None;
+        }
+
+        // The hash covers the whole counter, so a cookie from 32 periods ago doesn't match
+        let counter = now.wrapping_sub(age);
+        if cookie & HASH_MASK != self.hash(tcp_key, peer_isn, counter) {
+            return None;
+        }
+
+        let mss_index = (cookie >> MSS_SHIFT) as usize & 0b111;
+        MSS_TABLE.get(mss_index).copied()
+    }
+
+    fn hash(&self, tcp_key: &TcpKey, peer_isn: u32, counter: u32) -> u32 {
+        let mut hasher = self.secret.build_hasher();
+        tcp_key.hash(&mut hasher);
+        peer_isn.hash(&mut hasher);
+        counter.hash(&mut hasher);
+        hasher.finish() as u32 & HASH_MASK
+    }
+}
+
+fn counter(time: &MonotonicTime) -> u32 {
+    (time.get() as f32 / time.tick_freq() / SYN_COOKIE_PERIOD_S) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    create_test!(test_syn_cookies, {
+        let time = MonotonicTime::new(10.0);
+        let cookies = SynCookies::new(&mut Rng::new(0));
+        let tcp_key = TcpKey {
+            remote_ip: [192, 168, 2, 1],
+            local_ip: [192, 168, 2, 2],
+            remote_port: 1234,
+            local_port: 80,
+        };
+
+        let (cookie, mss) = cookies.generate(&tcp_key, 100, 1300, &time);
+        test_eq!(mss, 1200);
+        test_eq!(cookies.validate(&tcp_key, 100, cookie, &time), Some(1200));
+
+        // Tied to the connection and the peer's sequence number
+        test_true!(cookies.validate(&tcp_key, 101, cookie, &time).is_none());
+        let other_port = TcpKey {
+            remote_port: 1235,
+            ..tcp_key.clone()
+        };
+        test_true!(cookies.validate(&other_port, 100, cookie, &time).is_none());
+
+        // Good for one more period, then expired
+        let period_ticks = (SYN_COOKIE_PERIOD_S * time.tick_freq()) as usize;
+        time.set_tick(period_ticks);
+        test_eq!(cookies.validate(&tcp_key, 100, cookie, &time), Some(1200));
+        time.set_tick(2 * period_ticks);
+        test_true!(cookies.validate(&tcp_key, 100, cookie, &time).is_none());
+
+        Ok(())
+    });
+}
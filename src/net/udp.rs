@@ -0,0 +1,233 @@
+use crate::{
+    net::{arp::ArpTimeout, NetStack, NetworkInterface},
+    util::{
+        async_channel::{self, Receiver, Sender},
+        spinlock::SpinLock,
+    },
+    IpAddr,
+};
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use hashbrown::HashMap;
+
+// Ports handed out when binding to port 0, same range as linux/rfc 6335
+const EPHEMERAL_PORT_START: u16 = 49152;
+const EPHEMERAL_PORT_END: u16 = 65535;
+// Datagrams a socket can have waiting before new ones are dropped
+const SOCKET_QUEUE_LEN: usize = 64;
+
+#[derive(Debug)]
+pub struct PortInUse(pub u16);
+
+#[derive(Debug)]
+pub enum SendError {
+    ArpTimeout(ArpTimeout),
+    // More data than fits in one datagram
+    TooLarge(usize),
+}
+
+impl From<ArpTimeout> for SendError {
+    fn from(error: ArpTimeout) -> Self {
+        SendError::ArpTimeout(error)
+    }
+}
+
+#[derive(Debug)]
+pub struct UdpDatagram {
+    pub source_ip: IpAddr,
+    pub source_port: u16,
+    pub data: Vec<u8>,
+}
+
+struct UdpInner {
+    sockets: HashMap<u16, Sender<UdpDatagram>>,
+    next_ephemeral_port: u16,
+}
+
+impl UdpInner {
+    fn find_ephemeral_port(&mut self) -> Option<u16> {
+        let num_ports = EPHEMERAL_PORT_END - EPHEMERAL_PORT_START + 1;
+        for _ in 0..num_ports {
+            let port = self.next_ephemeral_port;
+            self.next_ephemeral_port = if port == EPHEMERAL_PORT_END {
+                EPHEMERAL_PORT_START
+            } else {
+                port + 1
+            };
+
+            if !self.sockets.contains_key(&port) {
+                return Some(port);
+            }
+        }
+
+        None
+    }
+}
+
+pub struct Udp {
+    inner: SpinLock<UdpInner>,
+    // Datagrams dropped because their socket's queue was full
+    queue_drops: AtomicUsize,
+}
+
+impl Udp {
+    pub fn new() -> Udp {
+        Udp {
+            inner: SpinLock::new(UdpInner {
+                sockets: HashMap::new(),
+                next_ephemeral_port: EPHEMERAL_PORT_START,
+            }),
+            queue_drops: AtomicUsize::new(0),
+        }
+    }
+
+    /// Registers port for incoming datagrams. A port of 0 picks a free ephemeral port. Returns
+    /// the bound port
+    fn bind(&self, port: u16) -> Result<(u16, Receiver<UdpDatagram>), PortInUse> {
+        let mut inner = self.inner.lock();
+
+        let port = match port {
+            0 => inner.find_ephemeral_port().ok_or(PortInUse(0))?,
+            p if inner.sockets.contains_key(&p) => return Err(PortInUse(p)),
+            p => p,
+        };
+
+        let (tx, rx) = async_channel::bounded_channel(SOCKET_QUEUE_LEN);
+        inner.sockets.insert(port, tx);
+        Ok((port, rx))
+    }
+
+    fn unbind(&self, port: u16) {
+        self.inner.lock().sockets.remove(&port);
+    }
+
+    /// Queues the datagram on the socket bound to dest_port, or drops it if the socket is too
+    /// far behind. Returns false if nobody is listening
+    pub(super) async fn handle_datagram(&self, dest_port: u16, datagram: UdpDatagram) -> bool {
+        let tx = match self.inner.lock().sockets.get(&dest_port) {
+            Some(tx) => tx.clone(),
+            None => return false,
+        };
+
+        if tx.try_send(datagram).await.is_err() {
+            let total = self.queue_drops.fetch_add(1, Ordering::Relaxed) + 1;
+            debug!(
+                "Queue for udp port {} full, {} datagrams dropped so far",
+                dest_port, total
+            );
+        }
+        true
+    }
+}
+
+pub struct UdpSocket<'a, I> {
+    net: &'a NetStack<I>,
+    port: u16,
+    rx: Receiver<UdpDatagram>,
+}
+
+impl<'a, I: NetworkInterface> UdpSocket<'a, I> {
+    /// Receive datagrams sent to port on any of our addresses. The port is released when the
+    /// socket is dropped
+    pub fn bind(net: &'a NetStack<I>, port: u16) -> Result<UdpSocket<'a, I>, PortInUse> {
+        let (port, rx) = net.udp.bind(port)?;
+        Ok(UdpSocket { net, port, rx })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub async fn recv_from(&self) -> UdpDatagram {
+        self.rx.recv().await
+    }
+
+    pub async fn send_to(
+        &self,
+        data: &[u8],
+        dest_ip: &IpAddr,
+        dest_port: u16,
+    ) -> Result<(), SendError> {
+        self.net
+            .send_udp_from(&self.net.ip(), self.port, dest_ip, dest_port, data)
+            .await
+    }
+}
+
+impl<I> Drop for UdpSocket<'_, I> {
+    fn drop(&mut self) {
+        self.net.udp.unbind(self.port);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    create_test!(test_udp_bind, {
+        let udp = Udp::new();
+
+        let (port, _rx) = udp.bind(1234).map_err(|e| alloc::format!("{:?}", e))?;
+        test_eq!(port, 1234);
+        test_true!(udp.bind(1234).is_err());
+
+        let (ephemeral_1, _rx_1) = udp.bind(0).map_err(|e| alloc::format!("{:?}", e))?;
+        let (ephemeral_2, _rx_2) = udp.bind(0).map_err(|e| alloc::format!("{:?}", e))?;
+        test_ge!(ephemeral_1, EPHEMERAL_PORT_START);
+        test_ge!(ephemeral_2, EPHEMERAL_PORT_START);
+        test_ne!(ephemeral_1, ephemeral_2);
+
+        udp.unbind(1234);
+        test_true!(udp.bind(1234).is_ok());
+
+        Ok(())
+    });
+
+    create_test!(test_udp_dispatch, {
+        let udp = Udp::new();
+        let (_, rx) = udp.bind(1234).map_err(|e| alloc::format!("{:?}", e))?;
+
+        let datagram = |data: &[u8]| UdpDatagram {
+            source_ip: [192, 168, 2, 1],
+            source_port: 5678,
+            data: data.to_vec(),
+        };
+
+        test_true!(udp.handle_datagram(1234, datagram(b"hello")).await);
+        test_false!(udp.handle_datagram(4321, datagram(b"nobody")).await);
+
+        let received = rx.recv().await;
+        test_eq!(received.source_port, 5678);
+        test_eq!(received.data, b"hello");
+
+        udp.unbind(1234);
+        test_false!(udp.handle_datagram(1234, datagram(b"gone")).await);
+
+        Ok(())
+    });
+
+    create_test!(test_udp_queue_full, {
+        let udp = Udp::new();
+        let (_, rx) = udp.bind(1234).map_err(|e| alloc::format!("{:?}", e))?;
+
+        for i in 0..SOCKET_QUEUE_LEN + 3 {
+            let datagram = UdpDatagram {
+                source_ip: [192, 168, 2, 1],
+                source_port: 5678,
+                data: alloc::vec![i as u8],
+            };
+            // Still bound, so no port unreachable even when dropped
+            let bound = udp.handle_datagram(1234, datagram).await;
+            test_true!(bound);
+        }
+        test_eq!(udp.queue_drops.load(Ordering::Relaxed), 3);
+
+        // The oldest ones were kept
+        test_eq!(rx.recv().await.data, [0]);
+
+        Ok(())
+    });
+}
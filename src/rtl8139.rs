@@ -1,6 +1,7 @@
 use crate::{
     interrupts::{InterruptHandlerData, InterruptHandlerRegisterError},
     io::pci::{GeneralPciDevice, InvalidIrq, Pci},
+    net::NetworkInterface,
     util::{
         async_mutex::Mutex,
         atomic_cell::AtomicCell,
@@ -11,7 +12,7 @@ use crate::{
 
 use hashbrown::HashMap;
 
-use alloc::{boxed::Box, sync::Arc, vec};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
 use core::{
     future::Future,
     ops::Deref,
@@ -538,7 +539,7 @@ impl Inner {
 
     pub async fn read<F, Fut>(&mut self, on_read: F) -> Fut
     where
-        F: Fn(&[u8]) -> Fut,
+        F: FnOnce(&[u8]) -> Fut,
         Fut: core::future::Future<Output = ()>,
     {
         let data = unsafe { get_packet(self.base, &self.receive_buf) };
@@ -611,7 +612,7 @@ impl Rtl8139 {
 
     pub async fn read<F, Fut>(&self, on_read: F)
     where
-        F: Fn(&[u8]) -> Fut,
+        F: FnOnce(&[u8]) -> Fut,
         Fut: core::future::Future<Output = ()>,
     {
         unsafe {
@@ -682,3 +683,31 @@ impl Rtl8139 {
         self.inner.try_lock().unwrap().get_mac()
     }
 }
+
+impl NetworkInterface for Rtl8139 {
+    type Error = PacketTooShort;
+
+    fn mac(&self) -> [u8; 6] {
+        self.get_mac()
+    }
+
+    fn send<'a>(
+        &'a self,
+        frame: &'a [u8],
+    ) -> Pin<Box<dyn Future<Output = Result<(), PacketTooShort>> + Send + 'a>> {
+        Box::pin(self.write(frame))
+    }
+
+    fn recv(&self) -> Pin<Box<dyn Future<Output = Vec<u8>> + Send + '_>> {
+        Box::pin(async move {
+            let mut ret = Vec::new();
+            // FIXME: Avoid copying but types are hard
+            self.read(|packet| {
+                ret = packet.to_vec();
+                async {}
+            })
+            .await;
+            ret
+        })
+    }
+}
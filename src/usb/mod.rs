@@ -6,6 +6,7 @@ use crate::{
         async_channel::{self, Receiver, Sender},
         bit_manipulation::GetBits,
         oneshot::{self, Sender as OneshotSender},
+        spinlock::SpinLock,
     },
 };
 
@@ -13,7 +14,7 @@ use uhci::Uhci;
 
 use core::fmt;
 
-use alloc::{vec, vec::Vec};
+use alloc::{sync::Arc, vec, vec::Vec};
 
 #[derive(Clone, Copy)]
 pub enum Pid {
@@ -80,16 +81,21 @@ pub type UsbPacketRequest = (Vec<UsbPacket>, OneshotSender<Vec<Vec<u8>>>);
 #[derive(Clone)]
 pub struct UsbServiceHandle {
     tx: Sender<UsbPacketRequest>,
+    devices: Arc<SpinLock<Vec<u8>>>,
 }
 
 impl UsbServiceHandle {
+    /// Addresses of the devices enumerated so far
+    pub fn device_addresses(&self) -> Vec<u8> {
+        self.devices.lock().clone()
+    }
+
     pub async fn queue_work(&self, work: Vec<UsbPacket>) -> Vec<Vec<u8>> {
         let (tx, rx) = oneshot::channel();
         self.tx.send((work, tx)).await;
         rx.recv().await.expect("Received oneshot twice")
     }
 
-    #[allow(unused)]
     pub async fn get_device_descriptor(&self, address: u8) -> UsbDeviceDescriptor<Vec<u8>> {
         const DESCRIPTOR_LENGTH: u16 = 18;
         let setup = UsbSetupRequestParams {
@@ -148,6 +154,7 @@ pub struct Usb {
     device_tx: Sender<UsbDevice>,
     packet_rx: Receiver<UsbPacketRequest>,
     packet_tx: Sender<UsbPacketRequest>,
+    devices: Arc<SpinLock<Vec<u8>>>,
 }
 
 impl Usb {
@@ -162,6 +169,7 @@ impl Usb {
             device_tx,
             packet_tx,
             packet_rx,
+            devices: Arc::new(SpinLock::new(Vec::new())),
         }
     }
 
@@ -171,7 +179,8 @@ impl Usb {
 
     pub fn handle(&self) -> UsbServiceHandle {
         let tx = self.packet_tx.clone();
-        UsbServiceHandle { tx }
+        let devices = Arc::clone(&self.devices);
+        UsbServiceHandle { tx, devices }
     }
 
     async fn set_address(&mut self, address: u8) {
@@ -247,6 +256,7 @@ impl Usb {
             self.set_address(address).await;
             // Assume single configuration for now
             self.set_configuration(address, 1).await;
+            self.devices.lock().push(address);
             self.device_tx.send(UsbDevice { address }).await;
         }
 
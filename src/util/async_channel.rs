@@ -6,6 +6,8 @@ use core::task::{Poll, Waker};
 struct Inner<T> {
     queue: VecDeque<T>,
     waker: Option<Waker>,
+    // Most values try_send queues up, send ignores it
+    capacity: usize,
 }
 
 pub struct Sender<T> {
@@ -28,6 +30,20 @@ impl<T> Sender<T> {
             waker.wake_by_ref();
         }
     }
+
+    /// Queues val unless the receiver already has capacity values waiting, in which case val
+    /// is handed back
+    pub async fn try_send(&self, val: T) -> Result<(), T> {
+        let mut inner = self.inner.lock().await;
+        if inner.queue.len() >= inner.capacity {
+            return Err(val);
+        }
+        inner.queue.push_back(val);
+        if let Some(waker) = &inner.waker {
+            waker.wake_by_ref();
+        }
+        Ok(())
+    }
 }
 
 struct ReceiverWaiter<'a, T> {
@@ -67,9 +83,15 @@ impl<T> Receiver<T> {
 }
 
 pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    bounded_channel(usize::MAX)
+}
+
+/// Channel where try_send fails once capacity values are waiting to be received
+pub fn bounded_channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
     let inner = Inner {
         queue: VecDeque::new(),
         waker: None,
+        capacity,
     };
     let inner = Arc::new(Mutex::new(inner));
     let sender = Sender {
@@ -101,4 +123,17 @@ mod test {
         }
         Ok(())
     });
+
+    create_test!(test_bounded_channel, {
+        let (tx, rx) = bounded_channel(2);
+        test_true!(tx.try_send(1).await.is_ok());
+        test_true!(tx.try_send(2).await.is_ok());
+        test_eq!(tx.try_send(3).await.err(), Some(3));
+
+        test_eq!(rx.recv().await, 1);
+        test_true!(tx.try_send(4).await.is_ok());
+        test_eq!(rx.recv().await, 2);
+        test_eq!(rx.recv().await, 4);
+        Ok(())
+    });
 }
@@ -0,0 +1,120 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{future::Future, pin::Pin};
+
+/// The stream ended before a read_exact could fill its buffer
+#[derive(Debug, Eq, PartialEq)]
+pub struct UnexpectedEof;
+
+/// Byte stream that can be read asynchronously, e.g. a tcp connection. Reads take &self so the
+/// same stream can be written from another task at the same time
+pub trait AsyncRead: Send + Sync {
+    type Error: core::fmt::Debug + From<UnexpectedEof> + Send;
+
+    /// Reads up to buf.len() bytes, waiting until at least one is available. Returns 0 once the
+    /// stream has ended
+    fn read<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> Pin<Box<dyn Future<Output = Result<usize, Self::Error>> + Send + 'a>>;
+
+    /// Fills all of buf, failing with UnexpectedEof if the stream ends first
+    #[allow(unused)]
+    fn read_exact<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> Pin<Box<dyn Future<Output = Result<(), Self::Error>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut filled = 0;
+            while filled < buf.len() {
+                match self.read(&mut buf[filled..]).await? {
+                    0 => return Err(UnexpectedEof.into()),
+                    n => filled += n,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Appends bytes to buf up to and including delimiter, returning how many were appended.
+    /// Stops early at the end of the stream, so 0 means there was nothing left. Goes a byte at a
+    /// time so nothing past the delimiter is consumed
+    fn read_until<'a>(
+        &'a self,
+        delimiter: u8,
+        buf: &'a mut Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<usize, Self::Error>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut byte = [0];
+            let mut appended = 0;
+            while self.read(&mut byte).await? != 0 {
+                buf.push(byte[0]);
+                appended += 1;
+                if byte[0] == delimiter {
+                    break;
+                }
+            }
+            Ok(appended)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{testing::*, util::spinlock::SpinLock};
+    use alloc::{collections::VecDeque, vec};
+
+    // Hands out at most one chunk per read, like a tcp connection would
+    struct Chunks(SpinLock<VecDeque<Vec<u8>>>);
+
+    impl AsyncRead for Chunks {
+        type Error = UnexpectedEof;
+
+        fn read<'a>(
+            &'a self,
+            buf: &'a mut [u8],
+        ) -> Pin<Box<dyn Future<Output = Result<usize, Self::Error>> + Send + 'a>> {
+            Box::pin(async move {
+                let mut chunks = self.0.lock();
+                let chunk = match chunks.front_mut() {
+                    Some(chunk) => chunk,
+                    None => return Ok(0),
+                };
+
+                let n = buf.len().min(chunk.len());
+                buf[..n].copy_from_slice(&chunk[..n]);
+                chunk.drain(..n);
+                if chunk.is_empty() {
+                    chunks.pop_front();
+                }
+                Ok(n)
+            })
+        }
+    }
+
+    create_test!(test_async_read_helpers, {
+        let stream = Chunks(SpinLock::new(
+            [&b"GET / HT"[..], b"TP/1.1\r\nab", b"cdef"]
+                .iter()
+                .map(|chunk| chunk.to_vec())
+                .collect(),
+        ));
+
+        let mut line = Vec::new();
+        test_eq!(stream.read_until(b'\n', &mut line).await.ok(), Some(18));
+        test_eq!(line, b"GET / HTTP/1.1\r\n");
+
+        // Spans the chunk boundary
+        let mut buf = [0; 5];
+        test_ok!(stream.read_exact(&mut buf).await);
+        test_eq!(&buf, b"abcde");
+
+        let mut buf = vec![0; 2];
+        test_err!(stream.read_exact(&mut buf).await);
+
+        let mut rest = Vec::new();
+        test_eq!(stream.read_until(b'\n', &mut rest).await.ok(), Some(0));
+
+        Ok(())
+    });
+}
@@ -0,0 +1,51 @@
+use alloc::string::String;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding (RFC 4648 4)
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - i * 6)) & 0x3f;
+                out.push(ALPHABET[index as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Whether c is one of the 64 characters base64 encodes data with
+pub fn is_alphabet(c: u8) -> bool {
+    ALPHABET.contains(&c)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+
+    create_test!(test_base64_encode, {
+        // RFC 4648 10
+        test_eq!(encode(b""), "");
+        test_eq!(encode(b"f"), "Zg==");
+        test_eq!(encode(b"fo"), "Zm8=");
+        test_eq!(encode(b"foo"), "Zm9v");
+        test_eq!(encode(b"foobar"), "Zm9vYmFy");
+        test_eq!(encode(&[0xfb, 0xff]), "+/8=");
+
+        test_true!(is_alphabet(b'+'));
+        test_false!(is_alphabet(b'='));
+
+        Ok(())
+    });
+}
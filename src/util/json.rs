@@ -0,0 +1,175 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+/// Values that can be written out as JSON
+pub trait ToJson {
+    fn write_json(&self, out: &mut String);
+
+    fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+}
+
+/// Writes a JSON object whose fields are added by f
+pub fn write_object<F: FnOnce(&mut ObjectWriter)>(out: &mut String, f: F) {
+    out.push('{');
+    f(&mut ObjectWriter { out, first: true });
+    out.push('}');
+}
+
+pub struct ObjectWriter<'a> {
+    out: &'a mut String,
+    first: bool,
+}
+
+impl ObjectWriter<'_> {
+    pub fn field<T: ToJson + ?Sized>(&mut self, name: &str, value: &T) -> &mut Self {
+        if !self.first {
+            self.out.push(',');
+        }
+        self.first = false;
+
+        name.write_json(self.out);
+        self.out.push(':');
+        value.write_json(self.out);
+        self
+    }
+}
+
+macro_rules! impl_to_json_for_number {
+    ($($t:ty),*) => {
+        $(
+            impl ToJson for $t {
+                fn write_json(&self, out: &mut String) {
+                    // Writing to a String can't fail
+                    let _ = write!(out, "{}", self);
+                }
+            }
+        )*
+    };
+}
+
+impl_to_json_for_number!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl ToJson for f32 {
+    fn write_json(&self, out: &mut String) {
+        // JSON has no representation for these
+        if !self.is_finite() {
+            out.push_str("null");
+            return;
+        }
+        let _ = write!(out, "{}", self);
+    }
+}
+
+impl ToJson for bool {
+    fn write_json(&self, out: &mut String) {
+        out.push_str(if *self { "true" } else { "false" });
+    }
+}
+
+impl ToJson for str {
+    fn write_json(&self, out: &mut String) {
+        out.push('"');
+        for c in self.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if (c as u32) < 0x20 => {
+                    let _ = write!(out, "\\u{:04x}", c as u32);
+                }
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+}
+
+impl ToJson for String {
+    fn write_json(&self, out: &mut String) {
+        self.as_str().write_json(out);
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn write_json(&self, out: &mut String) {
+        match self {
+            Some(v) => v.write_json(out),
+            None => out.push_str("null"),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn write_json(&self, out: &mut String) {
+        out.push('[');
+        for (i, item) in self.iter().enumerate() {
+            if i != 0 {
+                out.push(',');
+            }
+            item.write_json(out);
+        }
+        out.push(']');
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn write_json(&self, out: &mut String) {
+        self.as_slice().write_json(out);
+    }
+}
+
+impl<T: ToJson + ?Sized> ToJson for &T {
+    fn write_json(&self, out: &mut String) {
+        (**self).write_json(out);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::*;
+    use alloc::vec;
+
+    struct Point {
+        x: i32,
+        label: Option<&'static str>,
+    }
+
+    impl ToJson for Point {
+        fn write_json(&self, out: &mut String) {
+            write_object(out, |o| {
+                o.field("x", &self.x).field("label", &self.label);
+            });
+        }
+    }
+
+    create_test!(test_json_serialization, {
+        test_eq!(
+            "quote \" backslash \\ newline \n bell \x07".to_json(),
+            "\"quote \\\" backslash \\\\ newline \\n bell \\u0007\""
+        );
+        test_eq!(1.5f32.to_json(), "1.5");
+        test_eq!(f32::NAN.to_json(), "null");
+        test_eq!(vec![true, false].to_json(), "[true,false]");
+
+        let points = vec![
+            Point {
+                x: -1,
+                label: Some("a"),
+            },
+            Point { x: 2, label: None },
+        ];
+        test_eq!(
+            points.to_json(),
+            "[{\"x\":-1,\"label\":\"a\"},{\"x\":2,\"label\":null}]"
+        );
+
+        Ok(())
+    });
+}